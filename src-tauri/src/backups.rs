@@ -0,0 +1,267 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::keymap::{write_atomically, Keymap};
+
+/// A saved copy of a keymap file in the backups folder
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Backup {
+    /// name of the backup file, used to restore it
+    pub file_name: String,
+    pub keymap_name: String,
+    /// when the backup was taken, in milliseconds since the unix epoch
+    pub created_ms: u128,
+    /// set for the copies kept of files from before a schema migration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u64>,
+}
+
+impl Backup {
+    /// Reads a backup's details back out of its file name, which is either
+    /// `<keymap>.<created_ms>.json` or `<keymap>.<created_ms>.v<schema_version>.json`.
+    /// Only the last parts are parsed, so profile names can contain anything.
+    fn from_file_name(file_name: &str) -> Option<Backup> {
+        let (rest, last) = file_name.strip_suffix(".json")?.rsplit_once('.')?;
+
+        let (rest, schema_version) = match last.strip_prefix('v') {
+            Some(version) => (rest, Some(version.parse().ok()?)),
+            None => (file_name.strip_suffix(".json")?, None),
+        };
+
+        let (keymap_name, created_ms) = rest.rsplit_once('.')?;
+        let created_ms: u128 = created_ms.parse().ok()?;
+
+        Some(Backup {
+            file_name: file_name.to_string(),
+            keymap_name: keymap_name.to_string(),
+            created_ms,
+            schema_version,
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        let mut backup_path = backup_dir();
+        backup_path.push(&self.file_name);
+        backup_path
+    }
+}
+
+/// The folder backups of keymap files go in
+pub fn backup_dir() -> PathBuf {
+    let mut backup_dir = Keymap::keymap_dir();
+    backup_dir.push(".backups");
+    backup_dir
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default()
+}
+
+/// Where a new backup goes. Saves can come quicker than a millisecond apart,
+/// so the time is moved on until the name is free instead of overwriting a backup.
+fn new_backup_path(keymap_name: &str, created_ms: u128, schema_version: Option<u64>) -> PathBuf {
+    let mut created_ms = created_ms;
+
+    loop {
+        let file_name = match schema_version {
+            Some(version) => format!("{}.{}.v{}.json", keymap_name, created_ms, version),
+            None => format!("{}.{}.json", keymap_name, created_ms),
+        };
+
+        let backup_path = backup_dir().join(file_name);
+        if !backup_path.exists() {
+            return backup_path;
+        }
+        created_ms += 1;
+    }
+}
+
+/// Copies a keymap file into the backups folder before it gets overwritten,
+/// then drops the oldest backups of that keymap past `keep`
+pub fn backup_keymap_file(keymap_name: &str, keep: usize) -> Result<(), io::Error> {
+    let keymap_path = Keymap::keymap_path(keymap_name);
+    if !keymap_path.exists() || keep == 0 {
+        return Ok(());
+    }
+
+    fs::create_dir_all(backup_dir())?;
+
+    let backup_path = new_backup_path(keymap_name, now_ms(), None);
+    write_atomically(&backup_path, &fs::read(keymap_path)?)?;
+
+    // migration backups are left alone, they are the only copy of the old format
+    let rotating: Vec<Backup> = list(Some(keymap_name))?
+        .into_iter()
+        .filter(|backup| backup.schema_version.is_none())
+        .collect();
+
+    for backup in rotating.iter().skip(keep) {
+        if let Err(err) = fs::remove_file(backup.path()) {
+            eprintln!(
+                "Failed to remove old keymap backup {}: {}",
+                backup.file_name, err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps a keymap file's contents from before a schema migration
+pub fn backup_before_migration(
+    keymap_name: &str,
+    version: u64,
+    keymap_json: &str,
+) -> Result<PathBuf, io::Error> {
+    fs::create_dir_all(backup_dir())?;
+
+    let backup_path = new_backup_path(keymap_name, now_ms(), Some(version));
+    write_atomically(&backup_path, keymap_json.as_bytes())?;
+
+    Ok(backup_path)
+}
+
+/// Backups of one keymap, or of all of them, newest first
+pub fn list(keymap_name: Option<&str>) -> Result<Vec<Backup>, io::Error> {
+    let backup_dir = backup_dir();
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups: Vec<Backup> = Vec::new();
+    for entry in fs::read_dir(backup_dir)? {
+        let entry = entry?;
+
+        let backup = match entry.file_name().to_str().and_then(Backup::from_file_name) {
+            None => continue,
+            Some(backup) => backup,
+        };
+
+        if keymap_name.is_some_and(|name| name != backup.keymap_name) {
+            continue;
+        }
+
+        backups.push(backup);
+    }
+
+    backups.sort_by_key(|backup| Reverse(backup.created_ms));
+    Ok(backups)
+}
+
+/// Looks up a backup by file name, only accepting names `list` would return
+pub fn find(file_name: &str) -> Result<Backup, io::Error> {
+    let backup = Backup::from_file_name(file_name)
+        .filter(|_| !file_name.contains(['/', '\\']))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't a keymap backup", file_name),
+            )
+        })?;
+
+    if !backup.path().exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No keymap backup named {}", file_name),
+        ));
+    }
+
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::use_temp_data_dir;
+
+    fn parse(file_name: &str) -> Option<(String, u128, Option<u64>)> {
+        Backup::from_file_name(file_name)
+            .map(|backup| (backup.keymap_name, backup.created_ms, backup.schema_version))
+    }
+
+    #[test]
+    fn parses_plain_and_migration_backups() {
+        assert_eq!(
+            parse("game.1700.json"),
+            Some(("game".to_string(), 1700, None))
+        );
+        assert_eq!(
+            parse("game.1700.v0.json"),
+            Some(("game".to_string(), 1700, Some(0)))
+        );
+    }
+
+    #[test]
+    fn profile_names_with_dots_keep_their_name() {
+        assert_eq!(
+            parse("game.v2.1700.json"),
+            Some(("game.v2".to_string(), 1700, None))
+        );
+        assert_eq!(
+            parse("game.v2.1700.v0.json"),
+            Some(("game.v2".to_string(), 1700, Some(0)))
+        );
+        assert_eq!(
+            parse("a.b.5.1700.json"),
+            Some(("a.b.5".to_string(), 1700, None))
+        );
+    }
+
+    #[test]
+    fn other_files_are_not_backups() {
+        assert_eq!(parse("game.json"), None);
+        assert_eq!(parse("game.1700.vx.json"), None);
+        assert_eq!(parse("game.new.json"), None);
+        assert_eq!(parse("game.1700.json.tmp"), None);
+    }
+
+    #[test]
+    fn backups_taken_in_the_same_millisecond_get_their_own_names() {
+        use_temp_data_dir();
+        fs::create_dir_all(backup_dir()).unwrap();
+
+        let taken = new_backup_path("same-ms", 1700, None);
+        assert_eq!(taken.file_name().unwrap(), "same-ms.1700.json");
+        fs::write(&taken, "{}").unwrap();
+
+        let next = new_backup_path("same-ms", 1700, None);
+        assert_eq!(next.file_name().unwrap(), "same-ms.1701.json");
+        assert_eq!(
+            new_backup_path("same-ms", 1700, Some(0))
+                .file_name()
+                .unwrap(),
+            "same-ms.1700.v0.json"
+        );
+    }
+
+    #[test]
+    fn quick_saves_keep_the_newest_backups() {
+        use_temp_data_dir();
+        for count in 1..=6 {
+            Keymap::save_to_file(Keymap::new("quick-saves".to_string(), count), 3).unwrap();
+        }
+
+        // the first save had no file to back up yet
+        let backups = list(Some("quick-saves")).unwrap();
+        assert_eq!(backups.len(), 3);
+
+        let button_counts: Vec<i32> = backups
+            .iter()
+            .map(|backup| {
+                let keymap: Keymap =
+                    serde_json::from_str(&fs::read_to_string(backup.path()).unwrap()).unwrap();
+                keymap.button_count
+            })
+            .collect();
+        assert_eq!(button_counts, vec![5, 4, 3]);
+    }
+}
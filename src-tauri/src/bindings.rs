@@ -0,0 +1,257 @@
+// only the linux listeners report plain keys
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::input_devices::{DeviceSelector, InputDevice};
+use crate::key_queue::KeyState;
+use crate::keymap::{Key, Keymap};
+use crate::linux_keycodes;
+use crate::programmable_keys::ProgrammableKeys;
+
+/// Modifier keys a chord can need, either side of the keyboard counts
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Modifier {
+    Ctrl,
+    Shift,
+    Alt,
+    Meta,
+}
+
+impl Modifier {
+    /// The modifier a linux key code belongs to, if it is one
+    pub fn from_code(code: u32) -> Option<Modifier> {
+        match code {
+            29 | 97 => Some(Modifier::Ctrl),
+            42 | 54 => Some(Modifier::Shift),
+            56 | 100 => Some(Modifier::Alt),
+            125 | 126 => Some(Modifier::Meta),
+            _ => None,
+        }
+    }
+}
+
+/// A regular keyboard key, optionally with modifiers held, used as a macro
+/// button on keyboards without programmable buttons. Linux only.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct KeyBinding {
+    pub key: Key,
+    /// modifiers that have to be held when the key is pressed, and no others
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modifiers: Vec<Modifier>,
+    /// only count the key from matching devices, any device if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceSelector>,
+}
+
+impl KeyBinding {
+    fn matches(&self, code: u32, modifiers: &BTreeSet<Modifier>, device: &InputDevice) -> bool {
+        let needed: BTreeSet<Modifier> = self.modifiers.iter().copied().collect();
+
+        linux_keycodes::code_for_key(&self.key) == code
+            && needed == *modifiers
+            && self
+                .device
+                .as_ref()
+                .is_none_or(|selector| selector.matches(device))
+    }
+}
+
+/// Turns the plain key events of a keyboard into presses and releases of the
+/// keymap's key bindings. A binding is released with its key, even if its
+/// modifiers were let go first.
+pub struct ChordTracker {
+    keymap: Arc<Mutex<Keymap>>,
+    /// modifier keys held down, by key code
+    modifiers: HashMap<u32, Modifier>,
+    /// keys held down as a binding, by key code
+    pressed: HashMap<u32, ProgrammableKeys>,
+}
+
+impl ChordTracker {
+    pub fn new(keymap: Arc<Mutex<Keymap>>) -> ChordTracker {
+        ChordTracker {
+            keymap,
+            modifiers: HashMap::new(),
+            pressed: HashMap::new(),
+        }
+    }
+
+    /// The binding a key press or release is for, if any. Bindings on every
+    /// layer count, the key handler picks the one from the active layers.
+    pub fn on_key(
+        &mut self,
+        code: u32,
+        state: KeyState,
+        device: &InputDevice,
+    ) -> Option<ProgrammableKeys> {
+        if state == KeyState::Released {
+            self.modifiers.remove(&code);
+            return self.pressed.remove(&code);
+        }
+
+        let modifiers: BTreeSet<Modifier> = self.modifiers.values().copied().collect();
+        let binding = match self.keymap.lock() {
+            Ok(keymap) => keymap
+                .key_bindings()
+                .find(|binding| binding.matches(code, &modifiers, device))
+                .cloned(),
+            Err(err) => {
+                eprintln!("Error retrieving keymap lock: {}", err);
+                None
+            }
+        };
+
+        match binding {
+            Some(binding) => {
+                let key = ProgrammableKeys::Binding(Box::new(binding));
+                self.pressed.insert(code, key.clone());
+                Some(key)
+            }
+            None => {
+                if let Some(modifier) = Modifier::from_code(code) {
+                    self.modifiers.insert(code, modifier);
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether a key is held down as a binding
+    pub fn is_pressed(&self, code: u32) -> bool {
+        self.pressed.contains_key(&code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEFT_CTRL: u32 = 29;
+    const RIGHT_CTRL: u32 = 97;
+    const LEFT_SHIFT: u32 = 42;
+    const LEFT_ALT: u32 = 56;
+
+    fn device(vendor: u16) -> InputDevice {
+        InputDevice {
+            path: "/dev/input/event4".to_string(),
+            name: "Keyboard".to_string(),
+            vendor,
+            product: 0x0001,
+            phys: String::new(),
+        }
+    }
+
+    fn binding(key: Key, modifiers: Vec<Modifier>) -> KeyBinding {
+        KeyBinding {
+            key,
+            modifiers,
+            device: None,
+        }
+    }
+
+    /// A tracker for a keymap whose first buttons are these bindings
+    fn tracker(bindings: Vec<KeyBinding>) -> ChordTracker {
+        let mut keymap = Keymap::new("bindings".to_string(), bindings.len() as i32);
+        for (macro_key, binding) in keymap.buttons.iter_mut().zip(bindings) {
+            macro_key.programmable_key = ProgrammableKeys::Binding(Box::new(binding));
+        }
+        ChordTracker::new(Arc::new(Mutex::new(keymap)))
+    }
+
+    fn press(tracker: &mut ChordTracker, code: u32) -> Option<ProgrammableKeys> {
+        tracker.on_key(code, KeyState::Pressed, &device(1))
+    }
+
+    fn release(tracker: &mut ChordTracker, code: u32) -> Option<ProgrammableKeys> {
+        tracker.on_key(code, KeyState::Released, &device(1))
+    }
+
+    fn key_code(key: Key) -> u32 {
+        linux_keycodes::code_for_key(&key)
+    }
+
+    #[test]
+    fn modifiers_can_be_pressed_in_either_order() {
+        let chord = binding(Key::KeyC, vec![Modifier::Ctrl, Modifier::Shift]);
+        let button = Some(ProgrammableKeys::Binding(Box::new(chord.clone())));
+        let c = key_code(Key::KeyC);
+
+        for modifiers in [[LEFT_CTRL, LEFT_SHIFT], [LEFT_SHIFT, LEFT_CTRL]] {
+            let mut tracker = tracker(vec![chord.clone()]);
+            for modifier in modifiers {
+                assert_eq!(press(&mut tracker, modifier), None);
+            }
+
+            assert_eq!(press(&mut tracker, c), button);
+            assert!(tracker.is_pressed(c));
+            assert_eq!(release(&mut tracker, c), button);
+        }
+    }
+
+    #[test]
+    fn extra_or_missing_modifiers_dont_match() {
+        let mut tracker = tracker(vec![binding(Key::KeyC, vec![Modifier::Ctrl])]);
+        let c = key_code(Key::KeyC);
+
+        assert_eq!(press(&mut tracker, c), None);
+        assert_eq!(release(&mut tracker, c), None);
+
+        press(&mut tracker, LEFT_CTRL);
+        press(&mut tracker, LEFT_ALT);
+        assert_eq!(press(&mut tracker, c), None);
+        assert!(!tracker.is_pressed(c));
+    }
+
+    #[test]
+    fn either_side_of_a_modifier_counts() {
+        let chord = binding(Key::KeyC, vec![Modifier::Ctrl]);
+        let button = Some(ProgrammableKeys::Binding(Box::new(chord.clone())));
+        let c = key_code(Key::KeyC);
+
+        for ctrl in [LEFT_CTRL, RIGHT_CTRL] {
+            let mut tracker = tracker(vec![chord.clone()]);
+            press(&mut tracker, ctrl);
+            assert_eq!(press(&mut tracker, c), button);
+        }
+    }
+
+    #[test]
+    fn device_selector_picks_the_keyboard() {
+        let mut chord = binding(Key::F12, Vec::new());
+        chord.device = Some(DeviceSelector {
+            vendor: Some(0xfeed),
+            ..DeviceSelector::default()
+        });
+        let mut tracker = tracker(vec![chord.clone()]);
+        let f12 = key_code(Key::F12);
+
+        assert_eq!(tracker.on_key(f12, KeyState::Pressed, &device(1)), None);
+        assert_eq!(
+            tracker.on_key(f12, KeyState::Pressed, &device(0xfeed)),
+            Some(ProgrammableKeys::Binding(Box::new(chord)))
+        );
+    }
+
+    #[test]
+    fn releases_clear_the_held_keys() {
+        let chord = binding(Key::KeyC, vec![Modifier::Ctrl]);
+        let button = Some(ProgrammableKeys::Binding(Box::new(chord.clone())));
+        let mut tracker = tracker(vec![chord]);
+        let c = key_code(Key::KeyC);
+
+        // the binding is released with its key, even after its modifier
+        press(&mut tracker, LEFT_CTRL);
+        assert_eq!(press(&mut tracker, c), button);
+        assert_eq!(release(&mut tracker, LEFT_CTRL), None);
+        assert_eq!(release(&mut tracker, c), button);
+        assert!(!tracker.is_pressed(c));
+        assert_eq!(release(&mut tracker, c), None);
+
+        // a released modifier no longer counts
+        assert_eq!(press(&mut tracker, c), None);
+    }
+}
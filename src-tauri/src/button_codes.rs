@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::input_devices::{DeviceSelector, InputDevice};
+use crate::programmable_keys::ProgrammableKeys;
+
+fn one() -> u32 {
+    1
+}
+
+/// A run of consecutive codes that programmable buttons send, labelled in
+/// order. The number at the end of the first label counts up through the
+/// range, so 656 labelled MACRO1 makes 657 MACRO2 and so on.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct CodeRange {
+    pub first_code: u32,
+    /// how many codes the range covers
+    #[serde(default = "one")]
+    pub count: u32,
+    pub first_label: String,
+    /// only count the codes from matching devices, any device if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceSelector>,
+}
+
+impl CodeRange {
+    fn new(first_code: u32, count: u32, first_label: &str) -> CodeRange {
+        CodeRange {
+            first_code,
+            count,
+            first_label: first_label.to_string(),
+            device: None,
+        }
+    }
+
+    /// The label of the code this far into the range
+    fn label_at(&self, offset: u32) -> String {
+        if offset == 0 {
+            return self.first_label.clone();
+        }
+
+        let (prefix, number) = split_number(&self.first_label);
+        format!("{}{}", prefix, number + u64::from(offset))
+    }
+
+    fn label_for(&self, code: u32) -> Option<String> {
+        code.checked_sub(self.first_code)
+            .filter(|offset| *offset < self.count)
+            .map(|offset| self.label_at(offset))
+    }
+
+    fn has_label(&self, label: &str) -> bool {
+        let (prefix, number) = split_number(label);
+        let (first_prefix, first_number) = split_number(&self.first_label);
+
+        prefix == first_prefix
+            && number
+                .checked_sub(first_number)
+                .and_then(|offset| u32::try_from(offset).ok())
+                .is_some_and(|offset| offset < self.count && self.label_at(offset) == label)
+    }
+}
+
+/// Splits a label into its text and the number it ends with, zero if it has none
+fn split_number(label: &str) -> (&str, u64) {
+    let prefix = label.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = label[prefix.len()..].parse().unwrap_or(0);
+    (prefix, number)
+}
+
+/// The codes the QMK programmable buttons send on this platform
+// https://docs.qmk.fm/#/feature_programmable_button
+pub fn default_ranges() -> Vec<CodeRange> {
+    platform_ranges(cfg!(target_os = "windows"))
+}
+
+fn platform_ranges(windows: bool) -> Vec<CodeRange> {
+    if windows {
+        // raw input messages, these don't follow a pattern
+        [
+            261, 517, 1029, 2053, 4101, 8197, 16389, 32773, 65541, 131077, 262149, 524293, 1048581,
+            2097157, 4194309, 8388613, 16777221, 33554437, 207, 134217733, 208, 209, 210, 211, 212,
+            213, 214, 215, 216, 217, 218, 219,
+        ]
+        .iter()
+        .enumerate()
+        .map(|(index, code)| CodeRange::new(*code, 1, &format!("MACRO{}", index + 1)))
+        .collect()
+    } else {
+        // KEY_MACRO1 to KEY_MACRO30, and two codes past them
+        vec![CodeRange::new(656, 32, "MACRO1")]
+    }
+}
+
+/// Looks up which programmable button a code is, for the listeners
+#[derive(Clone, Debug)]
+pub struct ButtonCodes {
+    ranges: Arc<Vec<CodeRange>>,
+}
+
+impl ButtonCodes {
+    pub fn new(ranges: Vec<CodeRange>) -> ButtonCodes {
+        ButtonCodes {
+            ranges: Arc::new(ranges),
+        }
+    }
+
+    pub fn ranges(&self) -> &[CodeRange] {
+        &self.ranges
+    }
+
+    /// The button a device sent a code for, if it is one. Earlier ranges win,
+    /// so device specific ranges go before the ones for every device.
+    pub fn button_for(&self, code: u32, device: &InputDevice) -> Option<ProgrammableKeys> {
+        self.ranges
+            .iter()
+            .filter(|range| {
+                range
+                    .device
+                    .as_ref()
+                    .is_none_or(|selector| selector.matches(device))
+            })
+            .find_map(|range| range.label_for(code))
+            .map(ProgrammableKeys::Button)
+    }
+}
+
+/// Whether any range has a code labelled this way, whatever the device
+pub fn is_known_label(ranges: &[CodeRange], label: &str) -> bool {
+    ranges.iter().any(|range| range.has_label(label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The codes each button had before the ranges were configurable
+    const LINUX_CODES: [u32; 32] = [
+        656, 657, 658, 659, 660, 661, 662, 663, 664, 665, 666, 667, 668, 669, 670, 671, 672, 673,
+        674, 675, 676, 677, 678, 679, 680, 681, 682, 683, 684, 685, 686, 687,
+    ];
+    const WINDOWS_CODES: [u32; 32] = [
+        261, 517, 1029, 2053, 4101, 8197, 16389, 32773, 65541, 131077, 262149, 524293, 1048581,
+        2097157, 4194309, 8388613, 16777221, 33554437, 207, 134217733, 208, 209, 210, 211, 212,
+        213, 214, 215, 216, 217, 218, 219,
+    ];
+
+    fn device() -> InputDevice {
+        InputDevice {
+            path: "/dev/input/event3".to_string(),
+            name: "Macro Pad".to_string(),
+            vendor: 0xfeed,
+            product: 0x6060,
+            phys: "usb-0000:00:14.0-2/input1".to_string(),
+        }
+    }
+
+    fn label(codes: &ButtonCodes, code: u32) -> Option<String> {
+        match codes.button_for(code, &device()) {
+            Some(ProgrammableKeys::Button(label)) => Some(label),
+            _ => None,
+        }
+    }
+
+    const PLATFORMS: [(bool, [u32; 32]); 2] = [(false, LINUX_CODES), (true, WINDOWS_CODES)];
+
+    #[test]
+    fn default_ranges_label_the_codes_like_before() {
+        for (windows, platform_codes) in PLATFORMS {
+            let codes = ButtonCodes::new(platform_ranges(windows));
+
+            for (index, code) in platform_codes.into_iter().enumerate() {
+                assert_eq!(
+                    label(&codes, code),
+                    Some(format!("MACRO{}", index + 1)),
+                    "code {}",
+                    code
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn codes_just_outside_the_ranges_are_not_buttons() {
+        for (windows, platform_codes) in PLATFORMS {
+            let codes = ButtonCodes::new(platform_ranges(windows));
+
+            for range in codes.ranges() {
+                let before = range.first_code - 1;
+                if !platform_codes.contains(&before) {
+                    assert_eq!(label(&codes, before), None, "code {}", before);
+                }
+
+                let after = range.first_code + range.count;
+                if !platform_codes.contains(&after) {
+                    assert_eq!(label(&codes, after), None, "code {}", after);
+                }
+            }
+            assert_eq!(label(&codes, 0), None);
+            assert_eq!(label(&codes, u32::MAX), None);
+        }
+    }
+
+    #[test]
+    fn labels_are_known_only_inside_a_range() {
+        let ranges = default_ranges();
+
+        assert!(is_known_label(&ranges, "MACRO1"));
+        assert!(is_known_label(&ranges, "MACRO32"));
+        assert!(!is_known_label(&ranges, "MACRO0"));
+        assert!(!is_known_label(&ranges, "MACRO33"));
+        assert!(!is_known_label(&ranges, "MACROUNKNOWN"));
+    }
+
+    #[test]
+    fn device_ranges_only_count_for_their_device() {
+        let mut pad_range = CodeRange::new(30, 2, "PAD1");
+        pad_range.device = Some(DeviceSelector {
+            vendor: Some(0xfeed),
+            ..DeviceSelector::default()
+        });
+        let mut other_range = CodeRange::new(30, 2, "OTHER1");
+        other_range.device = Some(DeviceSelector {
+            vendor: Some(0xbeef),
+            ..DeviceSelector::default()
+        });
+        let codes = ButtonCodes::new(vec![other_range, pad_range, CodeRange::new(30, 3, "ANY1")]);
+
+        assert_eq!(label(&codes, 31), Some("PAD2".to_string()));
+        assert_eq!(label(&codes, 32), Some("ANY3".to_string()));
+        assert_eq!(label(&codes, 29), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::control_socket;
+use crate::control_socket::Request;
+use crate::keymap::{Keymap, MacroKey, Trigger};
+use crate::keymap_formats;
+use crate::macro_script;
+use crate::profiles::Profiles;
+use crate::programmable_keys::ProgrammableKeys;
+use crate::settings::{Settings, SharedSettings};
+
+const USAGE: &str = "Usage:
+  hotmap                                 start the app
+  hotmap --headless [--keymap <name>]    run macros without the window
+  hotmap list                            list keymap profiles, * marks the active one
+  hotmap show <keymap>                   print a profile's buttons as macro scripts
+  hotmap validate <file>                 check a json, toml or yaml keymap file
+  hotmap set <keymap> <button> <script>  replace a button's macro with a script
+  hotmap activate <profile>              switch the active profile
+  hotmap trigger <button>                press a button in the running app
+
+A <button> is a label like MACRO5 or a key binding like Ctrl+Shift+F12,
+bindings limited to one device can only be changed in the keymap file.";
+
+/// Runs a cli subcommand, None if the arguments aren't one so the app starts as usual.
+/// Commands go through the running instance when there is one, so it stays in sync.
+pub fn run(args: &[String]) -> Option<Result<(), String>> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["list"] => list(),
+        ["show", keymap] => show(keymap),
+        ["validate", file] => validate(Path::new(file)),
+        ["set", keymap, button, script] => set(keymap, button, script),
+        ["activate", profile] => activate(profile),
+        ["trigger", button] => trigger(button),
+        ["help" | "--help" | "-h"] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        [command, ..] if is_command(command) => Err(USAGE.to_string()),
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+fn is_command(arg: &str) -> bool {
+    matches!(
+        arg,
+        "list" | "show" | "validate" | "set" | "activate" | "trigger"
+    )
+}
+
+fn list() -> Result<(), String> {
+    let active = match control_socket::send(&Request::ActiveProfile) {
+        Some(Ok(Value::String(name))) => name,
+        _ => Settings::load().active_profile,
+    };
+
+    for profile in Profiles::list().map_err(|err| err.to_string())? {
+        let marker = if profile == active { "*" } else { " " };
+        println!("{} {}", marker, profile);
+    }
+    Ok(())
+}
+
+fn show(keymap_name: &str) -> Result<(), String> {
+    // read the file without loading it as a profile, so nothing is migrated or written back
+    let keymap_path = Keymap::keymap_path(keymap_name);
+    if !keymap_path.exists() {
+        return Err(format!("No keymap profile named {}", keymap_name));
+    }
+
+    let keymap_json = fs::read_to_string(keymap_path).map_err(|err| err.to_string())?;
+    let ranges = Settings::load().button_codes;
+    let (keymap, _) = Keymap::from_json(keymap_name.to_string(), &keymap_json, &ranges)
+        .map_err(|err| err.to_string())?;
+
+    println!("{} ({} buttons)", keymap.map_name, keymap.buttons.len());
+    print_buttons(&keymap.buttons, "");
+
+    for layer in &keymap.layers {
+        println!("layer {}", layer.name);
+        print_buttons(&layer.buttons, "  ");
+    }
+    Ok(())
+}
+
+/// Prints each button's actions as a script, a line per trigger it has actions for
+fn print_buttons(buttons: &[MacroKey], indent: &str) {
+    let triggers = [
+        (Trigger::Press, ""),
+        (Trigger::Release, " on release"),
+        (Trigger::Hold, " on hold"),
+        (Trigger::DoubleTap, " on double tap"),
+        (Trigger::TripleTap, " on triple tap"),
+    ];
+
+    for macro_key in buttons {
+        for (trigger, name) in &triggers {
+            if let Some(actions) = macro_key.actions_for(trigger) {
+                println!(
+                    "{}{}{}: {}",
+                    indent,
+                    macro_key.programmable_key,
+                    name,
+                    macro_script::print(actions)
+                );
+            }
+        }
+    }
+}
+
+fn validate(path: &Path) -> Result<(), String> {
+    // warnings are printed while the keymap is read
+    match keymap_formats::import_keymap(path, &Settings::load().button_codes) {
+        Ok(_) => {
+            println!("{} is a valid keymap", path.display());
+            Ok(())
+        }
+        Err(err) => {
+            for diagnostic in err.diagnostics() {
+                match (diagnostic.line, diagnostic.column) {
+                    (Some(line), Some(column)) => {
+                        println!(
+                            "{}:{}:{}: {}",
+                            path.display(),
+                            line,
+                            column,
+                            diagnostic.message
+                        )
+                    }
+                    _ => println!("{}: {}", path.display(), diagnostic.message),
+                }
+            }
+            Err(format!("{} is not a valid keymap", path.display()))
+        }
+    }
+}
+
+fn set(keymap_name: &str, button: &str, script: &str) -> Result<(), String> {
+    let settings = Settings::load();
+    let button = ProgrammableKeys::parse(button, &settings.button_codes);
+    let actions = macro_script::parse(script).map_err(|err| err.to_string())?;
+
+    let request = Request::SetButtonActions {
+        keymap: keymap_name.to_string(),
+        button: button.clone(),
+        actions: actions.clone(),
+    };
+
+    match control_socket::send(&request) {
+        Some(result) => result.map(|_| ()),
+        None => Profiles::set_stored_button_actions(
+            keymap_name,
+            &button,
+            actions,
+            &settings.button_codes,
+            settings.keymap_backups,
+        )
+        .map_err(|err| err.to_string()),
+    }
+}
+
+fn activate(profile: &str) -> Result<(), String> {
+    let request = Request::ActivateProfile {
+        name: profile.to_string(),
+    };
+
+    if let Some(result) = control_socket::send(&request) {
+        return result.map(|_| ());
+    }
+
+    // nothing is running, so just pick the profile the app starts with
+    if !Keymap::keymap_path(profile).exists() {
+        return Err(format!("No keymap profile named {}", profile));
+    }
+
+    SharedSettings::new(Settings::load())
+        .update(|settings| settings.active_profile = profile.to_string())
+        .map_err(|err| err.to_string())
+}
+
+fn trigger(button: &str) -> Result<(), String> {
+    let request = Request::Trigger {
+        button: ProgrammableKeys::parse(button, &Settings::load().button_codes),
+    };
+
+    match control_socket::send(&request) {
+        Some(result) => result.map(|_| ()),
+        None => Err("HotMap isn't running, start it to trigger buttons".to_string()),
+    }
+}
@@ -0,0 +1,698 @@
+// the socket is only served on linux, elsewhere the cli works on the files alone
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::keymap::{Keymap, MacroAction, MacroKey};
+use crate::programmable_keys::ProgrammableKeys;
+
+/// A command sent to a running instance, one json object per line. The
+/// keymap commands work like the tauri commands of the same name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// the active keymap
+    SendKeymap,
+    /// adds a button to the active keymap without saving it
+    AddButton {
+        button: MacroKey,
+    },
+    SaveKeymap {
+        keymap: Keymap,
+    },
+    /// name of the active keymap profile
+    ActiveProfile,
+    ActivateProfile {
+        name: String,
+    },
+    /// replaces a button's actions in a profile's base layer
+    SetButtonActions {
+        keymap: String,
+        button: ProgrammableKeys,
+        actions: Vec<MacroAction>,
+    },
+    /// presses and releases a button as if it came from a keyboard
+    Trigger {
+        button: ProgrammableKeys,
+    },
+    /// turns the connection into a stream of events, a line of json each
+    Subscribe,
+}
+
+/// The answer to a request, one json object per line
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+impl From<Result<Value, String>> for Response {
+    fn from(result: Result<Value, String>) -> Self {
+        match result {
+            Ok(value) => Response::Ok(value),
+            Err(err) => Response::Error(err),
+        }
+    }
+}
+
+impl From<Response> for Result<Value, String> {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Ok(value) => Ok(value),
+            Response::Error(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use client::send;
+
+#[cfg(not(target_os = "linux"))]
+pub fn send(_request: &Request) -> Option<Result<Value, String>> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod client {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+
+    use serde_json::Value;
+
+    use super::{Request, Response};
+
+    /// Where a running instance listens, in the user's runtime folder, or a
+    /// private folder in the temp folder for sessions that don't have one
+    pub fn socket_path() -> Result<PathBuf, io::Error> {
+        match env::var_os("XDG_RUNTIME_DIR") {
+            Some(runtime_dir) => Ok(PathBuf::from(runtime_dir).join("hotmap.sock")),
+            None => Ok(private_temp_dir()?.join("hotmap.sock")),
+        }
+    }
+
+    /// A folder in the temp folder only we can get into. Anyone can create it
+    /// first in the shared temp folder, so it is only used if it is ours.
+    fn private_temp_dir() -> Result<PathBuf, io::Error> {
+        let uid = user_id();
+        let dir = env::temp_dir().join(format!("hotmap-{}", uid));
+
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+
+        // symlink_metadata so a link to someone else's folder isn't followed
+        let metadata = fs::symlink_metadata(&dir)?;
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{:?} isn't a private folder of this user", dir),
+            ));
+        }
+        Ok(dir)
+    }
+
+    pub fn user_id() -> u32 {
+        unsafe { libc::getuid() }
+    }
+
+    /// Sends a request to the running instance, None if there isn't one
+    pub fn send(request: &Request) -> Option<Result<Value, String>> {
+        let path = match socket_path() {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("Not looking for a running instance: {}", err);
+                return None;
+            }
+        };
+
+        let stream = UnixStream::connect(path).ok()?;
+        Some(
+            exchange(stream, request)
+                .map_err(|err| err.to_string())
+                .and_then(Result::from),
+        )
+    }
+
+    fn exchange(
+        mut stream: UnixStream,
+        request: &Request,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let mut request_json = serde_json::to_string(request)?;
+        request_json.push('\n');
+        stream.write_all(request_json.as_bytes())?;
+
+        let mut response_json = String::new();
+        BufReader::new(stream).read_line(&mut response_json)?;
+        Ok(serde_json::from_str(&response_json)?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use server::{serve, ControlServer};
+
+#[cfg(target_os = "linux")]
+mod server {
+    use std::fs;
+    use std::io;
+    use std::io::{BufRead, BufReader, ErrorKind, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::client::{socket_path, user_id};
+    use super::{Request, Response};
+    use crate::events::EventSink;
+    use crate::key_queue::{KeyEvent, KeySender, KeyState};
+    use crate::keymap::Keymap;
+    use crate::profiles::Profiles;
+
+    /// how long a subscriber can leave an event unread before it is disconnected
+    const EVENT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// What the socket needs from the running instance to answer requests
+    #[derive(Clone)]
+    pub struct ControlServer {
+        pub keymap: Arc<Mutex<Keymap>>,
+        pub profiles: Profiles,
+        pub events: EventSink,
+        pub keys: KeySender,
+    }
+
+    impl ControlServer {
+        /// Answers a request, except for subscribing which is up to the connection
+        pub fn handle(&self, request: Request) -> Result<Value, String> {
+            match request {
+                Request::SendKeymap => {
+                    let keymap = match self.keymap.lock() {
+                        Ok(keymap) => keymap.clone(),
+                        Err(_) => {
+                            panic!("Failed to acquire keymap lock")
+                        }
+                    };
+                    serde_json::to_value(keymap).map_err(|err| err.to_string())
+                }
+                Request::AddButton { button } => {
+                    match self.keymap.lock() {
+                        Ok(mut keymap) => keymap.add_button(button),
+                        Err(_) => {
+                            panic!("Failed to acquire keymap lock")
+                        }
+                    }
+                    Ok(Value::Null)
+                }
+                Request::SaveKeymap { keymap } => self
+                    .profiles
+                    .save_keymap(keymap)
+                    .map(|_| Value::Null)
+                    .map_err(|err| err.to_string()),
+                Request::ActiveProfile => Ok(Value::String(self.profiles.active())),
+                Request::ActivateProfile { name } => self
+                    .profiles
+                    .activate(&name)
+                    .map(|_| Value::Null)
+                    .map_err(|err| err.to_string()),
+                Request::SetButtonActions {
+                    keymap,
+                    button,
+                    actions,
+                } => self
+                    .profiles
+                    .set_profile_button_actions(&keymap, &button, actions)
+                    .map(|_| Value::Null)
+                    .map_err(|err| err.to_string()),
+                Request::Trigger { button } => {
+                    for state in [KeyState::Pressed, KeyState::Released] {
+                        let event =
+                            KeyEvent::new(button.clone(), state, Some("control socket".into()));
+                        if !self.keys.send(event) {
+                            return Err("The key queue dropped the button press".to_string());
+                        }
+                    }
+                    Ok(Value::Null)
+                }
+                // answered by the connection, which starts streaming
+                Request::Subscribe => Ok(Value::Null),
+            }
+        }
+
+        /// Answers requests a line at a time until the connection is closed or subscribes
+        pub fn handle_connection(&self, stream: UnixStream) {
+            let mut writer = match stream.try_clone() {
+                Ok(writer) => writer,
+                Err(err) => {
+                    eprintln!("Failed to set up control connection: {}", err);
+                    return;
+                }
+            };
+
+            for line in BufReader::new(stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let request = serde_json::from_str::<Request>(&line);
+
+                // subscribe before answering, so no event after the answer is missed
+                let events = match request {
+                    Ok(Request::Subscribe) => Some(self.events.subscribe()),
+                    _ => None,
+                };
+
+                let response: Response = match request {
+                    Ok(request) => self.handle(request).into(),
+                    Err(err) => Response::Error(format!("Invalid request: {}", err)),
+                };
+
+                let mut response_json =
+                    serde_json::to_string(&response).expect("Failed to serialize response!");
+                response_json.push('\n');
+                if writer.write_all(response_json.as_bytes()).is_err() {
+                    break;
+                }
+
+                if let Some(events) = events {
+                    stream_events(events, &mut writer);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Writes every event to the connection until it is closed or stops reading
+    fn stream_events(events: Receiver<String>, writer: &mut UnixStream) {
+        if let Err(err) = writer.set_write_timeout(Some(EVENT_WRITE_TIMEOUT)) {
+            eprintln!("Failed to set up event stream: {}", err);
+            return;
+        }
+
+        for mut line in events {
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// The user on the other end of a connection
+    fn peer_uid(stream: &UnixStream) -> Result<u32, io::Error> {
+        let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(credentials.uid)
+    }
+
+    /// Listens on the control socket, answering each connection on its own thread
+    pub fn serve(server: ControlServer) {
+        let path = match socket_path() {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("Not opening the control socket: {}", err);
+                return;
+            }
+        };
+
+        // a socket file nobody answers on is left over from an instance that didn't shut down cleanly
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                eprintln!("Another instance is already listening on {:?}", path);
+                return;
+            }
+            if let Err(err) = fs::remove_file(&path) {
+                if err.kind() != ErrorKind::NotFound {
+                    eprintln!("Failed to remove stale control socket: {}", err);
+                }
+            }
+        }
+
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("Failed to open control socket {:?}: {}", path, err);
+                return;
+            }
+        };
+
+        // only the user running the app gets to control it
+        if let Err(err) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+            eprintln!("Failed to restrict control socket permissions: {}", err);
+        }
+        println!("Listening for commands on {:?}", path);
+
+        for stream in listener.incoming() {
+            match stream {
+                // the socket's permissions should keep other users out already
+                Ok(stream) => match peer_uid(&stream) {
+                    Ok(uid) if uid == user_id() => {
+                        let server = server.clone();
+                        thread::spawn(move || server.handle_connection(stream));
+                    }
+                    Ok(uid) => eprintln!("Refused a control connection from user {}", uid),
+                    Err(err) => eprintln!("Failed to check a control connection's user: {}", err),
+                },
+                Err(err) => eprintln!("Failed to accept control connection: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::keymap::Key;
+
+    fn assert_round_trips(request: Request, expected: Value) {
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value, expected);
+
+        let read_back: Request = serde_json::from_value(value).unwrap();
+        assert_eq!(serde_json::to_value(read_back).unwrap(), expected);
+    }
+
+    #[test]
+    fn requests_are_tagged_with_their_command() {
+        let button = ProgrammableKeys::Button("MACRO4".to_string());
+        let macro_key = MacroKey::new(button.clone());
+        let keymap = Keymap::new("socket".to_string(), 1);
+
+        assert_round_trips(Request::SendKeymap, json!({ "command": "send_keymap" }));
+        assert_round_trips(
+            Request::AddButton {
+                button: macro_key.clone(),
+            },
+            json!({ "command": "add_button", "button": macro_key }),
+        );
+        assert_round_trips(
+            Request::SaveKeymap {
+                keymap: keymap.clone(),
+            },
+            json!({ "command": "save_keymap", "keymap": keymap }),
+        );
+        assert_round_trips(
+            Request::ActiveProfile,
+            json!({ "command": "active_profile" }),
+        );
+        assert_round_trips(
+            Request::ActivateProfile {
+                name: "games".to_string(),
+            },
+            json!({ "command": "activate_profile", "name": "games" }),
+        );
+        assert_round_trips(
+            Request::SetButtonActions {
+                keymap: "games".to_string(),
+                button: button.clone(),
+                actions: vec![MacroAction::Tap(Key::KeyA)],
+            },
+            json!({
+                "command": "set_button_actions",
+                "keymap": "games",
+                "button": "MACRO4",
+                "actions": [{ "Tap": "KeyA" }],
+            }),
+        );
+        assert_round_trips(
+            Request::Trigger { button },
+            json!({ "command": "trigger", "button": "MACRO4" }),
+        );
+        assert_round_trips(Request::Subscribe, json!({ "command": "subscribe" }));
+    }
+
+    #[test]
+    fn responses_wrap_results() {
+        let ok = Response::from(Ok(json!({ "buttons": 3 })));
+        assert_eq!(
+            serde_json::to_value(&ok).unwrap(),
+            json!({ "ok": { "buttons": 3 } })
+        );
+
+        let error = Response::from(Err("No keymap profile named games".to_string()));
+        let error_json = serde_json::to_string(&error).unwrap();
+        assert_eq!(error_json, r#"{"error":"No keymap profile named games"}"#);
+
+        let read_back: Response = serde_json::from_str(&error_json).unwrap();
+        assert_eq!(
+            Result::from(read_back),
+            Err("No keymap profile named games".to_string())
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    mod server {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Instant;
+
+        use serde_json::json;
+
+        use super::super::{ControlServer, Request};
+        use crate::button_codes::{default_ranges, ButtonCodes};
+        use crate::events::EventSink;
+        use crate::key_queue::{key_queue, KeyReceiver, KeyState, OverflowPolicy, Received};
+        use crate::keymap::{Key, Keymap, MacroAction, MacroKey};
+        use crate::layers::LayerState;
+        use crate::profiles::Profiles;
+        use crate::programmable_keys::ProgrammableKeys;
+        use crate::test_support::{shared_settings, use_temp_data_dir};
+        use crate::validation::MAX_DELAY_MS;
+
+        /// A server for a freshly saved profile that is active
+        fn server(name: &str) -> (ControlServer, KeyReceiver) {
+            use_temp_data_dir();
+            Keymap::save_to_file(Keymap::new(name.to_string(), 2), 0).unwrap();
+
+            let keymap = Arc::new(Mutex::new(Keymap::new(name.to_string(), 2)));
+            let events = EventSink::new();
+            let settings = shared_settings();
+            let profiles = Profiles::new(
+                keymap.clone(),
+                Arc::new(Mutex::new(LayerState::load(name, &settings))),
+                events.clone(),
+                ButtonCodes::new(default_ranges()),
+                settings,
+                Vec::new(),
+            );
+            let (keys, key_receiver) = key_queue(8, OverflowPolicy::DropNewest);
+
+            let server = ControlServer {
+                keymap,
+                profiles,
+                events,
+                keys,
+            };
+            (server, key_receiver)
+        }
+
+        fn live_keymap(server: &ControlServer) -> Keymap {
+            server.keymap.lock().unwrap().clone()
+        }
+
+        fn stored(name: &str) -> Keymap {
+            Keymap::load_from_file(name.to_string(), &default_ranges()).unwrap()
+        }
+
+        #[test]
+        fn sends_the_active_keymap_and_profile() {
+            let (server, _keys) = server("socket-send");
+
+            assert_eq!(
+                server.handle(Request::SendKeymap),
+                Ok(serde_json::to_value(live_keymap(&server)).unwrap())
+            );
+            assert_eq!(
+                server.handle(Request::ActiveProfile),
+                Ok(json!("socket-send"))
+            );
+        }
+
+        #[test]
+        fn adds_a_button_without_saving() {
+            let (server, _keys) = server("socket-add");
+            let macro_key = MacroKey::new(ProgrammableKeys::get_from_index(5));
+
+            assert_eq!(
+                server.handle(Request::AddButton {
+                    button: macro_key.clone(),
+                }),
+                Ok(json!(null))
+            );
+            assert_eq!(live_keymap(&server).buttons.last(), Some(&macro_key));
+            assert_eq!(stored("socket-add").buttons.len(), 2);
+        }
+
+        #[test]
+        fn saves_a_keymap_and_refuses_a_broken_one() {
+            let (server, _keys) = server("socket-save");
+
+            let mut keymap = live_keymap(&server);
+            keymap.buttons[0].actions = vec![MacroAction::Tap(Key::KeyQ)];
+            assert_eq!(
+                server.handle(Request::SaveKeymap {
+                    keymap: keymap.clone(),
+                }),
+                Ok(json!(null))
+            );
+            assert_eq!(stored("socket-save"), keymap);
+
+            let mut broken = keymap.clone();
+            broken.buttons[1].actions = vec![MacroAction::Delay(MAX_DELAY_MS + 1)];
+            assert!(server
+                .handle(Request::SaveKeymap { keymap: broken })
+                .is_err());
+            assert_eq!(stored("socket-save"), keymap);
+        }
+
+        #[test]
+        fn activates_profiles() {
+            let (server, _keys) = server("socket-activate");
+            Keymap::save_to_file(Keymap::new("socket-activated".to_string(), 3), 0).unwrap();
+
+            assert_eq!(
+                server.handle(Request::ActivateProfile {
+                    name: "socket-activated".to_string(),
+                }),
+                Ok(json!(null))
+            );
+            assert_eq!(live_keymap(&server).map_name, "socket-activated");
+            assert_eq!(live_keymap(&server).buttons.len(), 3);
+
+            assert!(server
+                .handle(Request::ActivateProfile {
+                    name: "socket-missing".to_string(),
+                })
+                .is_err());
+            assert_eq!(live_keymap(&server).map_name, "socket-activated");
+        }
+
+        #[test]
+        fn sets_button_actions() {
+            let (server, _keys) = server("socket-set");
+            let actions = vec![MacroAction::Print("from the socket".to_string())];
+
+            assert_eq!(
+                server.handle(Request::SetButtonActions {
+                    keymap: "socket-set".to_string(),
+                    button: ProgrammableKeys::get_from_index(2),
+                    actions: actions.clone(),
+                }),
+                Ok(json!(null))
+            );
+            assert_eq!(live_keymap(&server).buttons[1].actions, actions);
+            assert_eq!(stored("socket-set").buttons[1].actions, actions);
+        }
+
+        #[test]
+        fn triggers_a_press_and_release() {
+            let (server, keys) = server("socket-trigger");
+            let button = ProgrammableKeys::get_from_index(1);
+
+            assert_eq!(
+                server.handle(Request::Trigger {
+                    button: button.clone(),
+                }),
+                Ok(json!(null))
+            );
+
+            for state in [KeyState::Pressed, KeyState::Released] {
+                match keys.recv_until(Some(Instant::now())) {
+                    Received::Event(event) => {
+                        assert_eq!(event.key, button);
+                        assert_eq!(event.state, state);
+                    }
+                    other => panic!("Expected a key event, got {:?}", other),
+                }
+            }
+        }
+
+        #[test]
+        fn subscribing_streams_events() {
+            let (server, _keys) = server("socket-subscribe");
+            let (client, connection) = UnixStream::pair().unwrap();
+
+            let events = server.events.clone();
+            let handler = thread::spawn(move || server.handle_connection(connection));
+
+            let mut reader = BufReader::new(client.try_clone().unwrap());
+            let mut writer = client;
+            let mut line = String::new();
+
+            writer
+                .write_all(b"{\"command\":\"active_profile\"}\n")
+                .unwrap();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "{\"ok\":\"socket-subscribe\"}\n");
+
+            line.clear();
+            writer.write_all(b"{\"command\":\"subscribe\"}\n").unwrap();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "{\"ok\":null}\n");
+
+            events.emit("macro-started", json!({ "button": "MACRO1" }));
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&line).unwrap(),
+                json!({ "event": "macro-started", "payload": { "button": "MACRO1" } })
+            );
+
+            // the stream ends at the first event after the client goes away
+            drop(reader);
+            drop(writer);
+            events.emit("load-keymap", "");
+            handler.join().unwrap();
+        }
+
+        #[test]
+        fn invalid_requests_get_an_error() {
+            let (server, _keys) = server("socket-invalid");
+            let (client, connection) = UnixStream::pair().unwrap();
+            let handler = thread::spawn(move || server.handle_connection(connection));
+
+            let mut reader = BufReader::new(client.try_clone().unwrap());
+            let mut writer = client;
+            writer.write_all(b"{\"command\":\"reboot\"}\n").unwrap();
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.starts_with("{\"error\":\"Invalid request"), "{}", line);
+
+            drop(writer);
+            drop(reader);
+            handler.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use libc::{input_absinfo, input_event, input_id};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::bindings::ChordTracker;
+use crate::button_codes::ButtonCodes;
+use crate::input_devices;
+use crate::input_devices::{DeviceSelector, InputDevice};
+use crate::key_queue::{KeyEvent, KeySender, KeyState};
+use crate::keymap::Keymap;
+use crate::linux_keycodes;
+use crate::recorder::MacroRecorder;
+use crate::uinput;
+use crate::uinput::{Capabilities, VirtualDevice};
+
+const INPUT_DIR: &str = "/dev/input";
+
+/// udev hands a new device node to the input group a moment after it appears
+const OPEN_RETRIES: u32 = 20;
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// how often to check whether keys are still held before grabbing a device
+const RELEASE_POLL_DELAY: Duration = Duration::from_millis(50);
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const EV_MSC: u16 = 0x04;
+const EV_LED: u16 = 0x11;
+const SYN_REPORT: u16 = 0;
+const EV_MAX: usize = 0x1f;
+const KEY_MAX: usize = 0x2ff;
+const REL_MAX: usize = 0x0f;
+const ABS_MAX: usize = 0x3f;
+const MSC_MAX: usize = 0x07;
+const LED_MAX: usize = 0x0f;
+const INPUT_PROP_MAX: usize = 0x1f;
+/// the codes from here up to KEY_MACRO1 are mouse, joystick and tablet buttons
+const BTN_MISC: usize = 0x100;
+const KEY_MACRO1: usize = 0x290;
+
+/// autorepeat, the key handler does its own hold timing
+const KEY_REPEAT: i32 = 2;
+
+const fn evdev_ioctl(number: u64, size: usize) -> u64 {
+    (2 << 30) | ((size as u64) << 16) | ((b'E' as u64) << 8) | number
+}
+
+const NAME_SIZE: usize = 256;
+
+const EVIOCGID: u64 = evdev_ioctl(0x02, std::mem::size_of::<input_id>());
+const EVIOCGNAME: u64 = evdev_ioctl(0x06, NAME_SIZE);
+const EVIOCGPHYS: u64 = evdev_ioctl(0x07, NAME_SIZE);
+const EVIOCGPROP: u64 = evdev_ioctl(0x09, INPUT_PROP_MAX / 8 + 1);
+const EVIOCGKEY: u64 = evdev_ioctl(0x18, KEY_MAX / 8 + 1);
+/// the only write request, takes a plain int instead of a buffer
+const EVIOCGRAB: u64 = (1 << 30) | (4 << 16) | ((b'E' as u64) << 8) | 0x90;
+
+const fn eviocgbit(event_type: u64, size: usize) -> u64 {
+    evdev_ioctl(0x20 + event_type, size)
+}
+
+const fn eviocgabs(axis: u64) -> u64 {
+    evdev_ioctl(0x40 + axis, std::mem::size_of::<input_absinfo>())
+}
+
+/// An open `/dev/input/event*` node
+struct EvdevDevice {
+    file: File,
+    info: InputDevice,
+}
+
+impl EvdevDevice {
+    fn open(path: &Path) -> Result<EvdevDevice, io::Error> {
+        let file = File::open(path)?;
+        let fd = file.as_raw_fd();
+
+        let mut id: input_id = unsafe { std::mem::zeroed() };
+        ioctl(fd, EVIOCGID, &mut id as *mut input_id as *mut u8)?;
+
+        let info = InputDevice {
+            path: path.to_string_lossy().to_string(),
+            name: read_string(fd, EVIOCGNAME)?,
+            vendor: id.vendor,
+            product: id.product,
+            // virtual devices have no physical path
+            phys: read_string(fd, EVIOCGPHYS).unwrap_or_default(),
+        };
+
+        Ok(EvdevDevice { file, info })
+    }
+
+    /// Whether the device has keyboard keys or macro keys, rather than only mouse or joystick buttons
+    fn is_keyboard(&self) -> bool {
+        let fd = self.file.as_raw_fd();
+
+        let mut event_types = [0u8; EV_MAX / 8 + 1];
+        if ioctl(
+            fd,
+            eviocgbit(0, event_types.len()),
+            event_types.as_mut_ptr(),
+        )
+        .is_err()
+            || !has_bit(&event_types, EV_KEY as usize)
+        {
+            return false;
+        }
+
+        let keys = self.key_codes();
+        keys.iter()
+            .any(|code| (1..BTN_MISC).contains(code) || (KEY_MACRO1..=KEY_MAX).contains(code))
+    }
+
+    /// Every key code the device can send
+    fn key_codes(&self) -> Vec<usize> {
+        self.event_codes(EV_KEY, KEY_MAX)
+    }
+
+    /// Every code of an event type the device can send, up to the type's highest code
+    fn event_codes(&self, event_type: u16, max: usize) -> Vec<usize> {
+        let mut codes = [0u8; KEY_MAX / 8 + 1];
+        let request = eviocgbit(event_type.into(), max / 8 + 1);
+        if ioctl(self.file.as_raw_fd(), request, codes.as_mut_ptr()).is_err() {
+            return Vec::new();
+        }
+
+        (0..=max).filter(|code| has_bit(&codes, *code)).collect()
+    }
+
+    /// Everything the device can send except its macro keys, for a passthrough
+    /// device that stands in for it while it is grabbed
+    fn passthrough_capabilities(&self, codes: &ButtonCodes) -> Capabilities {
+        let fd = self.file.as_raw_fd();
+        let to_u16 = |codes: Vec<usize>| codes.into_iter().map(|code| code as u16).collect();
+
+        let absolute_axes = self
+            .event_codes(EV_ABS, ABS_MAX)
+            .into_iter()
+            .filter_map(|axis| {
+                let mut absinfo: input_absinfo = unsafe { std::mem::zeroed() };
+                let request = eviocgabs(axis as u64);
+                ioctl(fd, request, &mut absinfo as *mut input_absinfo as *mut u8).ok()?;
+                Some((axis as u16, absinfo))
+            })
+            .collect();
+
+        // a device without properties is passed on without them
+        let mut properties = [0u8; INPUT_PROP_MAX / 8 + 1];
+        let _ = ioctl(fd, EVIOCGPROP, properties.as_mut_ptr());
+
+        Capabilities {
+            keys: self
+                .key_codes()
+                .into_iter()
+                .filter(|code| codes.button_for(*code as u32, &self.info).is_none())
+                .map(|code| code as u16)
+                .collect(),
+            relative_axes: to_u16(self.event_codes(EV_REL, REL_MAX)),
+            absolute_axes,
+            misc: to_u16(self.event_codes(EV_MSC, MSC_MAX)),
+            leds: to_u16(self.event_codes(EV_LED, LED_MAX)),
+            properties: (0..=INPUT_PROP_MAX)
+                .filter(|property| has_bit(&properties, *property))
+                .map(|property| property as u16)
+                .collect(),
+        }
+    }
+
+    /// Waits until no key on the device is held down
+    fn wait_for_release(&self) {
+        let mut keys = [0u8; KEY_MAX / 8 + 1];
+        while ioctl(self.file.as_raw_fd(), EVIOCGKEY, keys.as_mut_ptr()).is_ok()
+            && keys.iter().any(|byte| *byte != 0)
+        {
+            thread::sleep(RELEASE_POLL_DELAY);
+        }
+    }
+
+    /// Stops the device's events from reaching anything but us, until it is closed
+    fn grab(&self) -> Result<(), io::Error> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), EVIOCGRAB as libc::Ioctl, 1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn ioctl(fd: libc::c_int, request: u64, buffer: *mut u8) -> Result<(), io::Error> {
+    if unsafe { libc::ioctl(fd, request as libc::Ioctl, buffer) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn read_string(fd: libc::c_int, request: u64) -> Result<String, io::Error> {
+    let mut buffer = [0u8; NAME_SIZE];
+    ioctl(fd, request, buffer.as_mut_ptr())?;
+
+    let end = buffer
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(NAME_SIZE);
+    Ok(String::from_utf8_lossy(&buffer[..end]).to_string())
+}
+
+fn has_bit(bits: &[u8], bit: usize) -> bool {
+    bits.get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+fn is_event_node(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("event"))
+}
+
+/// Event nodes under `/dev/input`, sorted so they are opened in a stable order
+fn event_nodes() -> Result<Vec<PathBuf>, io::Error> {
+    let mut nodes: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(INPUT_DIR)? {
+        let path = entry?.path();
+        if is_event_node(&path) {
+            nodes.push(path);
+        }
+    }
+
+    nodes.sort();
+    Ok(nodes)
+}
+
+/// Every keyboard we can open, for picking devices in the settings
+pub fn list_devices() -> Result<Vec<InputDevice>, io::Error> {
+    let mut devices: Vec<InputDevice> = Vec::new();
+    for path in event_nodes()? {
+        // nodes we aren't allowed to read are left out rather than failing the whole list
+        match EvdevDevice::open(&path) {
+            Ok(device) if device.is_keyboard() => devices.push(device.info),
+            Ok(_) => {}
+            Err(err) => eprintln!("Failed to open input device {:?}: {}", path, err),
+        }
+    }
+    Ok(devices)
+}
+
+/// Hands out devices to reader threads, making sure each node is only read once
+#[derive(Clone)]
+struct Readers {
+    queue: KeySender,
+    recorder: MacroRecorder,
+    codes: ButtonCodes,
+    /// for looking up key bindings
+    keymap: Arc<Mutex<Keymap>>,
+    selectors: Arc<Vec<DeviceSelector>>,
+    /// grab the devices, passing the rest of their input on through a virtual device
+    grab: bool,
+    open: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Readers {
+    /// Opens a device and starts reading it if it is a selected keyboard
+    fn add(&self, path: &Path, retries: u32) {
+        let mut attempt = 0;
+        let device = loop {
+            match EvdevDevice::open(path) {
+                Ok(device) => break device,
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied && attempt < retries => {
+                    attempt += 1;
+                    thread::sleep(OPEN_RETRY_DELAY);
+                }
+                Err(err) => {
+                    eprintln!("Failed to open input device {:?}: {}", path, err);
+                    return;
+                }
+            }
+        };
+
+        // our own virtual devices would feed their output back in
+        if uinput::is_own_device(&device.info.name)
+            || !device.is_keyboard()
+            || !input_devices::is_selected(&self.selectors, &device.info)
+        {
+            return;
+        }
+
+        match self.open.lock() {
+            Ok(mut open) => {
+                if !open.insert(path.to_path_buf()) {
+                    return;
+                }
+            }
+            Err(err) => {
+                eprintln!("Error retrieving open devices lock: {}", err);
+                return;
+            }
+        }
+
+        println!("Listening to input device {}", device.info.label());
+        let readers = self.clone();
+        thread::spawn(move || {
+            let passthrough = if readers.grab {
+                grab(&device, &readers.codes)
+            } else {
+                None
+            };
+            readers.read_events(&device, passthrough.as_ref());
+
+            println!("Input device {} was removed", device.info.label());
+            match readers.open.lock() {
+                Ok(mut open) => {
+                    open.remove(Path::new(&device.info.path));
+                }
+                Err(err) => eprintln!("Error retrieving open devices lock: {}", err),
+            }
+        });
+    }
+
+    /// Reads a device until it goes away, passing on everything but the
+    /// macro keys if the device is grabbed
+    fn read_events(&self, device: &EvdevDevice, passthrough: Option<&VirtualDevice>) {
+        let label = device.info.label();
+        let mut events: [input_event; 64] = unsafe { std::mem::zeroed() };
+        let event_size = std::mem::size_of::<input_event>();
+        let mut passed_on: Vec<(u16, u16, i32)> = Vec::new();
+        let mut chords = ChordTracker::new(self.keymap.clone());
+
+        loop {
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(
+                    events.as_mut_ptr() as *mut u8,
+                    std::mem::size_of_val(&events),
+                )
+            };
+
+            let read = match (&device.file).read(bytes) {
+                Ok(0) => return,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // unplugged devices fail with ENODEV
+                Err(_) => return,
+            };
+
+            for event in &events[..read / event_size] {
+                if event.type_ == EV_SYN && event.code == SYN_REPORT {
+                    // a report with nothing but the scan code left came from a macro key
+                    let only_misc = passed_on
+                        .iter()
+                        .all(|(event_type, _, _)| *event_type == EV_MSC);
+
+                    if let Some(passthrough) = passthrough.filter(|_| !only_misc) {
+                        if let Err(err) = passthrough.emit(&passed_on) {
+                            eprintln!("Failed to pass on input from {}: {}", label, err);
+                        }
+                    }
+                    passed_on.clear();
+                }
+
+                if event.type_ != EV_KEY {
+                    // the device's other input, like a scroll wheel or media dial, is passed on as is
+                    if event.type_ != EV_SYN && passthrough.is_some() {
+                        passed_on.push((event.type_, event.code, event.value));
+                    }
+                    continue;
+                }
+
+                let code = u32::from(event.code);
+                let state = match event.value {
+                    0 => KeyState::Released,
+                    _ => KeyState::Pressed,
+                };
+
+                let mut prog_key = self.codes.button_for(code, &device.info);
+                if prog_key.is_none() && event.value != KEY_REPEAT {
+                    prog_key = chords.on_key(code, state, &device.info);
+                }
+
+                // repeats are passed on too, so held keys keep repeating like they did before the grab
+                if prog_key.is_none() && !chords.is_pressed(code) && passthrough.is_some() {
+                    passed_on.push((event.type_, event.code, event.value));
+                }
+                if event.value == KEY_REPEAT {
+                    continue;
+                }
+
+                match prog_key {
+                    // any other key only matters if a macro is being recorded
+                    None => self
+                        .recorder
+                        .record(linux_keycodes::key_from_code(code), state),
+                    Some(prog_key) => {
+                        self.queue
+                            .send(KeyEvent::new(prog_key, state, Some(label.clone())));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Grabs a device, making a passthrough device first so typing on it keeps
+/// working. A device that can't be grabbed is still read, its macro keys just
+/// reach other apps too.
+fn grab(device: &EvdevDevice, codes: &ButtonCodes) -> Option<VirtualDevice> {
+    let label = device.info.label();
+    let capabilities = device.passthrough_capabilities(codes);
+
+    let passthrough = match VirtualDevice::passthrough(&device.info.name, &capabilities) {
+        Ok(passthrough) => passthrough,
+        Err(err) => {
+            eprintln!(
+                "Failed to create a passthrough device, not grabbing {}: {}",
+                label, err
+            );
+            return None;
+        }
+    };
+
+    // a key held while grabbing would never see its release anywhere else and stay stuck
+    device.wait_for_release();
+
+    match device.grab() {
+        Ok(_) => {
+            println!("Grabbed input device {}", label);
+            Some(passthrough)
+        }
+        Err(err) => {
+            eprintln!("Failed to grab input device {}: {}", label, err);
+            None
+        }
+    }
+}
+
+/// Reads macro keys from the selected keyboards, or every keyboard if none are
+/// selected, picking up keyboards that are plugged in later. Grabbed keyboards
+/// keep their macro keys from reaching other apps. Blocks for as long as
+/// `/dev/input` can be watched.
+pub fn evdev_start(
+    queue: &KeySender,
+    recorder: &MacroRecorder,
+    codes: &ButtonCodes,
+    keymap: Arc<Mutex<Keymap>>,
+    selectors: &[DeviceSelector],
+    grab: bool,
+) {
+    let readers = Readers {
+        queue: queue.clone(),
+        recorder: recorder.clone(),
+        codes: codes.clone(),
+        keymap,
+        selectors: Arc::new(selectors.to_vec()),
+        grab,
+        open: Arc::new(Mutex::new(HashSet::new())),
+    };
+
+    // start watching before the first scan, so nothing plugged in between is missed
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            eprintln!("Failed to start input device watcher: {}", err);
+            None
+        }
+    };
+    if let Some(watcher) = watcher.as_mut() {
+        if let Err(err) = watcher.watch(Path::new(INPUT_DIR), RecursiveMode::NonRecursive) {
+            eprintln!(
+                "Failed to watch {}, plugged in keyboards won't be picked up: {}",
+                INPUT_DIR, err
+            );
+        }
+    }
+
+    match event_nodes() {
+        Ok(nodes) => {
+            for path in nodes {
+                readers.add(&path, 0);
+            }
+        }
+        Err(err) => eprintln!("Failed to list input devices: {}", err),
+    }
+
+    if watcher.is_none() {
+        return;
+    }
+
+    while let Ok(event) = receiver.recv() {
+        let event: Event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                eprintln!("Input device watcher error: {}", err);
+                continue;
+            }
+        };
+
+        // removed devices are noticed by their reader, the read fails
+        if !matches!(event.kind, EventKind::Create(_)) {
+            continue;
+        }
+
+        for path in event.paths.iter().filter(|path| is_event_node(path)) {
+            let readers = readers.clone();
+            let path = path.clone();
+            thread::spawn(move || readers.add(&path, OPEN_RETRIES));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serde_json::json;
+use tauri::{AppHandle, Manager};
+
+/// how many events a subscriber can fall behind by before it is dropped
+const SUBSCRIBER_BACKLOG: usize = 256;
+
+/// Sends backend events to the frontend and to anyone subscribed on the
+/// control socket. Background threads start before the tauri app exists, so
+/// the frontend misses events until the app handle is set.
+#[derive(Clone, Default)]
+pub struct EventSink {
+    app: Arc<Mutex<Option<AppHandle>>>,
+    /// each subscriber gets every event as a line of json
+    subscribers: Arc<Mutex<Vec<SyncSender<String>>>>,
+}
+
+impl EventSink {
+    pub fn new() -> EventSink {
+        EventSink::default()
+    }
+
+    pub fn set_app(&self, app: AppHandle) {
+        match self.app.lock() {
+            Ok(mut borrowed_app) => *borrowed_app = Some(app),
+            Err(err) => eprintln!("Error retrieving app handle lock: {}", err),
+        }
+    }
+
+    /// The tauri app handle, once the app is running
+    pub fn app(&self) -> Option<AppHandle> {
+        match self.app.lock() {
+            Ok(app) => app.clone(),
+            Err(err) => {
+                eprintln!("Error retrieving app handle lock: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Starts sending events to a new subscriber, until the receiver is dropped
+    /// or it stops keeping up
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BACKLOG);
+
+        match self.subscribers.lock() {
+            Ok(mut subscribers) => subscribers.push(sender),
+            Err(err) => eprintln!("Error retrieving event subscribers lock: {}", err),
+        }
+        receiver
+    }
+
+    pub fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) {
+        self.send_to_subscribers(event, &payload);
+
+        let app = match self.app.lock() {
+            Ok(app) => app,
+            Err(err) => {
+                eprintln!("Error retrieving app handle lock: {}", err);
+                return;
+            }
+        };
+
+        if let Some(app) = app.as_ref() {
+            if let Err(err) = app.emit_all(event, payload) {
+                eprintln!("Failed to emit {} event: {}", event, err);
+            }
+        }
+    }
+
+    fn send_to_subscribers<S: Serialize>(&self, event: &str, payload: &S) {
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(err) => {
+                eprintln!("Error retrieving event subscribers lock: {}", err);
+                return;
+            }
+        };
+
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let line = json!({ "event": event, "payload": payload }).to_string();
+
+        // never wait on a subscriber, the key handler and macros emit events too
+        subscribers.retain(|subscriber| match subscriber.try_send(line.clone()) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Dropping an event subscriber that stopped reading");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_get_every_event_as_json() {
+        let events = EventSink::new();
+        let receiver = events.subscribe();
+
+        events.emit("macro-started", json!({ "button": "MACRO1" }));
+        events.emit("load-keymap", "");
+
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            r#"{"event":"macro-started","payload":{"button":"MACRO1"}}"#
+        );
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            r#"{"event":"load-keymap","payload":""}"#
+        );
+    }
+
+    #[test]
+    fn subscribers_that_fall_behind_are_dropped() {
+        let events = EventSink::new();
+        let stuck = events.subscribe();
+        let gone = events.subscribe();
+        drop(gone);
+
+        for _ in 0..SUBSCRIBER_BACKLOG {
+            events.emit("load-keymap", "");
+        }
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+
+        // one more than the backlog holds doesn't block, it drops the subscriber
+        events.emit("load-keymap", "");
+        assert!(events.subscribers.lock().unwrap().is_empty());
+        assert_eq!(stuck.try_iter().count(), SUBSCRIBER_BACKLOG);
+    }
+}
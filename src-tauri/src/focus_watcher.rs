@@ -0,0 +1,254 @@
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+use serde_json::Value;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use crate::window_rules::WindowInfo;
+
+/// Calls `on_focus` with the focused window whenever it or its title changes.
+/// Uses the Sway/i3 IPC socket when there is one, and X11 otherwise. Blocks for
+/// as long as the connection to the window manager stays up.
+pub fn watch_focus<F: FnMut(WindowInfo)>(mut on_focus: F) {
+    let result = if let Some(socket) = env::var_os("SWAYSOCK").or(env::var_os("I3SOCK")) {
+        println!("Watching window focus over the sway/i3 socket");
+        watch_i3(socket, &mut on_focus)
+    } else if env::var_os("DISPLAY").is_some() {
+        println!("Watching window focus on X11");
+        watch_x11(&mut on_focus)
+    } else {
+        eprintln!("No supported window manager found, automatic profile switching is off");
+        return;
+    };
+
+    if let Err(err) = result {
+        eprintln!("Stopped watching window focus: {}", err);
+    }
+}
+
+/// Full path of a process' executable
+fn executable_of(pid: u32) -> Option<String> {
+    fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+struct Atoms {
+    net_active_window: u32,
+    net_wm_name: u32,
+    net_wm_pid: u32,
+    utf8_string: u32,
+}
+
+impl Atoms {
+    fn intern(conn: &RustConnection) -> Result<Atoms, Box<dyn Error>> {
+        let intern = |name: &[u8]| -> Result<u32, Box<dyn Error>> {
+            Ok(conn.intern_atom(false, name)?.reply()?.atom)
+        };
+
+        Ok(Atoms {
+            net_active_window: intern(b"_NET_ACTIVE_WINDOW")?,
+            net_wm_name: intern(b"_NET_WM_NAME")?,
+            net_wm_pid: intern(b"_NET_WM_PID")?,
+            utf8_string: intern(b"UTF8_STRING")?,
+        })
+    }
+}
+
+fn watch_x11(on_focus: &mut dyn FnMut(WindowInfo)) -> Result<(), Box<dyn Error>> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    let atoms = Atoms::intern(&conn)?;
+
+    // the window manager updates _NET_ACTIVE_WINDOW on the root window when focus moves
+    let watch = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+    conn.change_window_attributes(root, &watch)?.check()?;
+
+    let mut active: Option<Window> = None;
+    loop {
+        let window = x11_active_window(&conn, root, &atoms)?;
+
+        // follow the focused window's title too, the window may already be gone so ignore errors
+        if window != active {
+            if let Some(old) = active {
+                let unwatch = ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT);
+                conn.change_window_attributes(old, &unwatch)?.ignore_error();
+            }
+            if let Some(new) = window {
+                conn.change_window_attributes(new, &watch)?.ignore_error();
+            }
+            conn.flush()?;
+            active = window;
+        }
+
+        on_focus(match window {
+            Some(window) => x11_window_info(&conn, window, &atoms),
+            None => WindowInfo::default(),
+        });
+
+        // wait for focus or the title to change
+        loop {
+            if let Event::PropertyNotify(event) = conn.wait_for_event()? {
+                if event.atom == atoms.net_active_window
+                    || event.atom == atoms.net_wm_name
+                    || event.atom == u32::from(AtomEnum::WM_NAME)
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn x11_active_window(
+    conn: &RustConnection,
+    root: Window,
+    atoms: &Atoms,
+) -> Result<Option<Window>, Box<dyn Error>> {
+    let reply = conn
+        .get_property(false, root, atoms.net_active_window, AtomEnum::WINDOW, 0, 1)?
+        .reply()?;
+
+    Ok(reply
+        .value32()
+        .and_then(|mut value| value.next())
+        .filter(|window| *window != 0))
+}
+
+fn x11_window_info(conn: &RustConnection, window: Window, atoms: &Atoms) -> WindowInfo {
+    let property = |name: u32, kind: u32| -> Option<Vec<u8>> {
+        let reply = conn
+            .get_property(false, window, name, kind, 0, u32::MAX / 4)
+            .ok()?
+            .reply()
+            .ok()?;
+        Some(reply.value)
+    };
+
+    // WM_CLASS holds the instance and class names, each nul terminated
+    let class = property(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into()).and_then(|value| {
+        value
+            .split(|byte| *byte == 0)
+            .rfind(|part| !part.is_empty())
+            .map(|class| String::from_utf8_lossy(class).into_owned())
+    });
+
+    let title = property(atoms.net_wm_name, atoms.utf8_string)
+        .filter(|value| !value.is_empty())
+        .or_else(|| property(AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()))
+        .map(|value| String::from_utf8_lossy(&value).into_owned());
+
+    let executable = property(atoms.net_wm_pid, AtomEnum::CARDINAL.into())
+        .filter(|value| value.len() >= 4)
+        .map(|value| u32::from_ne_bytes([value[0], value[1], value[2], value[3]]))
+        .and_then(executable_of);
+
+    WindowInfo {
+        class,
+        title,
+        executable,
+    }
+}
+
+// i3 ipc message types, sway uses the same protocol
+const I3_SUBSCRIBE: u32 = 2;
+const I3_GET_TREE: u32 = 4;
+const I3_WINDOW_EVENT: u32 = 0x80000003;
+const I3_MAGIC: &[u8] = b"i3-ipc";
+
+fn watch_i3(socket: OsString, on_focus: &mut dyn FnMut(WindowInfo)) -> Result<(), Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket)?;
+
+    // start with whatever is focused right now
+    i3_send(&mut stream, I3_GET_TREE, "")?;
+    let (_, tree) = i3_read(&mut stream)?;
+    if let Some(focused) = i3_find_focused(&tree) {
+        on_focus(i3_window_info(focused));
+    }
+
+    i3_send(&mut stream, I3_SUBSCRIBE, r#"["window"]"#)?;
+    let (_, reply) = i3_read(&mut stream)?;
+    if reply["success"] != Value::Bool(true) {
+        return Err(format!("window manager refused the subscription: {}", reply).into());
+    }
+
+    loop {
+        let (message_type, event) = i3_read(&mut stream)?;
+        if message_type != I3_WINDOW_EVENT {
+            continue;
+        }
+
+        let container = &event["container"];
+        let change = event["change"].as_str().unwrap_or_default();
+        let focused = container["focused"].as_bool().unwrap_or(false);
+
+        if change == "focus" || (change == "title" && focused) {
+            on_focus(i3_window_info(container));
+        }
+    }
+}
+
+fn i3_send(
+    stream: &mut UnixStream,
+    message_type: u32,
+    payload: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut message = I3_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(message_type.to_ne_bytes());
+    message.extend(payload.as_bytes());
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+fn i3_read(stream: &mut UnixStream) -> Result<(u32, Value), Box<dyn Error>> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != I3_MAGIC {
+        return Err("bad message from the window manager".into());
+    }
+
+    let length = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+    let message_type = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((message_type, serde_json::from_slice(&payload)?))
+}
+
+fn i3_find_focused(node: &Value) -> Option<&Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|children| node[children].as_array())
+        .flatten()
+        .find_map(i3_find_focused)
+}
+
+fn i3_window_info(container: &Value) -> WindowInfo {
+    // wayland windows have an app id, xwayland and i3 windows a WM_CLASS
+    let class = container["app_id"]
+        .as_str()
+        .or(container["window_properties"]["class"].as_str())
+        .map(str::to_string);
+
+    WindowInfo {
+        class,
+        title: container["name"].as_str().map(str::to_string),
+        executable: container["pid"]
+            .as_u64()
+            .and_then(|pid| executable_of(pid as u32)),
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs;
+
+use crate::keymap::Keymap;
+use crate::runtime::Runtime;
+use crate::settings::Settings;
+
+/// Runs macros for a keymap profile without the window or tray, until told to
+/// stop. On linux SIGHUP reloads the keymap from disk and SIGTERM or SIGINT
+/// shuts down, releasing anything macros were holding.
+pub fn run(settings: Settings, profile: Option<String>) {
+    let profile = profile.unwrap_or_else(|| settings.active_profile.clone());
+
+    if !Keymap::keymap_path(&profile).exists() {
+        eprintln!("No keymap profile named {}", profile);
+        std::process::exit(1);
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // there is no window to show stdout in, keep all the logs together where the journal picks them up
+        unsafe {
+            libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO);
+        }
+
+        // threads inherit the signal mask, so block before any are started
+        let signals = signals::block();
+        let runtime = Runtime::start(&settings, &profile);
+        println!("Running headless with keymap profile {}", profile);
+        signals::handle(&signals, &runtime);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _runtime = Runtime::start(&settings, &profile);
+        println!("Running headless with keymap profile {}", profile);
+        loop {
+            std::thread::park();
+        }
+    }
+}
+
+/// Reads the active keymap again, keeping the current one if the file has errors
+fn reload(runtime: &Runtime) {
+    let name = runtime.profiles.active();
+
+    match fs::read_to_string(Keymap::keymap_path(&name)) {
+        Ok(keymap_json) => runtime.profiles.reload_active(&keymap_json),
+        Err(err) => eprintln!("Failed to read keymap profile {}: {}", name, err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod signals {
+    use std::ptr;
+
+    use crate::runtime::Runtime;
+
+    /// Blocks the signals we handle, so they queue up for `handle` instead of killing the process
+    pub fn block() -> libc::sigset_t {
+        unsafe {
+            let mut signals: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            for signal in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT] {
+                libc::sigaddset(&mut signals, signal);
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+            signals
+        }
+    }
+
+    /// Waits for signals until one asks us to shut down
+    pub fn handle(signals: &libc::sigset_t, runtime: &Runtime) {
+        loop {
+            let mut signal: libc::c_int = 0;
+            if unsafe { libc::sigwait(signals, &mut signal) } != 0 {
+                continue;
+            }
+
+            match signal {
+                libc::SIGHUP => {
+                    println!("Got SIGHUP, reloading the keymap");
+                    super::reload(runtime);
+                }
+                _ => {
+                    println!("Shutting down");
+                    runtime.executor.shutdown();
+                    std::process::exit(0);
+                }
+            }
+        }
+    }
+}
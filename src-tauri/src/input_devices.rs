@@ -0,0 +1,66 @@
+// only the linux evdev listener reads devices directly so far
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use serde::{Deserialize, Serialize};
+
+/// Where the listener reads macro keys from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum InputBackendKind {
+    /// every keyboard on seat0 through libinput, needs root
+    #[default]
+    Libinput,
+    /// chosen `/dev/input/event*` devices read directly, only needs read access to them
+    Evdev,
+}
+
+/// A keyboard found under `/dev/input`
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct InputDevice {
+    /// device node, changes between boots and replugs
+    pub path: String,
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    /// physical path, which port the device is plugged into
+    pub phys: String,
+}
+
+impl InputDevice {
+    /// How events from this device are labelled, the phys path tells apart identical keyboards
+    pub fn label(&self) -> String {
+        if self.phys.is_empty() {
+            format!("{} ({})", self.name, self.path)
+        } else {
+            format!("{} ({})", self.name, self.phys)
+        }
+    }
+}
+
+/// Picks input devices for the evdev listener. Every field that is set has to
+/// match, so a selector with only a name matches every device with that name.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq, Hash)]
+#[serde(default)]
+pub struct DeviceSelector {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phys: Option<String>,
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, device: &InputDevice) -> bool {
+        self.name.as_ref().is_none_or(|name| *name == device.name)
+            && self.vendor.is_none_or(|vendor| vendor == device.vendor)
+            && self.product.is_none_or(|product| product == device.product)
+            && self.phys.as_ref().is_none_or(|phys| *phys == device.phys)
+    }
+}
+
+/// Whether the listener should read from a device, no selectors means every keyboard
+pub fn is_selected(selectors: &[DeviceSelector], device: &InputDevice) -> bool {
+    selectors.is_empty() || selectors.iter().any(|selector| selector.matches(device))
+}
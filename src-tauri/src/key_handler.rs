@@ -0,0 +1,212 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde_json::json;
+
+use crate::events::EventSink;
+use crate::key_queue::{KeyEvent, KeyState};
+use crate::keymap::{Keymap, MacroKey, Trigger};
+use crate::layers::LayerState;
+use crate::macro_executor::MacroExecutor;
+use crate::programmable_keys::ProgrammableKeys;
+use crate::tap_dance::{ButtonTiming, TapDance};
+
+/// Turns press and release events from the key queue into macro runs,
+/// passing them through the tap dance state machine and the active layers first.
+pub struct KeyHandler {
+    keymap: Arc<Mutex<Keymap>>,
+    layers: Arc<Mutex<LayerState>>,
+    executor: MacroExecutor,
+    events: EventSink,
+    tap_dance: TapDance,
+    /// buttons that are physically held down
+    held: HashSet<ProgrammableKeys>,
+    /// the active layers when each held button was pressed, so its hold and
+    /// release actions come from the same layer as its press
+    pressed_on: HashMap<ProgrammableKeys, Vec<String>>,
+}
+
+impl KeyHandler {
+    pub fn new(
+        keymap: Arc<Mutex<Keymap>>,
+        layers: Arc<Mutex<LayerState>>,
+        executor: MacroExecutor,
+        events: EventSink,
+    ) -> KeyHandler {
+        KeyHandler {
+            keymap,
+            layers,
+            executor,
+            events,
+            tap_dance: TapDance::new(),
+            held: HashSet::new(),
+            pressed_on: HashMap::new(),
+        }
+    }
+
+    pub fn handle_event(&mut self, event: KeyEvent) {
+        self.events.emit(
+            "key-event",
+            json!({ "button": event.key, "state": event.state, "device": event.device }),
+        );
+
+        match event.state {
+            KeyState::Pressed => {
+                self.held.insert(event.key.clone());
+            }
+            KeyState::Released => {
+                self.held.remove(&event.key);
+            }
+        }
+
+        let timing = self.button_timing(&event.key);
+        let fired = self.tap_dance.on_event(&event, &timing);
+        self.process(fired);
+
+        // momentary layers end with the physical release, whatever the tap dance decided
+        if event.state == KeyState::Released {
+            self.pressed_on.remove(&event.key);
+
+            let released = match self.layers.lock() {
+                Ok(mut layers) => layers.release(&event.key),
+                Err(err) => {
+                    eprintln!("Error retrieving layer state lock: {}", err);
+                    false
+                }
+            };
+
+            if released {
+                self.emit_active_layers();
+            }
+        }
+    }
+
+    /// The next time a long press or tap sequence could fire, used to know how long to wait for events
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.tap_dance.next_deadline()
+    }
+
+    /// Fires everything whose deadline has passed
+    pub fn handle_timeouts(&mut self, now: Instant) {
+        let fired = self.tap_dance.on_timeout(now);
+        self.process(fired);
+    }
+
+    fn button_timing(&self, key: &ProgrammableKeys) -> ButtonTiming {
+        let active_layers = self.current_layers(false);
+
+        match self.keymap.lock() {
+            Ok(keymap) => keymap
+                .find_button(key, &active_layers)
+                .map(|macro_key| ButtonTiming::from_macro_key(macro_key, &keymap.timing))
+                .unwrap_or_default(),
+            Err(err) => {
+                eprintln!("Error retrieving keymap lock: {}", err);
+                ButtonTiming::default()
+            }
+        }
+    }
+
+    fn process(&mut self, fired: Vec<(ProgrammableKeys, Trigger)>) {
+        for (key, trigger) in fired {
+            // hold and release use the layers the button was pressed on
+            let active_layers = match (&trigger, self.pressed_on.get(&key)) {
+                (Trigger::Hold | Trigger::Release, Some(pressed_on)) => pressed_on.clone(),
+                _ => {
+                    let active_layers = self.current_layers(true);
+
+                    // taps that resolve after the release don't belong to the next press
+                    if self.held.contains(&key) {
+                        self.pressed_on.insert(key.clone(), active_layers.clone());
+                    }
+                    active_layers
+                }
+            };
+
+            let macro_key = match self.find_macro_key(&key, &active_layers) {
+                None => continue,
+                Some(macro_key) => macro_key,
+            };
+
+            self.apply_layer_actions(&macro_key, &trigger);
+            ProgrammableKeys::process_keys(&macro_key, trigger, &self.executor);
+        }
+    }
+
+    /// Layer actions run here rather than in the executor so the very next event already sees them
+    fn apply_layer_actions(&mut self, macro_key: &MacroKey, trigger: &Trigger) {
+        let actions = match macro_key.actions_for(trigger) {
+            None => return,
+            Some(actions) => actions,
+        };
+
+        let held = self.held.contains(&macro_key.programmable_key);
+        let mut changed = false;
+
+        match self.layers.lock() {
+            Ok(mut layers) => {
+                for action in actions.iter().filter(|action| action.is_layer_action()) {
+                    changed |= layers.apply(&macro_key.programmable_key, action, held);
+                }
+            }
+            Err(err) => eprintln!("Error retrieving layer state lock: {}", err),
+        }
+
+        if changed {
+            self.emit_active_layers();
+        }
+    }
+
+    /// The currently active layers, using up the one-shot layer if `consume_one_shot` is set
+    fn current_layers(&self, consume_one_shot: bool) -> Vec<String> {
+        let keymap = match self.keymap.lock() {
+            Ok(keymap) => keymap,
+            Err(err) => {
+                eprintln!("Error retrieving keymap lock: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let (active_layers, used_one_shot) = match self.layers.lock() {
+            Ok(mut layers) => {
+                let active_layers = layers.active_layers(&keymap);
+                let used_one_shot = consume_one_shot && layers.take_one_shot();
+                (active_layers, used_one_shot)
+            }
+            Err(err) => {
+                eprintln!("Error retrieving layer state lock: {}", err);
+                return Vec::new();
+            }
+        };
+        drop(keymap);
+
+        if used_one_shot {
+            self.emit_active_layers();
+        }
+
+        active_layers
+    }
+
+    fn find_macro_key(&self, key: &ProgrammableKeys, active_layers: &[String]) -> Option<MacroKey> {
+        match self.keymap.lock() {
+            Ok(keymap) => keymap.find_button(key, active_layers).cloned(),
+            Err(err) => {
+                eprintln!("Error retrieving keymap lock: {}", err);
+                None
+            }
+        }
+    }
+
+    fn emit_active_layers(&self) {
+        let active_layers = match (self.keymap.lock(), self.layers.lock()) {
+            (Ok(keymap), Ok(layers)) => layers.active_layers(&keymap),
+            _ => {
+                eprintln!("Error retrieving keymap or layer state lock");
+                return;
+            }
+        };
+
+        self.events.emit("active-layers", active_layers);
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::programmable_keys::ProgrammableKeys;
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+/// A programmable key press or release reported by one of the listeners
+#[derive(Debug, Clone)]
+pub struct KeyEvent {
+    pub key: ProgrammableKeys,
+    pub state: KeyState,
+    pub timestamp: Instant,
+    /// name or handle of the device that produced the event, if known
+    pub device: Option<String>,
+}
+
+impl KeyEvent {
+    pub fn new(key: ProgrammableKeys, state: KeyState, device: Option<String>) -> KeyEvent {
+        KeyEvent {
+            key,
+            state,
+            timestamp: Instant::now(),
+            device,
+        }
+    }
+}
+
+/// Result of waiting on the key queue
+#[derive(Debug)]
+pub enum Received {
+    Event(KeyEvent),
+    /// the deadline passed without a new event
+    Timeout,
+    /// every sender is gone and the queue is empty
+    Disconnected,
+}
+
+/// What to do with a new event when the queue is already full
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// discard the new event
+    DropNewest,
+    /// discard the oldest waiting event to make room
+    DropOldest,
+    /// wait until the handler makes room
+    Block,
+}
+
+struct QueueState {
+    events: VecDeque<KeyEvent>,
+    senders: usize,
+    /// false once the handler is gone, nothing will take events off the queue again
+    receiver_alive: bool,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    /// signalled when an event is pushed or the last sender goes away
+    not_empty: Condvar,
+    /// signalled when an event is taken off the queue or the receiver goes away
+    not_full: Condvar,
+    capacity: usize,
+    overflow: OverflowPolicy,
+}
+
+impl Shared {
+    fn lock(&self) -> Option<MutexGuard<'_, QueueState>> {
+        match self.state.lock() {
+            Ok(state) => Some(state),
+            Err(err) => {
+                eprintln!("Error locking queue: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Listener side of the key queue
+pub struct KeySender {
+    shared: Arc<Shared>,
+}
+
+/// Handler side of the key queue, events come out in the order they were pressed
+pub struct KeyReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Creates a bounded FIFO queue between the listeners and the macro handler
+pub fn key_queue(capacity: usize, overflow: OverflowPolicy) -> (KeySender, KeyReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState {
+            events: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity: capacity.max(1),
+        overflow,
+    });
+
+    (
+        KeySender {
+            shared: shared.clone(),
+        },
+        KeyReceiver { shared },
+    )
+}
+
+impl KeySender {
+    /// Pushes an event onto the queue, returning false if it was dropped or
+    /// the receiver is gone
+    pub fn send(&self, event: KeyEvent) -> bool {
+        let mut state = match self.shared.lock() {
+            Some(state) => state,
+            None => return false,
+        };
+
+        if !state.receiver_alive {
+            return false;
+        }
+
+        if state.events.len() >= self.shared.capacity {
+            match self.shared.overflow {
+                OverflowPolicy::DropNewest => {
+                    eprintln!("Key queue full, dropping {:?}", event.key);
+                    return false;
+                }
+                OverflowPolicy::DropOldest => {
+                    if let Some(dropped) = state.events.pop_front() {
+                        eprintln!("Key queue full, dropping {:?}", dropped.key);
+                    }
+                }
+                OverflowPolicy::Block => {
+                    state = match self.shared.not_full.wait_while(state, |state| {
+                        state.receiver_alive && state.events.len() >= self.shared.capacity
+                    }) {
+                        Ok(state) => state,
+                        Err(err) => {
+                            eprintln!("Error locking queue: {:?}", err);
+                            return false;
+                        }
+                    };
+
+                    if !state.receiver_alive {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        state.events.push_back(event);
+        self.shared.not_empty.notify_one();
+        true
+    }
+}
+
+impl Clone for KeySender {
+    fn clone(&self) -> Self {
+        if let Some(mut state) = self.shared.lock() {
+            state.senders += 1;
+        }
+
+        KeySender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for KeySender {
+    fn drop(&mut self) {
+        if let Some(mut state) = self.shared.lock() {
+            state.senders -= 1;
+        }
+        self.shared.not_empty.notify_all();
+    }
+}
+
+impl Drop for KeyReceiver {
+    fn drop(&mut self) {
+        if let Some(mut state) = self.shared.lock() {
+            state.receiver_alive = false;
+            state.events.clear();
+        }
+        self.shared.not_full.notify_all();
+    }
+}
+
+impl KeyReceiver {
+    /// Blocks until the next event arrives or the deadline passes, waiting
+    /// indefinitely if there is no deadline
+    pub fn recv_until(&self, deadline: Option<Instant>) -> Received {
+        let mut state = match self.shared.lock() {
+            Some(state) => state,
+            None => return Received::Disconnected,
+        };
+
+        while state.events.is_empty() && state.senders > 0 {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Received::Timeout;
+                    }
+
+                    match self.shared.not_empty.wait_timeout(state, deadline - now) {
+                        Ok((state, _)) => state,
+                        Err(err) => {
+                            eprintln!("Error locking queue: {:?}", err);
+                            return Received::Disconnected;
+                        }
+                    }
+                }
+                None => match self.shared.not_empty.wait(state) {
+                    Ok(state) => state,
+                    Err(err) => {
+                        eprintln!("Error locking queue: {:?}", err);
+                        return Received::Disconnected;
+                    }
+                },
+            };
+        }
+
+        match state.events.pop_front() {
+            Some(event) => {
+                self.shared.not_full.notify_one();
+                Received::Event(event)
+            }
+            None => Received::Disconnected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn event(index: i32) -> KeyEvent {
+        KeyEvent::new(
+            ProgrammableKeys::get_from_index(index),
+            KeyState::Pressed,
+            None,
+        )
+    }
+
+    fn received_index(receiver: &KeyReceiver) -> Option<i32> {
+        match receiver.recv_until(Some(Instant::now())) {
+            Received::Event(event) => {
+                (1..=32).find(|index| ProgrammableKeys::get_from_index(*index) == event.key)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn events_come_out_in_order_after_a_burst() {
+        let (sender, receiver) = key_queue(32, OverflowPolicy::DropNewest);
+        for index in 1..=32 {
+            assert!(sender.send(event(index)));
+        }
+
+        let received: Vec<Option<i32>> = (1..=32).map(|_| received_index(&receiver)).collect();
+        let expected: Vec<Option<i32>> = (1..=32).map(Some).collect();
+        assert_eq!(received, expected);
+        assert!(matches!(
+            receiver.recv_until(Some(Instant::now())),
+            Received::Timeout
+        ));
+    }
+
+    #[test]
+    fn drop_newest_refuses_events_at_capacity() {
+        let (sender, receiver) = key_queue(2, OverflowPolicy::DropNewest);
+        assert!(sender.send(event(1)));
+        assert!(sender.send(event(2)));
+        assert!(!sender.send(event(3)));
+
+        assert_eq!(received_index(&receiver), Some(1));
+        assert_eq!(received_index(&receiver), Some(2));
+        assert_eq!(received_index(&receiver), None);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_at_capacity() {
+        let (sender, receiver) = key_queue(2, OverflowPolicy::DropOldest);
+        assert!(sender.send(event(1)));
+        assert!(sender.send(event(2)));
+        assert!(sender.send(event(3)));
+
+        assert_eq!(received_index(&receiver), Some(2));
+        assert_eq!(received_index(&receiver), Some(3));
+        assert_eq!(received_index(&receiver), None);
+    }
+
+    #[test]
+    fn block_waits_for_the_receiver_to_make_room() {
+        let (sender, receiver) = key_queue(1, OverflowPolicy::Block);
+        assert!(sender.send(event(1)));
+
+        let (sent, was_sent) = mpsc::channel();
+        let blocked = thread::spawn(move || sent.send(sender.send(event(2))).unwrap());
+
+        assert!(was_sent.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(received_index(&receiver), Some(1));
+        assert_eq!(was_sent.recv_timeout(Duration::from_secs(5)), Ok(true));
+        blocked.join().unwrap();
+        assert_eq!(received_index(&receiver), Some(2));
+    }
+
+    #[test]
+    fn block_gives_up_when_the_receiver_is_dropped() {
+        let (sender, receiver) = key_queue(1, OverflowPolicy::Block);
+        assert!(sender.send(event(1)));
+
+        let (sent, was_sent) = mpsc::channel();
+        let blocked = thread::spawn(move || {
+            sent.send(sender.send(event(2))).unwrap();
+            sender
+        });
+
+        assert!(was_sent.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(receiver);
+        assert_eq!(was_sent.recv_timeout(Duration::from_secs(5)), Ok(false));
+
+        let sender = blocked.join().unwrap();
+        assert!(!sender.send(event(3)));
+    }
+
+    #[test]
+    fn events_keep_their_timestamp_and_device() {
+        let (sender, receiver) = key_queue(4, OverflowPolicy::DropNewest);
+        let sent = KeyEvent::new(
+            ProgrammableKeys::get_from_index(5),
+            KeyState::Released,
+            Some("/dev/input/event7".to_string()),
+        );
+        let timestamp = sent.timestamp;
+
+        thread::sleep(Duration::from_millis(5));
+        assert!(sender.send(sent));
+
+        match receiver.recv_until(None) {
+            Received::Event(event) => {
+                assert_eq!(event.key, ProgrammableKeys::get_from_index(5));
+                assert_eq!(event.state, KeyState::Released);
+                assert_eq!(event.timestamp, timestamp);
+                assert_eq!(event.device.as_deref(), Some("/dev/input/event7"));
+            }
+            other => panic!("expected an event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn waiting_receiver_wakes_up_on_send() {
+        let (sender, receiver) = key_queue(4, OverflowPolicy::DropNewest);
+        let waiting = thread::spawn(move || {
+            let received = receiver.recv_until(None);
+            (received, receiver)
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        let sent_at = Instant::now();
+        assert!(sender.send(event(1)));
+
+        let (received, receiver) = waiting.join().unwrap();
+        assert!(matches!(received, Received::Event(_)));
+        assert!(sent_at.elapsed() < Duration::from_secs(1));
+
+        // the last sender going away wakes it too
+        let waiting = thread::spawn(move || receiver.recv_until(None));
+        thread::sleep(Duration::from_millis(50));
+        drop(sender);
+        assert!(matches!(waiting.join().unwrap(), Received::Disconnected));
+    }
+
+    #[test]
+    fn deadline_passes_without_events() {
+        let (_sender, receiver) = key_queue(4, OverflowPolicy::DropNewest);
+        let deadline = Instant::now() + Duration::from_millis(20);
+
+        assert!(matches!(
+            receiver.recv_until(Some(deadline)),
+            Received::Timeout
+        ));
+        assert!(Instant::now() >= deadline);
+    }
+}
@@ -1,14 +1,26 @@
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io;
-use std::io::{Error, Read, Write};
+use std::io::{Read, Write};
 use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tauri::api::path;
 
+use crate::backups;
+use crate::bindings::KeyBinding;
+use crate::button_codes::CodeRange;
+use crate::keymap_watcher;
+use crate::macro_script;
+use crate::migrations;
+use crate::migrations::CURRENT_SCHEMA_VERSION;
 use crate::programmable_keys::ProgrammableKeys;
+use crate::validation;
+use crate::validation::{Diagnostic, KeymapError};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Key {
     Alt,
     Backspace,
@@ -103,6 +115,16 @@ pub enum Key {
     Unknown(u32),
 }
 
+/// Mouse buttons a macro can use, `Back` and `Forward` are the usual side buttons
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum MacroAction {
     Print(String),
@@ -111,6 +133,41 @@ pub enum MacroAction {
     Release(Key),
     Delay(u64),
     None,
+    /// activates a layer while the button stays held
+    LayerMomentary(String),
+    /// turns a layer on or off until toggled again
+    LayerToggle(String),
+    /// activates a layer for the next button press only
+    LayerOneShot(String),
+    /// moves the pointer to a position on screen, in pixels from the top left
+    MouseMoveTo {
+        x: i32,
+        y: i32,
+    },
+    /// moves the pointer relative to where it is, a drag is a press, a move and a release
+    MouseMoveBy {
+        x: i32,
+        y: i32,
+    },
+    MousePress(MouseButton),
+    MouseRelease(MouseButton),
+    MouseClick(MouseButton),
+    /// scrolls down by this many steps, negative values scroll up
+    ScrollVertical(i32),
+    /// scrolls right by this many steps, negative values scroll left
+    ScrollHorizontal(i32),
+}
+
+impl MacroAction {
+    /// Layer actions change which bindings are live instead of sending input
+    pub fn is_layer_action(&self) -> bool {
+        matches!(
+            self,
+            MacroAction::LayerMomentary(_)
+                | MacroAction::LayerToggle(_)
+                | MacroAction::LayerOneShot(_)
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -120,18 +177,181 @@ pub enum MacroType {
     Repeat(i32),
 }
 
+impl MacroType {
+    /// Whether the macro keeps running until its button is pressed again
+    pub fn loops_until_stopped(&self) -> bool {
+        match self {
+            MacroType::Toggle => true,
+            MacroType::Repeat(count) => *count <= 0,
+            MacroType::Once => false,
+        }
+    }
+}
+
+/// What happens when a button is pressed while its macro is still running
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub enum RunPolicy {
+    /// run again once the current run finishes
+    #[default]
+    Queue,
+    /// start another run alongside the current one
+    Parallel,
+    /// cancel the current run and start over
+    Restart,
+    /// drop the press
+    Ignore,
+}
+
+/// Button events a macro key can respond to
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Trigger {
+    /// the button was pressed, or tapped once if it has multi-tap bindings
+    Press,
+    Release,
+    /// the button has been held down past its hold threshold, also used as the long press
+    Hold,
+    DoubleTap,
+    TripleTap,
+}
+
+/// Timings used to tell taps, multi-taps and long presses apart
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(default)]
+pub struct TapTiming {
+    /// how long after a release another press still counts towards the same multi-tap
+    pub tap_window_ms: u64,
+    /// how long a button has to be held before its `on_hold` actions run
+    pub long_press_ms: u64,
+}
+
+impl Default for TapTiming {
+    fn default() -> Self {
+        TapTiming {
+            tap_window_ms: 200,
+            long_press_ms: 500,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct MacroKey {
     pub programmable_key: ProgrammableKeys,
     pub macro_type: MacroType,
+    #[serde(default)]
+    pub run_policy: RunPolicy,
+    #[serde(default)]
     pub actions: Vec<MacroAction>,
+    /// `actions` written as a macro script, compiled over them when the keymap loads.
+    /// Only `actions` has a script, the `on_*` lists below are always stored as actions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// runs on press instead of `actions` when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_press: Option<Vec<MacroAction>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_release: Option<Vec<MacroAction>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_hold: Option<Vec<MacroAction>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_double_tap: Option<Vec<MacroAction>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_triple_tap: Option<Vec<MacroAction>>,
+    /// overrides the keymap's long press time for this button
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_threshold_ms: Option<u64>,
+    /// overrides the keymap's tap window for this button
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tap_window_ms: Option<u64>,
+}
+
+impl MacroKey {
+    /// Create a blank macro key for a button
+    pub fn new(programmable_key: ProgrammableKeys) -> MacroKey {
+        MacroKey {
+            programmable_key,
+            macro_type: MacroType::Once,
+            run_policy: RunPolicy::default(),
+            actions: vec![MacroAction::None],
+            script: None,
+            on_press: None,
+            on_release: None,
+            on_hold: None,
+            on_double_tap: None,
+            on_triple_tap: None,
+            hold_threshold_ms: None,
+            tap_window_ms: None,
+        }
+    }
+
+    /// Returns the actions bound to a trigger, if any
+    pub fn actions_for(&self, trigger: &Trigger) -> Option<&Vec<MacroAction>> {
+        match trigger {
+            Trigger::Press => Some(self.on_press.as_ref().unwrap_or(&self.actions)),
+            Trigger::Release => self.on_release.as_ref(),
+            Trigger::Hold => self.on_hold.as_ref(),
+            Trigger::DoubleTap => self.on_double_tap.as_ref(),
+            Trigger::TripleTap => self.on_triple_tap.as_ref(),
+        }
+    }
+
+    /// Every action the button can run, whatever triggers it
+    pub fn all_actions(&self) -> impl Iterator<Item = &MacroAction> {
+        [
+            &self.on_press,
+            &self.on_release,
+            &self.on_hold,
+            &self.on_double_tap,
+            &self.on_triple_tap,
+        ]
+        .into_iter()
+        .flatten()
+        .chain([&self.actions])
+        .flatten()
+    }
+
+    /// Rewrites the script if `actions` were changed without it, so it never
+    /// undoes those changes the next time the keymap loads
+    fn sync_script(&mut self) {
+        if let Some(script) = &self.script {
+            if macro_script::parse(script).ok().as_ref() != Some(&self.actions) {
+                self.script = Some(macro_script::print(&self.actions));
+            }
+        }
+    }
+
+    pub fn hold_threshold(&self, timing: &TapTiming) -> Duration {
+        Duration::from_millis(self.hold_threshold_ms.unwrap_or(timing.long_press_ms))
+    }
+
+    pub fn tap_window(&self, timing: &TapTiming) -> Duration {
+        Duration::from_millis(self.tap_window_ms.unwrap_or(timing.tap_window_ms))
+    }
+}
+
+/// name of the always active layer made up of the keymap's own buttons
+pub const BASE_LAYER: &str = "base";
+
+/// A named set of bindings stacked on top of the base layer. Buttons missing
+/// from a layer are transparent and fall through to the layers below.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Layer {
+    pub name: String,
+    pub buttons: Vec<MacroKey>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct Keymap {
+    /// files from before versioning have no version, see `migrations`
+    #[serde(default)]
+    pub schema_version: u64,
     pub(crate) map_name: String,
     pub(crate) button_count: i32,
     pub buttons: Vec<MacroKey>,
+    #[serde(default)]
+    pub timing: TapTiming,
+    /// extra layers, later layers take priority over earlier ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
 }
 
 impl Keymap {
@@ -141,83 +361,281 @@ impl Keymap {
 
         // Fill a blank vec of macros, incrementing for each new button
         for i in 1..count + 1 {
-            blank_buttons.push(MacroKey {
-                programmable_key: ProgrammableKeys::get_from_index(i),
-                macro_type: MacroType::Once,
-                actions: vec![MacroAction::None],
-            })
+            blank_buttons.push(MacroKey::new(ProgrammableKeys::get_from_index(i)))
         }
 
         Keymap {
+            schema_version: CURRENT_SCHEMA_VERSION,
             map_name: name,
             button_count: count,
             buttons: blank_buttons,
+            timing: TapTiming::default(),
+            layers: Vec::new(),
         }
     }
 
-    /// Load a keymap json file into a Keymap struct, returning an error
-    /// if no file is found.
-    pub fn load_from_file(keymap_name: String) -> Result<Keymap, Error> {
+    /// Finds the binding for a button, checking the active layers from the
+    /// highest priority down before falling back to the base layer
+    pub fn find_button(
+        &self,
+        key: &ProgrammableKeys,
+        active_layers: &[String],
+    ) -> Option<&MacroKey> {
+        self.layers
+            .iter()
+            .rev()
+            .filter(|layer| active_layers.contains(&layer.name))
+            .flat_map(|layer| layer.buttons.iter())
+            .chain(self.buttons.iter())
+            .find(|k| k.programmable_key == *key)
+    }
+
+    /// Makes `button_count` match the base layer's buttons again, the buttons are what gets used
+    pub fn correct_button_count(&mut self) {
+        self.button_count = self.buttons.len() as i32;
+    }
+
+    /// Adds a button to the base layer
+    pub fn add_button(&mut self, button: MacroKey) {
+        self.button_count += 1;
+        self.buttons.push(button);
+    }
+
+    /// Replaces the actions of a button in the base layer, adding the button if
+    /// the keymap doesn't have it yet
+    pub fn set_button_actions(&mut self, button: &ProgrammableKeys, actions: Vec<MacroAction>) {
+        match self
+            .buttons
+            .iter_mut()
+            .find(|macro_key| macro_key.programmable_key == *button)
+        {
+            Some(macro_key) => macro_key.actions = actions,
+            None => self.add_button(MacroKey {
+                actions,
+                ..MacroKey::new(button.clone())
+            }),
+        }
+    }
+
+    /// Every key binding on any layer
+    pub fn key_bindings(&self) -> impl Iterator<Item = &KeyBinding> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.buttons.iter())
+            .chain(self.buttons.iter())
+            .filter_map(|macro_key| match &macro_key.programmable_key {
+                ProgrammableKeys::Binding(binding) => Some(binding.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// The folder all keymap files live in
+    pub fn keymap_dir() -> PathBuf {
+        let mut keymap_dir = path::local_data_dir().unwrap();
+        keymap_dir.extend(["hotmap", "keymaps"]);
+        keymap_dir
+    }
+
+    /// The json file a keymap is stored in
+    pub fn keymap_path(keymap_name: &str) -> PathBuf {
+        let mut keymap_path = Keymap::keymap_dir();
+        keymap_path.push(keymap_name.to_string().add(".json"));
+        keymap_path
+    }
+
+    /// Load a keymap json file into a Keymap struct, returning a blank keymap
+    /// if there is no file yet and an error if the file can't be used.
+    pub fn load_from_file(
+        keymap_name: String,
+        ranges: &[CodeRange],
+    ) -> Result<Keymap, KeymapError> {
         // create the path to keymap json file in the appdata directory
-        let mut keymap_path = path::local_data_dir().unwrap();
-        let binding = keymap_name.to_string().add(".json");
-        let file_name = binding.as_str();
-        keymap_path.extend(["hotmap", "keymaps"]);
-        keymap_path.push(file_name);
+        let keymap_path = Keymap::keymap_path(&keymap_name);
 
         if !keymap_path.exists() {
             println!("Didn't find existing keymap file");
-            return Ok(Keymap::new("keymap".to_string(), 1));
+            return Ok(Keymap::new(keymap_name, 1));
         }
 
-        // check if file exists, if not return an error
-        let mut keymap_file = match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(keymap_path)
-        {
-            Ok(file) => file,
-            Err(err) => {
-                return Err(err);
-            }
-        };
-
         let mut keymap_json = String::new();
-        keymap_file
-            .read_to_string(&mut keymap_json)
-            .expect("Failed to read keymap file!");
+        File::open(&keymap_path)?.read_to_string(&mut keymap_json)?;
+
+        let (temp, migrated_from) = Keymap::from_json(keymap_name, &keymap_json, ranges)?;
+
+        // keep the original around before rewriting it in the new format
+        if let Some(version) = migrated_from {
+            match backups::backup_before_migration(&temp.map_name, version, &keymap_json) {
+                Ok(backup_path) => {
+                    println!("Backed up the original keymap file to {:?}", backup_path);
 
-        // copy values from json file into the used keymap
-        let temp: Keymap = serde_json::from_str(&keymap_json).unwrap();
+                    if let Err(err) = Keymap::write_to_file(&temp) {
+                        eprintln!("Failed to save the upgraded keymap file: {}", err);
+                    }
+                }
+                Err(err) => eprintln!(
+                    "Failed to back up the keymap file, leaving it as is: {}",
+                    err
+                ),
+            }
+        }
 
         println!("Keymap file exists: {:?}", temp);
 
         Ok(temp)
     }
 
-    /// Saves a Keymap struct to a json file
-    pub fn save_to_file(keymap: Keymap) -> Result<(), io::Error> {
-        // establish a lock on the keymap while reading
-        // create the path to keymap json file in the appdata directory
-        let mut keymap_path = path::local_data_dir().unwrap();
-        let binding = keymap.map_name.to_string().add(".json");
-        let file_name = binding.as_str();
-        keymap_path.extend(["hotmap", "keymaps"]);
+    /// Reads a keymap from json, migrating and validating it. Also returns the
+    /// schema version it was migrated from, if it was.
+    pub fn from_json(
+        keymap_name: String,
+        keymap_json: &str,
+        ranges: &[CodeRange],
+    ) -> Result<(Keymap, Option<u64>), KeymapError> {
+        let document: Value = serde_json::from_str(keymap_json)?;
 
-        if !keymap_path.exists() {
-            std::fs::create_dir_all(&keymap_path)?;
+        // read up to date files straight from the text, so type errors keep their line and column
+        if migrations::schema_version(&document)? == CURRENT_SCHEMA_VERSION {
+            let keymap: Keymap = serde_json::from_str(keymap_json)?;
+            return Ok((Keymap::checked(keymap_name, keymap, ranges)?, None));
+        }
+
+        Keymap::from_document(keymap_name, document, ranges)
+    }
+
+    /// Reads a keymap from an already parsed document, migrating and validating it.
+    /// Also returns the schema version it was migrated from, if it was.
+    pub fn from_document(
+        keymap_name: String,
+        mut document: Value,
+        ranges: &[CodeRange],
+    ) -> Result<(Keymap, Option<u64>), KeymapError> {
+        // bring files from older versions up to date before reading them
+        let version = migrations::schema_version(&document)?;
+        let migrated = version != CURRENT_SCHEMA_VERSION;
+        if migrated {
+            migrations::migrate(&mut document)?;
+        }
+
+        let keymap: Keymap = serde_json::from_value(document)?;
+        Ok((
+            Keymap::checked(keymap_name, keymap, ranges)?,
+            migrated.then_some(version),
+        ))
+    }
+
+    /// Names a freshly read keymap, compiles its macro scripts and rejects it if
+    /// validation finds fatal problems
+    fn checked(
+        keymap_name: String,
+        mut keymap: Keymap,
+        ranges: &[CodeRange],
+    ) -> Result<Keymap, KeymapError> {
+        // the file name is the keymap's identity, so a stale name inside can't fork it on save
+        keymap.map_name = keymap_name;
+        keymap.compile_scripts()?;
+
+        let problems = validation::validate(&keymap, ranges);
+        if problems.iter().any(|problem| problem.is_fatal()) {
+            return Err(KeymapError::Invalid(problems));
+        }
+
+        for problem in problems {
+            eprintln!("Keymap {} warning: {}", keymap.map_name, problem);
+        }
+
+        keymap.correct_button_count();
+        Ok(keymap)
+    }
+
+    /// Replaces the actions of every button that has a macro script with the compiled script
+    fn compile_scripts(&mut self) -> Result<(), KeymapError> {
+        let layers = [(BASE_LAYER, &mut self.buttons)].into_iter().chain(
+            self.layers
+                .iter_mut()
+                .map(|layer| (layer.name.as_str(), &mut layer.buttons)),
+        );
+
+        for (layer, buttons) in layers {
+            for macro_key in buttons.iter_mut() {
+                if let Some(script) = &macro_key.script {
+                    macro_key.actions =
+                        macro_script::parse(script).map_err(|error| KeymapError::Script {
+                            layer: layer.to_string(),
+                            button: macro_key.programmable_key.clone(),
+                            error,
+                        })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads a keymap, falling back to a blank one if the file can't be used.
+    /// The broken file is copied next to it so saving the blank keymap can't lose it.
+    pub fn load_or_fallback(keymap_name: &str, ranges: &[CodeRange]) -> (Keymap, Vec<Diagnostic>) {
+        match Keymap::load_from_file(keymap_name.to_string(), ranges) {
+            Ok(keymap) => {
+                let diagnostics = validation::validate(&keymap, ranges)
+                    .iter()
+                    .map(Diagnostic::from)
+                    .collect();
+                (keymap, diagnostics)
+            }
+            Err(err) => {
+                eprintln!("Failed to load keymap {}: {}", keymap_name, err);
+
+                let keymap_path = Keymap::keymap_path(keymap_name);
+                let broken_path = keymap_path.with_extension("json.broken");
+                match std::fs::copy(&keymap_path, &broken_path) {
+                    Ok(_) => eprintln!("Kept the broken keymap file as {:?}", broken_path),
+                    Err(err) => eprintln!("Failed to keep the broken keymap file: {}", err),
+                }
+
+                (Keymap::new(keymap_name.to_string(), 0), err.diagnostics())
+            }
+        }
+    }
+
+    /// Saves a Keymap struct to a json file, backing up the file it replaces and
+    /// keeping that many backups of it
+    pub fn save_to_file(keymap: Keymap, backups: usize) -> Result<(), io::Error> {
+        if let Err(err) = backups::backup_keymap_file(&keymap.map_name, backups) {
+            eprintln!("Failed to back up keymap file: {}", err);
         }
 
-        keymap_path.push(file_name);
+        Keymap::write_to_file(&keymap)
+    }
 
-        let mut keymap_file = File::create(keymap_path)?;
+    /// Writes a keymap to its json file without taking a backup
+    fn write_to_file(keymap: &Keymap) -> Result<(), io::Error> {
+        let mut keymap = keymap.clone();
+        keymap.schema_version = CURRENT_SCHEMA_VERSION;
 
-        match keymap_file.write_all(
-            serde_json::to_string(&keymap.clone())
-                .expect("Failed to parse keymap file!")
-                .as_bytes(),
+        for macro_key in keymap.buttons.iter_mut().chain(
+            keymap
+                .layers
+                .iter_mut()
+                .flat_map(|layer| layer.buttons.iter_mut()),
         ) {
+            macro_key.sync_script();
+        }
+
+        // create the path to keymap json file in the appdata directory
+        let keymap_dir = Keymap::keymap_dir();
+
+        if !keymap_dir.exists() {
+            std::fs::create_dir_all(&keymap_dir)?;
+        }
+
+        let keymap_path = Keymap::keymap_path(&keymap.map_name);
+
+        let keymap_json = serde_json::to_string(&keymap).expect("Failed to parse keymap file!");
+
+        // let the watcher know this change came from us
+        keymap_watcher::note_own_write(&keymap_path, keymap_json.as_bytes());
+
+        match write_atomically(&keymap_path, keymap_json.as_bytes()) {
             Ok(_) => Ok(()),
             Err(err) => {
                 eprintln!("Failed to write data to keymap file!");
@@ -226,3 +644,76 @@ impl Keymap {
         }
     }
 }
+
+/// Replaces a file without ever leaving it half written. The data goes to a
+/// temporary file next to it first, which is synced to disk and renamed over the target.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+
+    let mut temp_file = File::create(&temp_path)?;
+    if let Err(err) = temp_file
+        .write_all(contents)
+        .and_then(|_| temp_file.sync_all())
+    {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    drop(temp_file);
+
+    std::fs::rename(&temp_path, path)?;
+
+    // sync the folder too, so the rename itself survives a crash
+    if let Some(parent) = path.parent() {
+        #[cfg(unix)]
+        File::open(parent)?.sync_all()?;
+        #[cfg(not(unix))]
+        let _ = parent;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn mouse_actions_round_trip_through_json() {
+        let actions = vec![
+            MacroAction::MouseMoveTo { x: 10, y: -20 },
+            MacroAction::MouseMoveBy { x: -3, y: 4 },
+            MacroAction::MousePress(MouseButton::Left),
+            MacroAction::MouseRelease(MouseButton::Right),
+            MacroAction::MouseClick(MouseButton::Middle),
+            MacroAction::MouseClick(MouseButton::Back),
+            MacroAction::MouseClick(MouseButton::Forward),
+            MacroAction::ScrollVertical(-5),
+            MacroAction::ScrollHorizontal(2),
+        ];
+
+        let document = serde_json::to_value(&actions).unwrap();
+        assert_eq!(
+            document,
+            json!([
+                { "MouseMoveTo": { "x": 10, "y": -20 } },
+                { "MouseMoveBy": { "x": -3, "y": 4 } },
+                { "MousePress": "Left" },
+                { "MouseRelease": "Right" },
+                { "MouseClick": "Middle" },
+                { "MouseClick": "Back" },
+                { "MouseClick": "Forward" },
+                { "ScrollVertical": -5 },
+                { "ScrollHorizontal": 2 },
+            ])
+        );
+
+        let read_back: Vec<MacroAction> = serde_json::from_value(document).unwrap();
+        assert_eq!(read_back, actions);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::button_codes::CodeRange;
+use crate::keymap::{write_atomically, Keymap};
+use crate::validation::KeymapError;
+
+/// File formats a keymap can be read from and written to. Profiles are always
+/// stored as json, the others are for importing, exporting and hand editing.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeymapFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl KeymapFormat {
+    /// Picks the format from a file's extension
+    pub fn from_path(path: &Path) -> Result<KeymapFormat, io::Error> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("json") => Ok(KeymapFormat::Json),
+            Some("toml") => Ok(KeymapFormat::Toml),
+            Some("yaml") | Some("yml") => Ok(KeymapFormat::Yaml),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Can't tell the keymap format of {:?}, use a .json, .toml or .yaml file",
+                    path
+                ),
+            )),
+        }
+    }
+
+    /// Writes a keymap out in this format
+    pub fn serialize(self, keymap: &Keymap) -> Result<String, io::Error> {
+        let serialized = match self {
+            KeymapFormat::Json => {
+                serde_json::to_string_pretty(keymap).map_err(|err| err.to_string())
+            }
+            KeymapFormat::Toml => toml::to_string_pretty(keymap).map_err(|err| err.to_string()),
+            KeymapFormat::Yaml => {
+                // plain `Print: text` maps instead of yaml tags for the action enums
+                let mut yaml = Vec::new();
+                let mut serializer = serde_yaml::Serializer::new(&mut yaml);
+                serde_yaml::with::singleton_map_recursive::serialize(keymap, &mut serializer)
+                    .map_err(|err| err.to_string())
+                    .and_then(|_| String::from_utf8(yaml).map_err(|err| err.to_string()))
+            }
+        };
+
+        serialized.map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    /// Reads a keymap document in this format into json, so every format goes
+    /// through the same migrations and validation
+    pub fn to_document(self, text: &str) -> Result<Value, KeymapError> {
+        match self {
+            KeymapFormat::Json => Ok(serde_json::from_str(text)?),
+            KeymapFormat::Toml => toml::from_str(text).map_err(|err| {
+                let (line, column) = err
+                    .span()
+                    .map(|span| line_and_column(text, span.start))
+                    .unwrap_or_default();
+
+                KeymapError::Parse {
+                    line,
+                    column,
+                    message: err.message().to_string(),
+                }
+            }),
+            KeymapFormat::Yaml => {
+                let deserializer = serde_yaml::Deserializer::from_str(text);
+                serde_yaml::with::singleton_map_recursive::deserialize(deserializer).map_err(
+                    |err: serde_yaml::Error| {
+                        let (line, column) = err
+                            .location()
+                            .map(|location| (location.line(), location.column()))
+                            .unwrap_or_default();
+
+                        KeymapError::Parse {
+                            line,
+                            column,
+                            message: err.to_string(),
+                        }
+                    },
+                )
+            }
+        }
+    }
+}
+
+/// Turns a byte offset into a one based line and column
+pub fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+    (line, column)
+}
+
+/// Reads a keymap file in any supported format, named after the file
+pub fn import_keymap(path: &Path, ranges: &[CodeRange]) -> Result<Keymap, KeymapError> {
+    let format = KeymapFormat::from_path(path)?;
+    let text = fs::read_to_string(path)?;
+
+    let keymap_name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("imported")
+        .to_string();
+
+    let (keymap, _) = Keymap::from_document(keymap_name, format.to_document(&text)?, ranges)?;
+    Ok(keymap)
+}
+
+/// Writes a keymap to a file, in the format its extension asks for
+pub fn export_keymap(keymap: &Keymap, path: &Path) -> Result<(), io::Error> {
+    let format = KeymapFormat::from_path(path)?;
+    write_atomically(path, format.serialize(keymap)?.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::bindings::{KeyBinding, Modifier};
+    use crate::button_codes::default_ranges;
+    use crate::input_devices::DeviceSelector;
+    use crate::keymap::{Key, Layer, MacroAction, MacroKey, MacroType, MouseButton, RunPolicy};
+    use crate::programmable_keys::ProgrammableKeys;
+    use crate::test_support::use_temp_data_dir;
+
+    /// A keymap using every kind of action and binding the formats have to carry
+    fn full_keymap(name: &str) -> Keymap {
+        let mut keymap = Keymap::new(name.to_string(), 3);
+
+        keymap.buttons[0].actions = vec![
+            MacroAction::Print("hello \"world\"".to_string()),
+            MacroAction::Tap(Key::KeyA),
+            MacroAction::Press(Key::ShiftLeft),
+            MacroAction::Release(Key::ShiftLeft),
+            MacroAction::Delay(25),
+            MacroAction::None,
+            MacroAction::Tap(Key::Unknown(183)),
+        ];
+        keymap.buttons[0].macro_type = MacroType::Repeat(3);
+        keymap.buttons[0].run_policy = RunPolicy::Restart;
+
+        keymap.buttons[1].actions = vec![
+            MacroAction::MouseMoveTo { x: 100, y: 200 },
+            MacroAction::MouseMoveBy { x: -5, y: 10 },
+            MacroAction::MousePress(MouseButton::Left),
+            MacroAction::MouseRelease(MouseButton::Left),
+            MacroAction::MouseClick(MouseButton::Back),
+            MacroAction::ScrollVertical(-3),
+            MacroAction::ScrollHorizontal(2),
+        ];
+        keymap.buttons[1].macro_type = MacroType::Toggle;
+
+        keymap.buttons[2].on_press = Some(vec![MacroAction::LayerMomentary("fn".to_string())]);
+        keymap.buttons[2].on_release = Some(vec![MacroAction::Tap(Key::Escape)]);
+        keymap.buttons[2].on_hold = Some(vec![MacroAction::LayerToggle("fn".to_string())]);
+        keymap.buttons[2].on_double_tap = Some(vec![MacroAction::LayerOneShot("fn".to_string())]);
+        keymap.buttons[2].on_triple_tap = Some(vec![MacroAction::Tap(Key::Return)]);
+        keymap.buttons[2].hold_threshold_ms = Some(350);
+        keymap.buttons[2].tap_window_ms = Some(150);
+
+        keymap.timing.tap_window_ms = 180;
+        keymap.timing.long_press_ms = 600;
+
+        let mut binding = MacroKey::new(ProgrammableKeys::Binding(Box::new(KeyBinding {
+            key: Key::KeyK,
+            modifiers: vec![Modifier::Ctrl, Modifier::Alt],
+            device: Some(DeviceSelector {
+                name: Some("Test Keyboard".to_string()),
+                vendor: Some(0x046d),
+                product: None,
+                phys: None,
+            }),
+        })));
+        binding.actions = vec![MacroAction::Tap(Key::PageDown)];
+
+        keymap.layers.push(Layer {
+            name: "fn".to_string(),
+            buttons: vec![binding],
+        });
+
+        keymap
+    }
+
+    #[test]
+    fn every_format_round_trips_a_keymap() {
+        let export_dir = use_temp_data_dir().join("format-round-trip");
+        fs::create_dir_all(&export_dir).unwrap();
+
+        // imports are named after the file, so every export shares the keymap's name
+        let keymap = full_keymap("round-trip");
+
+        for extension in ["json", "toml", "yaml"] {
+            let path = export_dir.join(format!("round-trip.{}", extension));
+            export_keymap(&keymap, &path).unwrap();
+
+            let imported = import_keymap(&path, &default_ranges())
+                .unwrap_or_else(|err| panic!("Failed to import {}: {}", extension, err));
+            assert_eq!(imported, keymap, "{} changed the keymap", extension);
+        }
+    }
+
+    #[test]
+    fn picks_the_format_from_the_extension() {
+        assert_eq!(
+            KeymapFormat::from_path(Path::new("a.JSON")).unwrap(),
+            KeymapFormat::Json
+        );
+        assert_eq!(
+            KeymapFormat::from_path(Path::new("a.toml")).unwrap(),
+            KeymapFormat::Toml
+        );
+        assert_eq!(
+            KeymapFormat::from_path(Path::new("a.yml")).unwrap(),
+            KeymapFormat::Yaml
+        );
+        assert!(KeymapFormat::from_path(Path::new("a.txt")).is_err());
+        assert!(KeymapFormat::from_path(Path::new("a")).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::keymap::Keymap;
+use crate::profiles::Profiles;
+
+/// how long the keymaps folder has to stay quiet before a change is picked up,
+/// editors tend to save in several steps
+const SETTLE_TIME: Duration = Duration::from_millis(150);
+
+/// hashes of what the app itself last wrote to each keymap file
+static OWN_WRITES: Mutex<Vec<(PathBuf, u64)>> = Mutex::new(Vec::new());
+
+fn hash_contents(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Remembers a write made by the app, so the watcher doesn't reload it
+pub fn note_own_write(path: &Path, contents: &[u8]) {
+    let hash = hash_contents(contents);
+
+    match OWN_WRITES.lock() {
+        Ok(mut own_writes) => {
+            own_writes.retain(|(written, _)| written != path);
+            own_writes.push((path.to_path_buf(), hash));
+        }
+        Err(err) => eprintln!("Error retrieving own writes lock: {}", err),
+    }
+}
+
+fn is_own_write(path: &Path, contents: &[u8]) -> bool {
+    let hash = hash_contents(contents);
+
+    match OWN_WRITES.lock() {
+        Ok(own_writes) => own_writes
+            .iter()
+            .any(|(written, written_hash)| written == path && *written_hash == hash),
+        Err(err) => {
+            eprintln!("Error retrieving own writes lock: {}", err);
+            false
+        }
+    }
+}
+
+/// Watches the keymaps folder, reloading the active keymap when it is edited
+/// on disk and updating the profile list when keymap files come and go.
+/// Blocks for as long as the watcher runs.
+pub fn watch_keymaps(profiles: Profiles) {
+    let keymap_dir = Keymap::keymap_dir();
+    if let Err(err) = fs::create_dir_all(&keymap_dir) {
+        eprintln!("Failed to create keymap folder: {}", err);
+        return;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("Failed to start keymap watcher: {}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = watcher.watch(&keymap_dir, RecursiveMode::NonRecursive) {
+        eprintln!("Failed to watch {:?}: {}", keymap_dir, err);
+        return;
+    }
+
+    println!("Watching {:?} for keymap changes", keymap_dir);
+
+    while let Ok(event) = receiver.recv() {
+        let (changed, profiles_changed) = match settle(&receiver, event) {
+            Some(changes) => changes,
+            None => return,
+        };
+
+        let active_path = Keymap::keymap_path(&profiles.active());
+        if changed.contains(&active_path) {
+            reload_active(&profiles, &active_path);
+        }
+
+        if profiles_changed {
+            profiles.refresh();
+        }
+    }
+}
+
+/// Gathers the changes from an event and every event after it until the folder
+/// stays quiet, returning the keymap files that changed and whether files were
+/// added or removed. None once the watcher has stopped.
+fn settle(
+    receiver: &Receiver<notify::Result<Event>>,
+    event: notify::Result<Event>,
+) -> Option<(Vec<PathBuf>, bool)> {
+    let mut changed: Vec<PathBuf> = Vec::new();
+    let mut profiles_changed = false;
+    collect_event(event, &mut changed, &mut profiles_changed);
+
+    // wait for the folder to settle before reading anything
+    loop {
+        match receiver.recv_timeout(SETTLE_TIME) {
+            Ok(event) => collect_event(event, &mut changed, &mut profiles_changed),
+            Err(mpsc::RecvTimeoutError::Timeout) => return Some((changed, profiles_changed)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// Sorts a watcher event into keymap files that changed, and whether files were added or removed
+fn collect_event(
+    event: notify::Result<Event>,
+    changed: &mut Vec<PathBuf>,
+    profiles_changed: &mut bool,
+) {
+    let event = match event {
+        Ok(event) => event,
+        Err(err) => {
+            eprintln!("Keymap watcher error: {}", err);
+            return;
+        }
+    };
+
+    if matches!(event.kind, EventKind::Access(_)) {
+        return;
+    }
+
+    // temp files from atomic saves and hidden files aren't keymaps
+    let keymap_files = event.paths.into_iter().filter(|path| {
+        path.extension().is_some_and(|ext| ext == "json")
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| !name.starts_with('.'))
+    });
+
+    for path in keymap_files {
+        // a rename shows up as the new file appearing, which is also how atomic saves land
+        *profiles_changed |= matches!(
+            event.kind,
+            EventKind::Create(_)
+                | EventKind::Remove(_)
+                | EventKind::Modify(notify::event::ModifyKind::Name(_))
+        );
+
+        if !changed.contains(&path) {
+            changed.push(path);
+        }
+    }
+}
+
+fn reload_active(profiles: &Profiles, path: &Path) {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            // removed, or caught halfway through a non-atomic save, either way keep what we have
+            eprintln!("Failed to read changed keymap file: {}", err);
+            return;
+        }
+    };
+
+    if is_own_write(path, &contents) {
+        return;
+    }
+
+    println!("Keymap file {:?} changed on disk, reloading", path);
+    profiles.reload_active(&String::from_utf8_lossy(&contents));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    use notify::event::{AccessKind, CreateKind, DataChange, ModifyKind};
+
+    use crate::button_codes::{default_ranges, ButtonCodes};
+    use crate::events::EventSink;
+    use crate::layers::LayerState;
+    use crate::test_support::shared_settings;
+
+    fn event(kind: EventKind, path: &str) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(PathBuf::from(path)))
+    }
+
+    fn modified(path: &str) -> notify::Result<Event> {
+        event(
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            path,
+        )
+    }
+
+    fn send_later(
+        sender: Sender<notify::Result<Event>>,
+        events: Vec<(u64, notify::Result<Event>)>,
+    ) {
+        thread::spawn(move || {
+            for (wait_ms, event) in events {
+                thread::sleep(Duration::from_millis(wait_ms));
+                let _ = sender.send(event);
+            }
+        });
+    }
+
+    #[test]
+    fn changes_close_together_are_handled_once_settled() {
+        let (sender, receiver) = mpsc::channel();
+        let started = Instant::now();
+        send_later(
+            sender.clone(),
+            vec![
+                (30, modified("/keymaps/game.json")),
+                (
+                    30,
+                    event(EventKind::Access(AccessKind::Any), "/keymaps/work.json"),
+                ),
+                (30, modified("/keymaps/.game.json.tmp")),
+                (30, modified("/keymaps/game.json")),
+            ],
+        );
+
+        let changes = settle(&receiver, modified("/keymaps/game.json"));
+        assert_eq!(
+            changes,
+            Some((vec![PathBuf::from("/keymaps/game.json")], false))
+        );
+        assert!(started.elapsed() >= Duration::from_millis(120) + SETTLE_TIME);
+
+        // a file appearing changes the profile list
+        send_later(
+            sender.clone(),
+            vec![(
+                0,
+                event(EventKind::Create(CreateKind::File), "/keymaps/new.json"),
+            )],
+        );
+        let first = receiver.recv().unwrap();
+        assert_eq!(
+            settle(&receiver, first),
+            Some((vec![PathBuf::from("/keymaps/new.json")], true))
+        );
+        drop(sender);
+    }
+
+    #[test]
+    fn settling_stops_with_the_watcher() {
+        let (sender, receiver) = mpsc::channel();
+        drop(sender);
+
+        assert_eq!(settle(&receiver, modified("/keymaps/game.json")), None);
+    }
+
+    /// Profiles with a freshly saved profile active, and a subscriber to its events
+    fn watched_profiles(name: &str) -> (Profiles, Receiver<String>) {
+        let settings = shared_settings();
+        Keymap::save_to_file(Keymap::new(name.to_string(), 1), 0).unwrap();
+
+        let events = EventSink::new();
+        let subscriber = events.subscribe();
+        let profiles = Profiles::new(
+            Arc::new(Mutex::new(Keymap::new(name.to_string(), 1))),
+            Arc::new(Mutex::new(LayerState::load(name, &settings))),
+            events,
+            ButtonCodes::new(default_ranges()),
+            settings,
+            Vec::new(),
+        );
+        (profiles, subscriber)
+    }
+
+    fn reloaded(subscriber: &Receiver<String>) -> bool {
+        subscriber
+            .try_iter()
+            .any(|line| line.contains("\"load-keymap\""))
+    }
+
+    #[test]
+    fn own_saves_are_not_reloaded() {
+        let (profiles, subscriber) = watched_profiles("watch-own");
+        let path = Keymap::keymap_path("watch-own");
+
+        Keymap::save_to_file(Keymap::new("watch-own".to_string(), 3), 0).unwrap();
+        reload_active(&profiles, &path);
+        assert!(!reloaded(&subscriber));
+    }
+
+    #[test]
+    fn outside_edits_are_reloaded() {
+        let (profiles, subscriber) = watched_profiles("watch-outside");
+        let path = Keymap::keymap_path("watch-outside");
+
+        let edited = Keymap::new("watch-outside".to_string(), 3);
+        fs::write(&path, serde_json::to_string_pretty(&edited).unwrap()).unwrap();
+        reload_active(&profiles, &path);
+        assert!(reloaded(&subscriber));
+
+        // the same contents written by us again count as our own
+        Keymap::save_to_file(edited, 0).unwrap();
+        reload_active(&profiles, &path);
+        assert!(!reloaded(&subscriber));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::keymap::{Keymap, MacroAction, BASE_LAYER};
+use crate::programmable_keys::ProgrammableKeys;
+use crate::settings::SharedSettings;
+
+/// Tracks which keymap layers are live and why
+#[derive(Debug, Clone)]
+pub struct LayerState {
+    /// keymap the toggled layers are saved under
+    map_name: String,
+    settings: SharedSettings,
+    toggled: Vec<String>,
+    /// layers held on by a button, removed again when that button is released
+    momentary: Vec<(ProgrammableKeys, String)>,
+    one_shot: Option<String>,
+}
+
+impl LayerState {
+    /// Creates the layer state for a keymap, restoring the layers that were toggled on last time
+    pub fn load(map_name: &str, settings: &SharedSettings) -> LayerState {
+        let toggled = settings
+            .get()
+            .toggled_layers
+            .get(map_name)
+            .cloned()
+            .unwrap_or_default();
+
+        LayerState {
+            map_name: map_name.to_string(),
+            settings: settings.clone(),
+            toggled,
+            momentary: Vec::new(),
+            one_shot: None,
+        }
+    }
+
+    /// Active layer names from the highest priority down, always ending with the base layer
+    pub fn active_layers(&self, keymap: &Keymap) -> Vec<String> {
+        let mut active: Vec<String> = keymap
+            .layers
+            .iter()
+            .rev()
+            .map(|layer| layer.name.clone())
+            .filter(|name| self.is_active(name))
+            .collect();
+
+        active.push(BASE_LAYER.to_string());
+        active
+    }
+
+    fn is_active(&self, name: &String) -> bool {
+        self.toggled.contains(name)
+            || self.momentary.iter().any(|(_, layer)| layer == name)
+            || self.one_shot.as_ref() == Some(name)
+    }
+
+    /// Applies a layer action run by a button, returning true if the active layers changed.
+    /// Momentary layers only turn on while the button is still physically held.
+    pub fn apply(&mut self, key: &ProgrammableKeys, action: &MacroAction, held: bool) -> bool {
+        match action {
+            MacroAction::LayerMomentary(name) => {
+                if !held {
+                    eprintln!("Ignoring momentary layer {} from a released button", name);
+                    return false;
+                }
+
+                self.momentary.push((key.clone(), name.clone()));
+                true
+            }
+            MacroAction::LayerToggle(name) => {
+                if self.toggled.contains(name) {
+                    self.toggled.retain(|layer| layer != name);
+                } else {
+                    self.toggled.push(name.clone());
+                }
+
+                self.save_toggled();
+                true
+            }
+            MacroAction::LayerOneShot(name) => {
+                self.one_shot = Some(name.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops the momentary layers held by a button, returning true if any were active
+    pub fn release(&mut self, key: &ProgrammableKeys) -> bool {
+        let before = self.momentary.len();
+        self.momentary.retain(|(held_by, _)| held_by != key);
+        before != self.momentary.len()
+    }
+
+    /// Clears the one-shot layer once a press has used it, returning true if there was one
+    pub fn take_one_shot(&mut self) -> bool {
+        self.one_shot.take().is_some()
+    }
+
+    /// Remembers the toggled layers so they survive a restart
+    fn save_toggled(&self) {
+        let saved = self.settings.update(|settings| {
+            settings
+                .toggled_layers
+                .insert(self.map_name.clone(), self.toggled.clone());
+        });
+
+        if let Err(err) = saved {
+            eprintln!("Failed to save toggled layers: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::Layer;
+    use crate::settings::Settings;
+    use crate::test_support::shared_settings;
+
+    fn button(index: i32) -> ProgrammableKeys {
+        ProgrammableKeys::get_from_index(index)
+    }
+
+    /// A keymap with a `nav` and a higher priority `symbols` layer
+    fn keymap() -> Keymap {
+        let mut keymap = Keymap::new("layers".to_string(), 2);
+        for name in ["nav", "symbols"] {
+            keymap.layers.push(Layer {
+                name: name.to_string(),
+                buttons: Vec::new(),
+            });
+        }
+        keymap
+    }
+
+    fn active(state: &LayerState) -> Vec<String> {
+        state.active_layers(&keymap())
+    }
+
+    #[test]
+    fn momentary_layer_lasts_while_its_button_is_held() {
+        let mut state = LayerState::load("momentary", &shared_settings());
+        let hold = MacroAction::LayerMomentary("nav".to_string());
+
+        assert!(!state.apply(&button(1), &hold, false));
+        assert_eq!(active(&state), vec![BASE_LAYER]);
+
+        assert!(state.apply(&button(1), &hold, true));
+        assert_eq!(active(&state), vec!["nav", BASE_LAYER]);
+
+        // another button letting go leaves it on
+        assert!(!state.release(&button(2)));
+        assert!(state.release(&button(1)));
+        assert_eq!(active(&state), vec![BASE_LAYER]);
+    }
+
+    #[test]
+    fn toggled_layer_stays_until_toggled_again() {
+        let mut state = LayerState::load("toggle", &shared_settings());
+        let toggle = MacroAction::LayerToggle("symbols".to_string());
+
+        assert!(state.apply(&button(1), &toggle, true));
+        assert!(!state.release(&button(1)));
+        assert_eq!(active(&state), vec!["symbols", BASE_LAYER]);
+
+        assert!(state.apply(&button(1), &toggle, true));
+        assert_eq!(active(&state), vec![BASE_LAYER]);
+    }
+
+    #[test]
+    fn one_shot_layer_is_used_up_by_one_press() {
+        let mut state = LayerState::load("one-shot", &shared_settings());
+
+        assert!(state.apply(
+            &button(1),
+            &MacroAction::LayerOneShot("nav".to_string()),
+            true
+        ));
+        assert_eq!(active(&state), vec!["nav", BASE_LAYER]);
+
+        assert!(state.take_one_shot());
+        assert_eq!(active(&state), vec![BASE_LAYER]);
+        assert!(!state.take_one_shot());
+    }
+
+    #[test]
+    fn later_layers_take_priority() {
+        let mut state = LayerState::load("priority", &shared_settings());
+        state.apply(
+            &button(1),
+            &MacroAction::LayerToggle("symbols".to_string()),
+            true,
+        );
+        state.apply(
+            &button(2),
+            &MacroAction::LayerMomentary("nav".to_string()),
+            true,
+        );
+
+        assert_eq!(active(&state), vec!["symbols", "nav", BASE_LAYER]);
+    }
+
+    #[test]
+    fn toggled_layers_are_saved_for_their_keymap() {
+        let settings = shared_settings();
+        let mut state = LayerState::load("persist", &settings);
+        state.apply(
+            &button(1),
+            &MacroAction::LayerToggle("nav".to_string()),
+            true,
+        );
+
+        let stored = Settings::load();
+        assert_eq!(
+            stored.toggled_layers.get("persist"),
+            Some(&vec!["nav".to_string()])
+        );
+        assert_eq!(stored.toggled_layers.get("other-keymap"), None);
+
+        // a fresh start turns them back on, but only for that keymap
+        let restored = LayerState::load("persist", &SharedSettings::new(stored.clone()));
+        assert_eq!(active(&restored), vec!["nav", BASE_LAYER]);
+        let other = LayerState::load("other-keymap", &SharedSettings::new(stored));
+        assert_eq!(active(&other), vec![BASE_LAYER]);
+    }
+}
@@ -0,0 +1,119 @@
+// only linux has input event codes
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use crate::keymap::Key;
+
+/// Linux input event codes for the keymap's keys, these name physical key
+/// positions so they don't change with the keyboard layout
+const KEY_CODES: &[(u32, Key)] = &[
+    (1, Key::Escape),
+    (2, Key::Num1),
+    (3, Key::Num2),
+    (4, Key::Num3),
+    (5, Key::Num4),
+    (6, Key::Num5),
+    (7, Key::Num6),
+    (8, Key::Num7),
+    (9, Key::Num8),
+    (10, Key::Num9),
+    (11, Key::Num0),
+    (12, Key::Minus),
+    (13, Key::Equal),
+    (14, Key::Backspace),
+    (15, Key::Tab),
+    (16, Key::KeyQ),
+    (17, Key::KeyW),
+    (18, Key::KeyE),
+    (19, Key::KeyR),
+    (20, Key::KeyT),
+    (21, Key::KeyY),
+    (22, Key::KeyU),
+    (23, Key::KeyI),
+    (24, Key::KeyO),
+    (25, Key::KeyP),
+    (26, Key::LeftBracket),
+    (27, Key::RightBracket),
+    (28, Key::Return),
+    (29, Key::ControlLeft),
+    (30, Key::KeyA),
+    (31, Key::KeyS),
+    (32, Key::KeyD),
+    (33, Key::KeyF),
+    (34, Key::KeyG),
+    (35, Key::KeyH),
+    (36, Key::KeyJ),
+    (37, Key::KeyK),
+    (38, Key::KeyL),
+    (39, Key::SemiColon),
+    (40, Key::Quote),
+    (41, Key::BackQuote),
+    (42, Key::ShiftLeft),
+    (43, Key::BackSlash),
+    (44, Key::KeyZ),
+    (45, Key::KeyX),
+    (46, Key::KeyC),
+    (47, Key::KeyV),
+    (48, Key::KeyB),
+    (49, Key::KeyN),
+    (50, Key::KeyM),
+    (51, Key::Comma),
+    (52, Key::Dot),
+    (53, Key::Slash),
+    (54, Key::ShiftRight),
+    (55, Key::KpMultiply),
+    (56, Key::Alt),
+    (57, Key::Space),
+    (58, Key::CapsLock),
+    (59, Key::F1),
+    (60, Key::F2),
+    (61, Key::F3),
+    (62, Key::F4),
+    (63, Key::F5),
+    (64, Key::F6),
+    (65, Key::F7),
+    (66, Key::F8),
+    (67, Key::F9),
+    (68, Key::F10),
+    (69, Key::NumLock),
+    (78, Key::KpPlus),
+    (86, Key::IntlBackslash),
+    (87, Key::F11),
+    (88, Key::F12),
+    (97, Key::ControlRight),
+    (99, Key::PrintScreen),
+    // the keymap only has the one alt key, so both alts read back as left alt
+    (100, Key::Alt),
+    (102, Key::Home),
+    (103, Key::UpArrow),
+    (104, Key::PageUp),
+    (105, Key::LeftArrow),
+    (106, Key::RightArrow),
+    (107, Key::End),
+    (108, Key::DownArrow),
+    (109, Key::PageDown),
+    (110, Key::Insert),
+    (111, Key::Delete),
+    (119, Key::Pause),
+    (125, Key::MetaLeft),
+    (126, Key::MetaRight),
+];
+
+/// The keymap key for a linux input event code, codes without one are kept as `Key::Unknown`
+pub fn key_from_code(code: u32) -> Key {
+    KEY_CODES
+        .iter()
+        .find(|(key_code, _)| *key_code == code)
+        .map_or(Key::Unknown(code), |(_, key)| key.clone())
+}
+
+/// The linux input event code for a key, `Key::Unknown` holds its code as is
+pub fn code_for_key(key: &Key) -> u32 {
+    match key {
+        Key::Unknown(code) => *code,
+        key => KEY_CODES
+            .iter()
+            .find(|(_, code_key)| code_key == key)
+            .map(|(code, _)| *code)
+            .expect("every named key has a linux key code"),
+    }
+}
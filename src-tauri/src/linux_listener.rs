@@ -1,27 +1,33 @@
-use std::{thread, time};
 use std::fs::{File, OpenOptions};
-use std::os::fd::OwnedFd;
+use std::os::fd::{AsRawFd, OwnedFd};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use input::{Event, Libinput, LibinputInterface};
 use input::event::keyboard::KeyboardEventTrait;
+use input::event::EventTrait;
 use input::event::KeyboardEvent;
-use libc::{O_RDONLY, O_RDWR, O_WRONLY};
-
-use crate::programmable_keys::ProgrammableKeys;
+use input::{Event, Libinput, LibinputInterface};
+use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
 
-const LIBINPUT_FETCH_DELAY: time::Duration = time::Duration::from_millis(20);
+use crate::bindings::ChordTracker;
+use crate::button_codes::ButtonCodes;
+use crate::input_devices::InputDevice;
+use crate::key_queue::{KeyEvent, KeySender, KeyState};
+use crate::keymap::Keymap;
+use crate::linux_keycodes;
+use crate::recorder::MacroRecorder;
 
 struct Interface;
 
 impl LibinputInterface for Interface {
     fn open_restricted(&mut self, path: &Path, flags: i32) -> Result<OwnedFd, i32> {
+        // O_RDONLY is zero, so the access mode has to be compared, not tested as bits
+        let access = flags & O_ACCMODE;
         OpenOptions::new()
             .custom_flags(flags)
-            .read((flags & O_RDONLY != 0) | (flags & O_RDWR != 0))
-            .write((flags & O_WRONLY != 0) | (flags & O_RDWR != 0))
+            .read(access == O_RDONLY || access == O_RDWR)
+            .write(access == O_WRONLY || access == O_RDWR)
             .open(path)
             .map(|file| file.into())
             .map_err(|err| err.raw_os_error().unwrap())
@@ -31,25 +37,61 @@ impl LibinputInterface for Interface {
     }
 }
 
-fn watch_events(input: Libinput, queue: &Arc<Mutex<Vec<ProgrammableKeys>>>) {
+/// Blocks until libinput has new events to read
+fn wait_for_events(input: &Libinput) {
+    let mut poll_fd = libc::pollfd {
+        fd: input.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // an error here just means we dispatch early, which is harmless
+    unsafe {
+        libc::poll(&mut poll_fd, 1, -1);
+    }
+}
+
+/// What key bindings get to match a libinput device on, it has no physical path
+fn device_info(device: &input::Device) -> InputDevice {
+    InputDevice {
+        path: format!("/dev/input/{}", device.sysname()),
+        name: device.name().to_string(),
+        vendor: device.id_vendor() as u16,
+        product: device.id_product() as u16,
+        phys: String::new(),
+    }
+}
+
+fn watch_events(
+    input: Libinput,
+    queue: &KeySender,
+    recorder: &MacroRecorder,
+    codes: &ButtonCodes,
+    chords: &mut ChordTracker,
+) {
     loop {
         let mut borrowed_input: Libinput = input.clone();
         match borrowed_input.dispatch() {
             Ok(_) => {
                 for event in borrowed_input {
                     if let Event::Keyboard(KeyboardEvent::Key(event)) = event {
-                        if event.key_state() == input::event::keyboard::KeyState::Pressed {
-                            let prog_key = ProgrammableKeys::from_u32(event.key());
-                            match prog_key {
-                                ProgrammableKeys::MACROUNKNOWN => {}
-                                _ => match queue.lock() {
-                                    Ok(mut borrowed_queue) => {
-                                        borrowed_queue.push(prog_key);
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Error locking queue: {:?}", e);
-                                    }
-                                },
+                        let state = match event.key_state() {
+                            input::event::keyboard::KeyState::Pressed => KeyState::Pressed,
+                            input::event::keyboard::KeyState::Released => KeyState::Released,
+                        };
+
+                        let device = device_info(&event.device());
+                        let prog_key = codes
+                            .button_for(event.key(), &device)
+                            .or_else(|| chords.on_key(event.key(), state, &device));
+
+                        match prog_key {
+                            // any other key only matters if a macro is being recorded
+                            None => {
+                                recorder.record(linux_keycodes::key_from_code(event.key()), state)
+                            }
+                            Some(prog_key) => {
+                                queue.send(KeyEvent::new(prog_key, state, Some(device.name)));
                             }
                         }
                     }
@@ -59,18 +101,29 @@ fn watch_events(input: Libinput, queue: &Arc<Mutex<Vec<ProgrammableKeys>>>) {
                 eprintln!("Failed to dispatch libinput: {}", err);
             }
         }
-        // sleep here so it doesn't eat up all the CPU
-        thread::sleep(LIBINPUT_FETCH_DELAY);
+        // wait here so it doesn't eat up all the CPU
+        wait_for_events(&input);
     }
 }
 
-pub fn linux_start(queue: &Arc<Mutex<Vec<ProgrammableKeys>>>) {
+pub fn linux_start(
+    queue: &KeySender,
+    recorder: &MacroRecorder,
+    codes: &ButtonCodes,
+    keymap: Arc<Mutex<Keymap>>,
+) {
     let mut input = Libinput::new_with_udev(Interface);
     println!("Created input device!");
 
     match input.udev_assign_seat("seat0") {
         Ok(_) => {
-            watch_events(input, &queue);
+            watch_events(
+                input,
+                queue,
+                recorder,
+                codes,
+                &mut ChordTracker::new(keymap),
+            );
         }
         Err(_) => println!("Failed to assign seat"),
     }
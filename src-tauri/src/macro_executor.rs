@@ -0,0 +1,363 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::events::EventSink;
+use crate::keymap::{MacroKey, RunPolicy, Trigger};
+use crate::output::Outputs;
+use crate::programmable_keys::{handle_macro_key, ProgrammableKeys};
+
+/// Cancellation flag for a running macro job, waking it up early if it is in a delay
+#[derive(Default)]
+pub struct CancelToken {
+    cancelled: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        if let Ok(mut cancelled) = self.cancelled.lock() {
+            *cancelled = true;
+        }
+        self.condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        match self.cancelled.lock() {
+            Ok(cancelled) => *cancelled,
+            Err(_) => true,
+        }
+    }
+
+    /// Sleeps for the given duration, returning early if the job is cancelled
+    pub fn sleep(&self, duration: Duration) {
+        let cancelled = match self.cancelled.lock() {
+            Ok(cancelled) => cancelled,
+            Err(_) => return,
+        };
+
+        let _ = self
+            .condvar
+            .wait_timeout_while(cancelled, duration, |cancelled| !*cancelled);
+    }
+}
+
+/// Jobs are tracked per button and per trigger, so release actions don't
+/// wait behind or stop the press actions
+type JobKey = (ProgrammableKeys, Trigger);
+
+/// Jobs belonging to a single button trigger
+#[derive(Default)]
+struct ButtonJobs {
+    running: Vec<Arc<CancelToken>>,
+    queued: VecDeque<MacroKey>,
+}
+
+/// Runs every triggered macro as its own cancellable job, so a long running
+/// macro never blocks the other buttons.
+#[derive(Clone)]
+pub struct MacroExecutor {
+    jobs: Arc<Mutex<HashMap<JobKey, ButtonJobs>>>,
+    outputs: Outputs,
+    events: EventSink,
+}
+
+impl MacroExecutor {
+    pub fn new(outputs: Outputs, events: EventSink) -> MacroExecutor {
+        MacroExecutor {
+            jobs: Arc::default(),
+            outputs,
+            events,
+        }
+    }
+
+    /// Starts a macro, following its button's run policy if it is already running
+    pub fn trigger(&self, macro_key: MacroKey, trigger: Trigger) {
+        let mut jobs = match self.jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(err) => {
+                eprintln!("Error retrieving macro jobs lock: {}", err);
+                return;
+            }
+        };
+
+        let button = jobs
+            .entry((macro_key.programmable_key.clone(), trigger.clone()))
+            .or_default();
+
+        if !button.running.is_empty() {
+            // looping macros are always stopped by pressing their button again
+            if macro_key.macro_type.loops_until_stopped() {
+                Self::cancel_button(button);
+                return;
+            }
+
+            match macro_key.run_policy {
+                RunPolicy::Queue => {
+                    button.queued.push_back(macro_key);
+                    return;
+                }
+                RunPolicy::Ignore => return,
+                RunPolicy::Restart => Self::cancel_button(button),
+                RunPolicy::Parallel => {}
+            }
+        }
+
+        let token = Arc::new(CancelToken::default());
+        button.running.push(token.clone());
+        drop(jobs);
+
+        self.spawn_job(macro_key, trigger, token);
+    }
+
+    /// Cancels every running macro and drops anything still queued
+    pub fn stop_all(&self) {
+        match self.jobs.lock() {
+            Ok(mut jobs) => {
+                for button in jobs.values_mut() {
+                    Self::cancel_button(button);
+                }
+            }
+            Err(err) => eprintln!("Error retrieving macro jobs lock: {}", err),
+        }
+    }
+
+    /// Stops every macro and removes the virtual input device, before the app exits
+    pub fn shutdown(&self) {
+        self.stop_all();
+        self.outputs.shutdown();
+    }
+
+    fn cancel_button(button: &mut ButtonJobs) {
+        button.queued.clear();
+        for token in button.running.drain(..) {
+            token.cancel();
+        }
+    }
+
+    fn spawn_job(&self, macro_key: MacroKey, trigger: Trigger, token: Arc<CancelToken>) {
+        let jobs = self.jobs.clone();
+        let outputs = self.outputs.clone();
+        let events = self.events.clone();
+
+        thread::spawn(move || {
+            let key = (macro_key.programmable_key.clone(), trigger);
+
+            match outputs.backend() {
+                Ok(mut output) => {
+                    let mut next = Some(macro_key);
+
+                    while let Some(macro_key) = next {
+                        events.emit(
+                            "macro-started",
+                            json!({ "button": key.0, "trigger": key.1 }),
+                        );
+
+                        // a macro that can't send its input drops whatever was queued behind it
+                        let result = handle_macro_key(&macro_key, output.as_mut(), &token);
+                        if let Err(err) = &result {
+                            eprintln!("Stopped macro for {:?}: {}", key.0, err);
+                            token.cancel();
+                        }
+
+                        events.emit(
+                            "macro-finished",
+                            json!({
+                                "button": key.0,
+                                "trigger": key.1,
+                                "cancelled": token.is_cancelled(),
+                                "error": result.err().map(|err| err.to_string()),
+                            }),
+                        );
+                        next = Self::next_queued(&jobs, &key, &token);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Failed to create input simulator: {}", err);
+                    token.cancel();
+                    Self::next_queued(&jobs, &key, &token);
+                }
+            }
+        });
+    }
+
+    /// Takes the next queued press for a button, or retires the job if there is none
+    /// or it was cancelled
+    fn next_queued(
+        jobs: &Arc<Mutex<HashMap<JobKey, ButtonJobs>>>,
+        key: &JobKey,
+        token: &Arc<CancelToken>,
+    ) -> Option<MacroKey> {
+        let mut jobs = match jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(err) => {
+                eprintln!("Error retrieving macro jobs lock: {}", err);
+                return None;
+            }
+        };
+
+        let button = jobs.get_mut(key)?;
+
+        // presses queued behind a cancelled or failed run are dropped with it,
+        // instead of waiting for the next press to run them
+        if token.is_cancelled() {
+            button.queued.clear();
+        } else if let Some(next) = button.queued.pop_front() {
+            return Some(next);
+        }
+
+        button
+            .running
+            .retain(|running| !Arc::ptr_eq(running, token));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+    use std::time::Instant;
+
+    use serde_json::Value;
+
+    use crate::keymap::{MacroAction, MacroType};
+
+    /// Long enough that a run only ends when something stops it
+    const FOREVER_MS: u64 = 60_000;
+
+    fn button() -> ProgrammableKeys {
+        ProgrammableKeys::Button("MACRO1".to_string())
+    }
+
+    fn macro_key(macro_type: MacroType, run_policy: RunPolicy, delay_ms: u64) -> MacroKey {
+        let mut macro_key = MacroKey::new(button());
+        macro_key.macro_type = macro_type;
+        macro_key.run_policy = run_policy;
+        macro_key.actions = vec![MacroAction::Delay(delay_ms)];
+        macro_key
+    }
+
+    fn executor() -> (MacroExecutor, Receiver<String>) {
+        let events = EventSink::new();
+        let receiver = events.subscribe();
+        (MacroExecutor::new(Outputs::recording(), events), receiver)
+    }
+
+    /// The next job event, `started` or whether a finished run was cancelled
+    fn next_event(events: &Receiver<String>) -> Result<Option<bool>, RecvTimeoutError> {
+        let line = events.recv_timeout(Duration::from_secs(5))?;
+        let event: Value = serde_json::from_str(&line).unwrap();
+
+        Ok(match event["event"].as_str() {
+            Some("macro-started") => None,
+            _ => Some(event["payload"]["cancelled"].as_bool().unwrap()),
+        })
+    }
+
+    fn assert_no_more_events(events: &Receiver<String>) {
+        assert_eq!(
+            events.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Timeout)
+        );
+    }
+
+    /// (running, queued) jobs for the test button's press trigger
+    fn jobs(executor: &MacroExecutor) -> (usize, usize) {
+        let jobs = executor.jobs.lock().unwrap();
+        jobs.get(&(button(), Trigger::Press))
+            .map_or((0, 0), |button| (button.running.len(), button.queued.len()))
+    }
+
+    #[test]
+    fn pressing_again_stops_a_loop() {
+        let (executor, events) = executor();
+        let looping = macro_key(MacroType::Toggle, RunPolicy::Parallel, 5);
+
+        executor.trigger(looping.clone(), Trigger::Press);
+        assert_eq!(next_event(&events), Ok(None));
+
+        executor.trigger(looping, Trigger::Press);
+        assert_eq!(next_event(&events), Ok(Some(true)));
+        assert_no_more_events(&events);
+        assert_eq!(jobs(&executor), (0, 0));
+    }
+
+    #[test]
+    fn restart_cancels_the_run_and_starts_over() {
+        let (executor, events) = executor();
+        let restarting = macro_key(MacroType::Once, RunPolicy::Restart, FOREVER_MS);
+
+        executor.trigger(restarting.clone(), Trigger::Press);
+        assert_eq!(next_event(&events), Ok(None));
+
+        executor.trigger(restarting, Trigger::Press);
+        let mut restarted = vec![next_event(&events).unwrap(), next_event(&events).unwrap()];
+        restarted.sort();
+        assert_eq!(restarted, vec![None, Some(true)]);
+        assert_eq!(jobs(&executor), (1, 0));
+
+        executor.stop_all();
+        assert_eq!(next_event(&events), Ok(Some(true)));
+        assert_no_more_events(&events);
+    }
+
+    #[test]
+    fn queue_runs_each_press_in_turn() {
+        let (executor, events) = executor();
+        let queued = macro_key(MacroType::Once, RunPolicy::Queue, 50);
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            executor.trigger(queued.clone(), Trigger::Press);
+        }
+
+        for _ in 0..3 {
+            assert_eq!(next_event(&events), Ok(None));
+            assert_eq!(next_event(&events), Ok(Some(false)));
+        }
+        assert_no_more_events(&events);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(jobs(&executor), (0, 0));
+    }
+
+    #[test]
+    fn cancelled_run_drops_the_presses_queued_behind_it() {
+        let (executor, events) = executor();
+        let queued = macro_key(MacroType::Once, RunPolicy::Queue, FOREVER_MS);
+
+        for _ in 0..3 {
+            executor.trigger(queued.clone(), Trigger::Press);
+        }
+        assert_eq!(next_event(&events), Ok(None));
+        assert_eq!(jobs(&executor), (1, 2));
+
+        // cancelled without `stop_all`, like a run whose output failed
+        let token = executor.jobs.lock().unwrap()[&(button(), Trigger::Press)].running[0].clone();
+        token.cancel();
+
+        assert_eq!(next_event(&events), Ok(Some(true)));
+        assert_no_more_events(&events);
+        assert_eq!(jobs(&executor), (0, 0));
+    }
+
+    #[test]
+    fn ignore_leaves_the_running_macro_alone() {
+        let (executor, events) = executor();
+        let ignoring = macro_key(MacroType::Once, RunPolicy::Ignore, FOREVER_MS);
+
+        executor.trigger(ignoring.clone(), Trigger::Press);
+        assert_eq!(next_event(&events), Ok(None));
+
+        executor.trigger(ignoring, Trigger::Press);
+        assert_no_more_events(&events);
+        assert_eq!(jobs(&executor), (1, 0));
+
+        executor.stop_all();
+        assert_eq!(next_event(&events), Ok(Some(true)));
+        assert_no_more_events(&events);
+    }
+}
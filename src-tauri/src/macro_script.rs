@@ -0,0 +1,853 @@
+//! A short text form for macro action lists, so a button can be written as
+//! `ctrl+c; wait 50ms; type "hello"` instead of a list of json actions.
+//!
+//! Statements are separated by `;` or new lines, except around `{...}`, and `#` starts a comment:
+//!
+//! - `a`, `enter`, `ctrl+shift+t` taps a key, holding any keys before the last one
+//! - `{shift down}` and `{shift up}` press and release a key
+//! - `type "text"` types out text, with `\"`, `\\`, `\n` and `\t` escapes
+//! - `wait 50ms` or `wait 2s` pauses, a bare number is in milliseconds
+//! - `layer hold nav`, `layer toggle nav` and `layer oneshot nav` switch layers
+//! - `move 100 200` puts the mouse pointer somewhere, `move by 10 -5` nudges it
+//! - `click left`, `mousedown right` and `mouseup middle` use the mouse buttons,
+//!   `back` and `forward` are the side buttons
+//! - `scroll down 3`, `scroll up 3`, `scroll left 2` and `scroll right 2` scroll
+//! - `none` does nothing
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::keymap::{Key, MacroAction, MouseButton};
+use crate::keymap_formats::line_and_column;
+
+/// Names keys go by in scripts, the first name listed for a key is the one scripts are written with
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("ctrl", Key::ControlLeft),
+    ("control", Key::ControlLeft),
+    ("lctrl", Key::ControlLeft),
+    ("rctrl", Key::ControlRight),
+    ("shift", Key::ShiftLeft),
+    ("lshift", Key::ShiftLeft),
+    ("rshift", Key::ShiftRight),
+    ("alt", Key::Alt),
+    ("meta", Key::MetaLeft),
+    ("super", Key::MetaLeft),
+    ("win", Key::MetaLeft),
+    ("cmd", Key::MetaLeft),
+    ("lmeta", Key::MetaLeft),
+    ("rmeta", Key::MetaRight),
+    ("enter", Key::Return),
+    ("return", Key::Return),
+    ("esc", Key::Escape),
+    ("escape", Key::Escape),
+    ("space", Key::Space),
+    ("tab", Key::Tab),
+    ("backspace", Key::Backspace),
+    ("delete", Key::Delete),
+    ("del", Key::Delete),
+    ("insert", Key::Insert),
+    ("ins", Key::Insert),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pgup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("pgdn", Key::PageDown),
+    ("up", Key::UpArrow),
+    ("down", Key::DownArrow),
+    ("left", Key::LeftArrow),
+    ("right", Key::RightArrow),
+    ("capslock", Key::CapsLock),
+    ("numlock", Key::NumLock),
+    ("printscreen", Key::PrintScreen),
+    ("prtsc", Key::PrintScreen),
+    ("pause", Key::Pause),
+    ("f1", Key::F1),
+    ("f2", Key::F2),
+    ("f3", Key::F3),
+    ("f4", Key::F4),
+    ("f5", Key::F5),
+    ("f6", Key::F6),
+    ("f7", Key::F7),
+    ("f8", Key::F8),
+    ("f9", Key::F9),
+    ("f10", Key::F10),
+    ("f11", Key::F11),
+    ("f12", Key::F12),
+    ("a", Key::KeyA),
+    ("b", Key::KeyB),
+    ("c", Key::KeyC),
+    ("d", Key::KeyD),
+    ("e", Key::KeyE),
+    ("f", Key::KeyF),
+    ("g", Key::KeyG),
+    ("h", Key::KeyH),
+    ("i", Key::KeyI),
+    ("j", Key::KeyJ),
+    ("k", Key::KeyK),
+    ("l", Key::KeyL),
+    ("m", Key::KeyM),
+    ("n", Key::KeyN),
+    ("o", Key::KeyO),
+    ("p", Key::KeyP),
+    ("q", Key::KeyQ),
+    ("r", Key::KeyR),
+    ("s", Key::KeyS),
+    ("t", Key::KeyT),
+    ("u", Key::KeyU),
+    ("v", Key::KeyV),
+    ("w", Key::KeyW),
+    ("x", Key::KeyX),
+    ("y", Key::KeyY),
+    ("z", Key::KeyZ),
+    ("1", Key::Num1),
+    ("2", Key::Num2),
+    ("3", Key::Num3),
+    ("4", Key::Num4),
+    ("5", Key::Num5),
+    ("6", Key::Num6),
+    ("7", Key::Num7),
+    ("8", Key::Num8),
+    ("9", Key::Num9),
+    ("0", Key::Num0),
+    ("minus", Key::Minus),
+    ("-", Key::Minus),
+    ("equal", Key::Equal),
+    ("=", Key::Equal),
+    ("[", Key::LeftBracket),
+    ("leftbracket", Key::LeftBracket),
+    ("]", Key::RightBracket),
+    ("rightbracket", Key::RightBracket),
+    ("semicolon", Key::SemiColon),
+    ("'", Key::Quote),
+    ("quote", Key::Quote),
+    ("`", Key::BackQuote),
+    ("backquote", Key::BackQuote),
+    ("\\", Key::BackSlash),
+    ("backslash", Key::BackSlash),
+    ("intlbackslash", Key::IntlBackslash),
+    (",", Key::Comma),
+    ("comma", Key::Comma),
+    (".", Key::Dot),
+    ("dot", Key::Dot),
+    ("period", Key::Dot),
+    ("/", Key::Slash),
+    ("slash", Key::Slash),
+    ("kpplus", Key::KpPlus),
+    ("kpmultiply", Key::KpMultiply),
+];
+
+/// keys without a name of their own are written as their raw code, `unknown:<code>`
+const UNKNOWN_KEY_PREFIX: &str = "unknown:";
+
+/// Where in a script something went wrong, as byte offsets and a one based line and column
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ScriptError {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Plus,
+    Separator,
+    OpenBrace,
+    CloseBrace,
+}
+
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    start: usize,
+    end: usize,
+}
+
+struct Parser<'a> {
+    script: &'a str,
+    tokens: Vec<Spanned>,
+    position: usize,
+}
+
+/// Compiles a script into the actions it stands for
+pub fn parse(script: &str) -> Result<Vec<MacroAction>, ScriptError> {
+    let mut parser = Parser {
+        script,
+        tokens: tokenize(script)?,
+        position: 0,
+    };
+
+    let mut actions = Vec::new();
+    while parser.position < parser.tokens.len() {
+        if parser.peek() == Some(&Token::Separator) {
+            parser.position += 1;
+            continue;
+        }
+
+        parser.statement(&mut actions)?;
+
+        // braces already mark where `{shift down}` starts and ends, so no separator is needed around it
+        if parser.tokens[parser.position - 1].token == Token::CloseBrace
+            || parser.peek() == Some(&Token::OpenBrace)
+        {
+            continue;
+        }
+
+        match parser.next() {
+            None => break,
+            Some(Spanned {
+                token: Token::Separator,
+                ..
+            }) => {}
+            Some(unexpected) => {
+                return Err(parser.error_at(
+                    &unexpected,
+                    format!(
+                        "expected `;` or a new line, found {}",
+                        describe(&unexpected.token)
+                    ),
+                ))
+            }
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Writes actions out as a script, `parse` turns the result back into the same actions
+pub fn print(actions: &[MacroAction]) -> String {
+    let mut statements: Vec<String> = Vec::new();
+    let mut index = 0;
+
+    while index < actions.len() {
+        if let Some((chord, length)) = chord_at(&actions[index..]) {
+            statements.push(chord);
+            index += length;
+            continue;
+        }
+
+        statements.push(match &actions[index] {
+            MacroAction::Print(text) => format!("type {}", quote(text)),
+            MacroAction::Tap(key) => key_name(key),
+            MacroAction::Press(key) => format!("{{{} down}}", key_name(key)),
+            MacroAction::Release(key) => format!("{{{} up}}", key_name(key)),
+            MacroAction::Delay(delay_ms) if *delay_ms > 0 && delay_ms % 1000 == 0 => {
+                format!("wait {}s", delay_ms / 1000)
+            }
+            MacroAction::Delay(delay_ms) => format!("wait {}ms", delay_ms),
+            MacroAction::None => "none".to_string(),
+            MacroAction::LayerMomentary(layer) => format!("layer hold {}", layer_name(layer)),
+            MacroAction::LayerToggle(layer) => format!("layer toggle {}", layer_name(layer)),
+            MacroAction::LayerOneShot(layer) => format!("layer oneshot {}", layer_name(layer)),
+            MacroAction::MouseMoveTo { x, y } => format!("move {} {}", x, y),
+            MacroAction::MouseMoveBy { x, y } => format!("move by {} {}", x, y),
+            MacroAction::MouseClick(button) => format!("click {}", button_name(button)),
+            MacroAction::MousePress(button) => format!("mousedown {}", button_name(button)),
+            MacroAction::MouseRelease(button) => format!("mouseup {}", button_name(button)),
+            MacroAction::ScrollVertical(steps) if *steps < 0 => {
+                format!("scroll up {}", steps.unsigned_abs())
+            }
+            MacroAction::ScrollVertical(steps) => format!("scroll down {}", steps),
+            MacroAction::ScrollHorizontal(steps) if *steps < 0 => {
+                format!("scroll left {}", steps.unsigned_abs())
+            }
+            MacroAction::ScrollHorizontal(steps) => format!("scroll right {}", steps),
+        });
+        index += 1;
+    }
+
+    statements.join("; ")
+}
+
+/// Spots presses, a tap and the matching releases in reverse, which read back as a chord like `ctrl+c`
+fn chord_at(actions: &[MacroAction]) -> Option<(String, usize)> {
+    let held: Vec<&Key> = actions
+        .iter()
+        .map_while(|action| match action {
+            MacroAction::Press(key) => Some(key),
+            _ => None,
+        })
+        .collect();
+
+    let tapped = match actions.get(held.len()) {
+        Some(MacroAction::Tap(key)) if !held.is_empty() => key,
+        _ => return None,
+    };
+
+    let releases = &actions[held.len() + 1..];
+    let releases_match = releases.len() >= held.len()
+        && held
+            .iter()
+            .rev()
+            .zip(releases)
+            .all(|(key, release)| *release == MacroAction::Release((*key).clone()));
+
+    if !releases_match {
+        return None;
+    }
+
+    let mut names: Vec<String> = held.iter().map(|key| key_name(key)).collect();
+    names.push(key_name(tapped));
+    Some((names.join("+"), held.len() * 2 + 1))
+}
+
+fn key_name(key: &Key) -> String {
+    match KEY_NAMES.iter().find(|(_, named)| named == key) {
+        Some((name, _)) => name.to_string(),
+        None => match key {
+            Key::Unknown(code) => format!("{}{}", UNKNOWN_KEY_PREFIX, code),
+            key => format!("{:?}", key),
+        },
+    }
+}
+
+const BUTTON_NAMES: &[(&str, MouseButton)] = &[
+    ("left", MouseButton::Left),
+    ("right", MouseButton::Right),
+    ("middle", MouseButton::Middle),
+    ("back", MouseButton::Back),
+    ("forward", MouseButton::Forward),
+];
+
+fn button_name(button: &MouseButton) -> &'static str {
+    BUTTON_NAMES
+        .iter()
+        .find(|(_, named)| named == button)
+        .map_or("left", |(name, _)| name)
+}
+
+fn layer_name(layer: &str) -> String {
+    if !layer.is_empty() && layer.chars().all(is_word_char) {
+        layer.to_string()
+    } else {
+        quote(layer)
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == ':'
+}
+
+/// punctuation keys that make up a word on their own
+fn is_symbol_key(c: char) -> bool {
+    matches!(
+        c,
+        '-' | '=' | '[' | ']' | '\'' | '`' | '\\' | ',' | '.' | '/'
+    )
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("`{}`", word),
+        Token::Text(_) => "text".to_string(),
+        Token::Plus => "`+`".to_string(),
+        Token::Separator => "`;`".to_string(),
+        Token::OpenBrace => "`{`".to_string(),
+        Token::CloseBrace => "`}`".to_string(),
+    }
+}
+
+fn error(script: &str, start: usize, end: usize, message: String) -> ScriptError {
+    let (line, column) = line_and_column(script, start);
+    ScriptError {
+        message,
+        start,
+        end,
+        line,
+        column,
+    }
+}
+
+fn tokenize(script: &str) -> Result<Vec<Spanned>, ScriptError> {
+    let mut tokens = Vec::new();
+    let mut chars = script.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            '\n' | ';' => Token::Separator,
+            c if c.is_whitespace() => continue,
+            '#' => {
+                // comments run to the end of the line, which still ends the statement
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                continue;
+            }
+            '+' => Token::Plus,
+            '{' => Token::OpenBrace,
+            '}' => Token::CloseBrace,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => {
+                            return Err(error(
+                                script,
+                                start,
+                                script.len(),
+                                "text is missing its closing `\"`".to_string(),
+                            ))
+                        }
+                        Some((_, '"')) => break,
+                        Some((escape_start, '\\')) => match chars.next() {
+                            Some((_, '"')) => text.push('"'),
+                            Some((_, '\\')) => text.push('\\'),
+                            Some((_, 'n')) => text.push('\n'),
+                            Some((_, 't')) => text.push('\t'),
+                            Some((escape_end, other)) => {
+                                return Err(error(
+                                    script,
+                                    escape_start,
+                                    escape_end + other.len_utf8(),
+                                    format!("unknown escape `\\{}`", other),
+                                ))
+                            }
+                            None => continue,
+                        },
+                        Some((_, c)) => text.push(c),
+                    }
+                }
+                Token::Text(text)
+            }
+            // a minus right before a digit starts a negative number rather than being the key
+            '-' if chars.peek().is_some_and(|(_, next)| next.is_ascii_digit()) => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c if is_symbol_key(c) => Token::Word(c.to_string()),
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => {
+                return Err(error(
+                    script,
+                    start,
+                    start + c.len_utf8(),
+                    format!("unexpected `{}`", c),
+                ))
+            }
+        };
+
+        let end = chars.peek().map_or(script.len(), |(end, _)| *end);
+        tokens.push(Spanned { token, start, end });
+    }
+
+    Ok(tokens)
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|spanned| &spanned.token)
+    }
+
+    fn next(&mut self) -> Option<Spanned> {
+        let spanned = self.tokens.get(self.position).cloned();
+        if spanned.is_some() {
+            self.position += 1;
+        }
+        spanned
+    }
+
+    fn error_at(&self, spanned: &Spanned, message: String) -> ScriptError {
+        error(self.script, spanned.start, spanned.end, message)
+    }
+
+    /// The next token, or an error pointing just past the last one when the script ends early
+    fn expect(&mut self, expected: &str) -> Result<Spanned, ScriptError> {
+        match self.next() {
+            Some(spanned) if spanned.token != Token::Separator => Ok(spanned),
+            Some(separator) => Err(self.error_at(
+                &separator,
+                format!("expected {}, found the end of the statement", expected),
+            )),
+            None => Err(error(
+                self.script,
+                self.script.len(),
+                self.script.len(),
+                format!("expected {}, found the end of the script", expected),
+            )),
+        }
+    }
+
+    fn expect_word(&mut self, expected: &str) -> Result<(String, Spanned), ScriptError> {
+        let spanned = self.expect(expected)?;
+        match &spanned.token {
+            Token::Word(word) => Ok((word.clone(), spanned)),
+            token => Err(self.error_at(
+                &spanned,
+                format!("expected {}, found {}", expected, describe(token)),
+            )),
+        }
+    }
+
+    fn statement(&mut self, actions: &mut Vec<MacroAction>) -> Result<(), ScriptError> {
+        let first = self.expect("a statement")?;
+
+        let word = match &first.token {
+            Token::OpenBrace => {
+                actions.push(self.key_change()?);
+                return Ok(());
+            }
+            Token::Word(word) => word.to_lowercase(),
+            token => {
+                return Err(self.error_at(
+                    &first,
+                    format!("expected a key or a statement, found {}", describe(token)),
+                ))
+            }
+        };
+
+        match word.as_str() {
+            "type" | "print" => {
+                let text = self.expect("text in quotes")?;
+                match text.token {
+                    Token::Text(text) => actions.push(MacroAction::Print(text)),
+                    ref token => {
+                        return Err(self.error_at(
+                            &text,
+                            format!("expected text in quotes, found {}", describe(token)),
+                        ))
+                    }
+                }
+            }
+            "wait" | "delay" | "sleep" => {
+                let (duration, spanned) = self.expect_word("a duration like `50ms`")?;
+                let delay_ms = parse_duration(&duration).ok_or_else(|| {
+                    self.error_at(&spanned, format!("invalid duration `{}`", duration))
+                })?;
+                actions.push(MacroAction::Delay(delay_ms));
+            }
+            "layer" => {
+                let (kind, kind_span) = self.expect_word("`hold`, `toggle` or `oneshot`")?;
+                let layer = self.expect("a layer name")?;
+                let layer = match layer.token {
+                    Token::Word(name) | Token::Text(name) => name,
+                    ref token => {
+                        return Err(self.error_at(
+                            &layer,
+                            format!("expected a layer name, found {}", describe(token)),
+                        ))
+                    }
+                };
+
+                actions.push(match kind.to_lowercase().as_str() {
+                    "hold" | "momentary" => MacroAction::LayerMomentary(layer),
+                    "toggle" => MacroAction::LayerToggle(layer),
+                    "oneshot" | "once" => MacroAction::LayerOneShot(layer),
+                    _ => {
+                        return Err(self.error_at(
+                            &kind_span,
+                            format!("expected `hold`, `toggle` or `oneshot`, found `{}`", kind),
+                        ))
+                    }
+                });
+            }
+            "move" => {
+                let relative = self.peek() == Some(&Token::Word("by".to_string()));
+                if relative {
+                    self.position += 1;
+                }
+
+                let x = self.number("an x position")?;
+                let y = self.number("a y position")?;
+                actions.push(if relative {
+                    MacroAction::MouseMoveBy { x, y }
+                } else {
+                    MacroAction::MouseMoveTo { x, y }
+                });
+            }
+            "click" => actions.push(MacroAction::MouseClick(self.mouse_button()?)),
+            "mousedown" => actions.push(MacroAction::MousePress(self.mouse_button()?)),
+            "mouseup" => actions.push(MacroAction::MouseRelease(self.mouse_button()?)),
+            "scroll" => {
+                let expected = "`up`, `down`, `left` or `right`";
+                let (direction, direction_span) = self.expect_word(expected)?;
+                let (steps, steps_span) = self.expect_word("a number of steps")?;
+
+                // up and left are negative, which reaches one step further than positive
+                let (vertical, sign) = match direction.to_lowercase().as_str() {
+                    "down" => (true, 1),
+                    "up" => (true, -1),
+                    "right" => (false, 1),
+                    "left" => (false, -1),
+                    _ => {
+                        return Err(self.error_at(
+                            &direction_span,
+                            format!("expected {}, found `{}`", expected, direction),
+                        ))
+                    }
+                };
+
+                let steps = steps
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|steps| i32::try_from(steps * sign).ok())
+                    .ok_or_else(|| {
+                        self.error_at(&steps_span, format!("invalid number of steps `{}`", steps))
+                    })?;
+
+                actions.push(if vertical {
+                    MacroAction::ScrollVertical(steps)
+                } else {
+                    MacroAction::ScrollHorizontal(steps)
+                });
+            }
+            "none" => actions.push(MacroAction::None),
+            _ => {
+                self.position -= 1;
+                self.chord(actions)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `{key down}` or `{key up}`
+    fn key_change(&mut self) -> Result<MacroAction, ScriptError> {
+        let key = self.key()?;
+        let (change, spanned) = self.expect_word("`down` or `up`")?;
+
+        let action = match change.to_lowercase().as_str() {
+            "down" | "press" => MacroAction::Press(key),
+            "up" | "release" => MacroAction::Release(key),
+            _ => {
+                return Err(self.error_at(
+                    &spanned,
+                    format!("expected `down` or `up`, found `{}`", change),
+                ))
+            }
+        };
+
+        let close = self.expect("`}`")?;
+        if close.token != Token::CloseBrace {
+            return Err(self.error_at(
+                &close,
+                format!("expected `}}`, found {}", describe(&close.token)),
+            ));
+        }
+
+        Ok(action)
+    }
+
+    /// One key tapped, with any keys joined on before it held down around the tap
+    fn chord(&mut self, actions: &mut Vec<MacroAction>) -> Result<(), ScriptError> {
+        let mut keys = vec![self.key()?];
+        while self.peek() == Some(&Token::Plus) {
+            self.position += 1;
+            keys.push(self.key()?);
+        }
+
+        let tapped = keys.pop().expect("a chord has at least one key");
+        actions.extend(keys.iter().cloned().map(MacroAction::Press));
+        actions.push(MacroAction::Tap(tapped));
+        actions.extend(keys.into_iter().rev().map(MacroAction::Release));
+        Ok(())
+    }
+
+    fn number(&mut self, expected: &str) -> Result<i32, ScriptError> {
+        let (word, spanned) = self.expect_word(expected)?;
+        word.parse().map_err(|_| {
+            self.error_at(&spanned, format!("expected {}, found `{}`", expected, word))
+        })
+    }
+
+    fn mouse_button(&mut self) -> Result<MouseButton, ScriptError> {
+        let (word, spanned) = self.expect_word("a mouse button")?;
+        let lowercase = word.to_lowercase();
+
+        BUTTON_NAMES
+            .iter()
+            .find(|(name, _)| *name == lowercase)
+            .map(|(_, button)| *button)
+            .ok_or_else(|| self.error_at(&spanned, format!("unknown mouse button `{}`", word)))
+    }
+
+    fn key(&mut self) -> Result<Key, ScriptError> {
+        let (word, spanned) = self.expect_word("a key")?;
+        parse_key(&word).ok_or_else(|| self.error_at(&spanned, format!("unknown key `{}`", word)))
+    }
+}
+
+/// Reads a key by its script name, its raw code, or the name it has in keymap files
+pub fn parse_key(word: &str) -> Option<Key> {
+    let lowercase = word.to_lowercase();
+
+    if let Some((_, key)) = KEY_NAMES.iter().find(|(name, _)| *name == lowercase) {
+        return Some(key.clone());
+    }
+
+    if let Some(code) = lowercase.strip_prefix(UNKNOWN_KEY_PREFIX) {
+        return code.parse().ok().map(Key::Unknown);
+    }
+
+    serde_json::from_value(serde_json::Value::String(word.to_string())).ok()
+}
+
+/// `50ms`, `2s` or a bare number of milliseconds
+fn parse_duration(duration: &str) -> Option<u64> {
+    if let Some(ms) = duration.strip_suffix("ms") {
+        ms.parse().ok()
+    } else if let Some(seconds) = duration.strip_suffix('s') {
+        seconds.parse::<u64>().ok()?.checked_mul(1000)
+    } else {
+        duration.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_action() -> Vec<MacroAction> {
+        vec![
+            MacroAction::Print("say \"hi\"\\\n\tthere".to_string()),
+            MacroAction::Tap(Key::KeyA),
+            MacroAction::Tap(Key::Unknown(183)),
+            MacroAction::Press(Key::ShiftLeft),
+            MacroAction::Release(Key::ShiftLeft),
+            MacroAction::Press(Key::ControlLeft),
+            MacroAction::Press(Key::Alt),
+            MacroAction::Tap(Key::Delete),
+            MacroAction::Release(Key::Alt),
+            MacroAction::Release(Key::ControlLeft),
+            MacroAction::Delay(50),
+            MacroAction::Delay(2000),
+            MacroAction::Delay(0),
+            MacroAction::None,
+            MacroAction::LayerMomentary("nav".to_string()),
+            MacroAction::LayerToggle("game mode".to_string()),
+            MacroAction::LayerOneShot("symbols".to_string()),
+            MacroAction::MouseMoveTo { x: 100, y: 200 },
+            MacroAction::MouseMoveBy { x: -10, y: 5 },
+            MacroAction::MouseClick(MouseButton::Left),
+            MacroAction::MousePress(MouseButton::Right),
+            MacroAction::MouseRelease(MouseButton::Middle),
+            MacroAction::MouseClick(MouseButton::Back),
+            MacroAction::MouseClick(MouseButton::Forward),
+            MacroAction::ScrollVertical(3),
+            MacroAction::ScrollVertical(i32::MIN),
+            MacroAction::ScrollHorizontal(2),
+            MacroAction::ScrollHorizontal(-2),
+        ]
+    }
+
+    #[test]
+    fn every_action_kind_is_covered() {
+        // fails to build when a new action is added, so it gets added to `every_action` too
+        for action in every_action() {
+            match action {
+                MacroAction::Print(_)
+                | MacroAction::Tap(_)
+                | MacroAction::Press(_)
+                | MacroAction::Release(_)
+                | MacroAction::Delay(_)
+                | MacroAction::None
+                | MacroAction::LayerMomentary(_)
+                | MacroAction::LayerToggle(_)
+                | MacroAction::LayerOneShot(_)
+                | MacroAction::MouseMoveTo { .. }
+                | MacroAction::MouseMoveBy { .. }
+                | MacroAction::MousePress(_)
+                | MacroAction::MouseRelease(_)
+                | MacroAction::MouseClick(_)
+                | MacroAction::ScrollVertical(_)
+                | MacroAction::ScrollHorizontal(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn parses_the_documented_example() {
+        assert_eq!(
+            parse("ctrl+c; wait 50ms; type \"hello\"; {shift down}"),
+            Ok(vec![
+                MacroAction::Press(Key::ControlLeft),
+                MacroAction::Tap(Key::KeyC),
+                MacroAction::Release(Key::ControlLeft),
+                MacroAction::Delay(50),
+                MacroAction::Print("hello".to_string()),
+                MacroAction::Press(Key::ShiftLeft),
+            ])
+        );
+    }
+
+    #[test]
+    fn printed_actions_parse_back_to_the_same_actions() {
+        let actions = every_action();
+        let script = print(&actions);
+        assert_eq!(parse(&script), Ok(actions), "script: {}", script);
+    }
+
+    #[test]
+    fn brace_statements_and_comments_need_no_separator() {
+        assert_eq!(
+            parse("{shift down}a{shift up} # done\nwait 1s"),
+            Ok(vec![
+                MacroAction::Press(Key::ShiftLeft),
+                MacroAction::Tap(Key::KeyA),
+                MacroAction::Release(Key::ShiftLeft),
+                MacroAction::Delay(1000),
+            ])
+        );
+    }
+
+    #[test]
+    fn unterminated_text_points_from_the_quote_to_the_end() {
+        let error = parse("a\ntype \"hello").unwrap_err();
+        assert_eq!((error.start, error.end), (7, 13));
+        assert_eq!((error.line, error.column), (2, 6));
+    }
+
+    #[test]
+    fn unknown_key_points_at_the_key() {
+        let error = parse("ctrl+nope").unwrap_err();
+        assert_eq!(error.message, "unknown key `nope`");
+        assert_eq!((error.start, error.end), (5, 9));
+        assert_eq!((error.line, error.column), (1, 6));
+    }
+
+    #[test]
+    fn bad_duration_points_at_the_duration() {
+        let error = parse("a; b\n  wait 5x").unwrap_err();
+        assert_eq!(error.message, "invalid duration `5x`");
+        assert_eq!((error.start, error.end), (12, 14));
+        assert_eq!((error.line, error.column), (2, 8));
+    }
+}
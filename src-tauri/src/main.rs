@@ -1,121 +1,190 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::{thread, time};
-use std::sync::{Arc, Mutex};
-
-use enigo::Settings;
-use tauri::{CustomMenuItem, SystemTrayMenu, SystemTrayMenuItem};
 use tauri::{Manager, SystemTray, SystemTrayEvent};
 
-use crate::keymap::Keymap;
-use crate::programmable_keys::ProgrammableKeys;
-use crate::tauri_commands::{add_button, save_keymap, send_keymap};
-
+use crate::macro_executor::MacroExecutor;
+use crate::profiles::Profiles;
+use crate::runtime::Runtime;
+use crate::settings::Settings;
+use crate::tauri_commands::{
+    activate_profile, add_button, cancel_recording, create_profile, delete_profile,
+    duplicate_profile, export_keymap, import_keymap, list_backups, list_input_devices,
+    list_profiles, parse_macro_script, preview_macro, print_macro_script, rename_profile,
+    restore_backup, save_keymap, send_active_layers, send_active_profile, send_keymap,
+    send_keymap_diagnostics, start_recording, stop_all_macros, stop_recording,
+};
+
+mod backups;
+mod bindings;
+mod button_codes;
+mod cli;
+mod control_socket;
+mod events;
+mod headless;
+mod input_devices;
+mod key_handler;
+mod key_queue;
 mod keymap;
+mod keymap_formats;
+mod keymap_watcher;
+mod layers;
+mod linux_keycodes;
+mod macro_executor;
+mod macro_script;
+mod migrations;
+mod output;
+mod profiles;
 mod programmable_keys;
+mod recorder;
+mod runtime;
+mod settings;
+mod tap_dance;
 mod tauri_commands;
-
-const QUEUE_CHECKING_DELAY: time::Duration = time::Duration::from_millis(20);
+#[cfg(test)]
+mod test_support;
+mod tray;
+mod validation;
+mod window_rules;
 
+#[cfg(target_os = "linux")]
+mod evdev_listener;
+#[cfg(target_os = "linux")]
+mod focus_watcher;
 #[cfg(target_os = "linux")]
 mod linux_listener;
+#[cfg(target_os = "linux")]
+mod uinput;
 
 #[cfg(target_os = "windows")]
 mod windows_listener;
 
 fn main() {
-    // Create dummy keymap
-    let keymap: Keymap = Keymap::load_from_file("keymap".parse().unwrap()).unwrap();
-
-    let keymap_arc: Arc<Mutex<Keymap>> = Arc::new(Mutex::new(keymap.clone()));
-
-    // Handle keyboard presses
-    let programmable_keys_vec: Vec<ProgrammableKeys> = Vec::new();
-    let programmable_keys_arc = Arc::new(Mutex::new(programmable_keys_vec));
-
-    let queue = programmable_keys_arc.clone();
-    let keymap_clone = keymap_arc.clone();
-    thread::spawn(move || {
-        println!("started handler thread");
-
-        loop {
-            thread::sleep(QUEUE_CHECKING_DELAY);
-
-            let retrieved_key = match queue.lock() {
-                Ok(mut borrowed_queue) => borrowed_queue.pop(),
-                Err(e) => {
-                    eprintln!("Error locking queue: {:?}", e);
-                    None
-                }
-            };
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-            match retrieved_key {
-                Some(key) => {
-                    eprintln!("Handling a keypress");
-                    let simulator = enigo::Enigo::new(&Settings::default()).unwrap();
-                    ProgrammableKeys::process_keys(key, &keymap_clone, simulator);
-                }
-                None => {}
-            }
+    // cli subcommands do their work and exit without starting the app
+    if let Some(result) = cli::run(&args) {
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
-    });
-
-    thread::spawn(move || {
-        #[cfg(target_os = "linux")]
-        linux_listener::linux_start(&programmable_keys_arc);
-
-        #[cfg(target_os = "windows")]
-        windows_listener::windows_start(&programmable_keys_arc);
-    });
+        return;
+    }
+
+    let settings = Settings::load();
+
+    // write the settings back if new options need to show up in the file
+    if let Err(err) = settings.save_new_options() {
+        eprintln!("Failed to save settings file: {}", err);
+    }
+
+    let mut args = args.into_iter();
+    let mut headless = false;
+    let mut keymap_name: Option<String> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => headless = true,
+            "--keymap" => keymap_name = args.next(),
+            _ => eprintln!("Ignoring unknown argument {}", arg),
+        }
+    }
+
+    // Run without the window or tray, for servers and login sessions without a tray
+    if headless {
+        headless::run(settings, keymap_name);
+        return;
+    }
+
+    // Load the keymap profile that was active last time, a broken file leaves us with an empty keymap
+    let Runtime {
+        keymap,
+        layers,
+        events,
+        profiles,
+        executor,
+        recorder,
+    } = Runtime::start(&settings, &settings.active_profile);
 
     // Create tauri app
-    let show = CustomMenuItem::new("show".to_string(), "Show");
-    let quit = CustomMenuItem::new("quit".to_string(), "Quit");
-    let hide = CustomMenuItem::new("hide".to_string(), "Hide");
-    let tray_menu = SystemTrayMenu::new()
-        .add_item(quit)
-        .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(show)
-        .add_item(hide);
-    let tray = SystemTray::new().with_menu(tray_menu);
+    let tray = SystemTray::new().with_menu(tray::build_tray_menu(&profiles.active()));
 
     tauri::Builder::default()
-        .manage(keymap_arc)
+        .manage(keymap)
+        .manage(executor)
+        .manage(layers)
+        .manage(profiles)
+        .manage(recorder)
         .system_tray(tray)
-        .on_system_tray_event(|app, event| match event {
-            SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
-                "quit" => {
-                    std::process::exit(0);
-                }
-                "show" => {
-                    let window = app.get_window("main").unwrap();
-                    window.show().unwrap();
+        .on_system_tray_event(|app, event| {
+            if let SystemTrayEvent::MenuItemClick { id, .. } = event {
+                match id.as_str() {
+                    "quit" => {
+                        app.state::<MacroExecutor>().shutdown();
+                        std::process::exit(0);
+                    }
+                    "show" => {
+                        let window = app.get_window("main").unwrap();
+                        window.show().unwrap();
+                    }
+                    "hide" => {
+                        let window = app.get_window("main").unwrap();
+                        window.hide().unwrap();
+                    }
+                    "stop_macros" => {
+                        app.state::<MacroExecutor>().stop_all();
+                    }
+                    _ => {
+                        if let Some(profile) = id.strip_prefix(tray::PROFILE_ITEM_PREFIX) {
+                            if let Err(err) = app.state::<Profiles>().activate(profile) {
+                                eprintln!(
+                                    "Failed to switch to keymap profile {}: {}",
+                                    profile, err
+                                );
+                            }
+                        }
+                    }
                 }
-                "hide" => {
-                    let window = app.get_window("main").unwrap();
-                    window.hide().unwrap();
-                }
-                _ => {}
-            },
-            _ => {}
+            }
         })
-        .on_window_event(|event| match event.event() {
-            tauri::WindowEvent::CloseRequested { api, .. } => {
+        .on_window_event(|event| {
+            if let tauri::WindowEvent::CloseRequested { api, .. } = event.event() {
                 event.window().hide().unwrap();
                 api.prevent_close();
             }
-            _ => {}
         })
         .invoke_handler(tauri::generate_handler![
             send_keymap,
             add_button,
-            save_keymap
+            save_keymap,
+            stop_all_macros,
+            send_active_layers,
+            send_keymap_diagnostics,
+            list_profiles,
+            send_active_profile,
+            create_profile,
+            duplicate_profile,
+            rename_profile,
+            delete_profile,
+            activate_profile,
+            list_backups,
+            restore_backup,
+            import_keymap,
+            export_keymap,
+            parse_macro_script,
+            print_macro_script,
+            start_recording,
+            stop_recording,
+            cancel_recording,
+            preview_macro,
+            list_input_devices
         ])
         .build(tauri::generate_context!())
         .expect("error while running tauri application")
-        .run(|_app_handle, event| match event {
-            tauri::RunEvent::Ready {} => _app_handle.emit_all("load-keymap", "").unwrap(),
+        .run(move |_app_handle, event| match event {
+            tauri::RunEvent::Ready => {
+                events.set_app(_app_handle.clone());
+                _app_handle.emit_all("load-keymap", "").unwrap()
+            }
             tauri::RunEvent::ExitRequested { api, .. } => {
                 api.prevent_exit();
             }
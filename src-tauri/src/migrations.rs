@@ -0,0 +1,164 @@
+use serde_json::Value;
+
+use crate::validation::KeymapError;
+
+/// Schema version of the keymap files this build writes
+pub const CURRENT_SCHEMA_VERSION: u64 = 1;
+
+/// Upgrades a keymap document by one schema version in place
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` upgrades a version n keymap document to version n + 1
+const MIGRATIONS: [Migration; CURRENT_SCHEMA_VERSION as usize] = [v0_to_v1];
+
+/// The schema version of a keymap document, files from before versioning count as version 0
+pub fn schema_version(document: &Value) -> Result<u64, KeymapError> {
+    match document.get("schema_version") {
+        None => Ok(0),
+        Some(version) => version.as_u64().ok_or_else(|| KeymapError::Migration {
+            from: 0,
+            message: format!("schema_version must be a whole number, not {}", version),
+        }),
+    }
+}
+
+/// Upgrades a keymap document step by step to the current schema version
+pub fn migrate(document: &mut Value) -> Result<(), KeymapError> {
+    let version = schema_version(document)?;
+
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(KeymapError::UnsupportedVersion { version });
+    }
+
+    for from in version..CURRENT_SCHEMA_VERSION {
+        MIGRATIONS[from as usize](document)
+            .map_err(|message| KeymapError::Migration { from, message })?;
+        document["schema_version"] = Value::from(from + 1);
+
+        println!(
+            "Migrated keymap from schema version {} to {}",
+            from,
+            from + 1
+        );
+    }
+
+    Ok(())
+}
+
+/// Version 0 is every keymap saved before the schema was versioned. Everything
+/// added up to version 1 was optional, so the only change is the version itself.
+fn v0_to_v1(document: &mut Value) -> Result<(), String> {
+    if !document.is_object() {
+        return Err("a keymap has to be a json object".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::backups;
+    use crate::button_codes::default_ranges;
+    use crate::keymap::{Keymap, MacroAction, MacroType, RunPolicy, Trigger};
+    use crate::programmable_keys::ProgrammableKeys;
+    use crate::test_support::use_temp_data_dir;
+
+    const V0: &str = include_str!("../fixtures/keymaps/v0.json");
+    const V1: &str = include_str!("../fixtures/keymaps/v1.json");
+
+    /// Puts a fixture in the keymaps folder and loads it like the app does
+    fn load_fixture(keymap_name: &str, fixture: &str) -> Keymap {
+        use_temp_data_dir();
+        fs::create_dir_all(Keymap::keymap_dir()).unwrap();
+        fs::write(Keymap::keymap_path(keymap_name), fixture).unwrap();
+
+        Keymap::load_from_file(keymap_name.to_string(), &default_ranges()).unwrap()
+    }
+
+    fn stored_version(keymap_name: &str) -> Value {
+        let stored = fs::read_to_string(Keymap::keymap_path(keymap_name)).unwrap();
+        serde_json::from_str::<Value>(&stored).unwrap()["schema_version"].clone()
+    }
+
+    #[test]
+    fn v0_fixture_is_migrated_and_backed_up() {
+        let keymap = load_fixture("fixture-v0", V0);
+
+        assert_eq!(keymap.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(keymap.map_name, "fixture-v0");
+        assert_eq!(keymap.buttons.len(), 3);
+        assert_eq!(
+            keymap.buttons[0].actions,
+            vec![MacroAction::Print("Hello, world!".to_string())]
+        );
+        assert_eq!(keymap.buttons[1].macro_type, MacroType::Toggle);
+        assert_eq!(keymap.buttons[2].macro_type, MacroType::Repeat(3));
+        // fields added since version 0 get their defaults
+        assert_eq!(keymap.buttons[0].run_policy, RunPolicy::Queue);
+        assert!(keymap.layers.is_empty());
+
+        // the file is rewritten in the current format, after keeping the original
+        assert_eq!(stored_version("fixture-v0"), CURRENT_SCHEMA_VERSION);
+
+        let backups = backups::list(Some("fixture-v0")).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].schema_version, Some(0));
+        assert_eq!(fs::read_to_string(backups[0].path()).unwrap(), V0);
+    }
+
+    #[test]
+    fn v1_fixture_loads_as_is() {
+        let keymap = load_fixture("fixture-v1", V1);
+
+        assert_eq!(keymap.schema_version, 1);
+        assert_eq!(keymap.buttons.len(), 3);
+        assert_eq!(keymap.timing.tap_window_ms, 250);
+        assert_eq!(keymap.buttons[0].run_policy, RunPolicy::Restart);
+        assert_eq!(
+            keymap.buttons[0].actions_for(&Trigger::DoubleTap),
+            Some(&vec![MacroAction::Print("Hello again!".to_string())])
+        );
+        assert_eq!(keymap.buttons[2].hold_threshold_ms, Some(400));
+        assert_eq!(keymap.layers.len(), 1);
+        assert_eq!(
+            keymap.layers[0].buttons[0].programmable_key,
+            ProgrammableKeys::get_from_index(2)
+        );
+
+        // nothing to migrate, so nothing is rewritten or backed up
+        assert_eq!(
+            fs::read_to_string(Keymap::keymap_path("fixture-v1")).unwrap(),
+            V1
+        );
+        assert!(backups::list(Some("fixture-v1")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn every_fixture_migrates_to_the_current_version() {
+        for fixture in [V0, V1] {
+            let mut document: Value = serde_json::from_str(fixture).unwrap();
+            migrate(&mut document).unwrap();
+
+            assert_eq!(schema_version(&document).unwrap(), CURRENT_SCHEMA_VERSION);
+            Keymap::from_document("fixture".to_string(), document, &default_ranges()).unwrap();
+        }
+    }
+
+    #[test]
+    fn newer_and_broken_versions_are_refused() {
+        let mut newer = serde_json::json!({ "schema_version": CURRENT_SCHEMA_VERSION + 1 });
+        assert!(matches!(
+            migrate(&mut newer),
+            Err(KeymapError::UnsupportedVersion { .. })
+        ));
+
+        let broken = serde_json::json!({ "schema_version": "one" });
+        assert!(schema_version(&broken).is_err());
+
+        let mut not_an_object = serde_json::json!([]);
+        assert!(migrate(&mut not_an_object).is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use enigo::{Coordinate, Enigo, Keyboard, Mouse, Settings};
+use serde::{Deserialize, Serialize};
+
+use crate::keymap::{Key, MouseButton};
+use crate::macro_executor::CancelToken;
+#[cfg(target_os = "linux")]
+use crate::uinput::{UinputBackend, VirtualDevice};
+
+/// Input that couldn't be sent
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutputError {
+    pub message: String,
+}
+
+impl OutputError {
+    pub fn new(message: String) -> OutputError {
+        OutputError { message }
+    }
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to send input: {}", self.message)
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+impl From<enigo::InputError> for OutputError {
+    fn from(err: enigo::InputError) -> Self {
+        OutputError::new(err.to_string())
+    }
+}
+
+impl From<enigo::NewConError> for OutputError {
+    fn from(err: enigo::NewConError) -> Self {
+        OutputError::new(err.to_string())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    Press,
+    Release,
+    /// a press straight followed by a release
+    Click,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Axis {
+    Vertical,
+    Horizontal,
+}
+
+/// Somewhere macros send their keyboard and mouse input
+pub trait OutputBackend {
+    fn key(&mut self, key: &Key, direction: Direction) -> Result<(), OutputError>;
+
+    /// types out text, whatever keys it takes
+    fn text(&mut self, text: &str) -> Result<(), OutputError>;
+
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        direction: Direction,
+    ) -> Result<(), OutputError>;
+
+    fn move_mouse_to(&mut self, x: i32, y: i32) -> Result<(), OutputError>;
+
+    fn move_mouse_by(&mut self, x: i32, y: i32) -> Result<(), OutputError>;
+
+    /// positive steps scroll down or right
+    fn scroll(&mut self, steps: i32, axis: Axis) -> Result<(), OutputError>;
+
+    /// Waits between actions, returning early if the macro is cancelled
+    fn delay(&mut self, duration: Duration, token: &CancelToken) {
+        token.sleep(duration);
+    }
+}
+
+/// Which backend macros send their input through
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum OutputBackendKind {
+    #[default]
+    Enigo,
+    /// a virtual device made through `/dev/uinput`, linux only, works the same on any compositor
+    Uinput,
+}
+
+/// Hands out an output backend to each macro job. The uinput device is made
+/// once and shared, the compositor takes a moment to pick up a new device.
+#[derive(Clone, Default)]
+pub struct Outputs {
+    #[cfg(target_os = "linux")]
+    uinput: Option<Arc<VirtualDevice>>,
+    #[cfg(test)]
+    recording: bool,
+}
+
+impl Outputs {
+    /// Sets up the chosen backend, falling back to enigo if it isn't available
+    pub fn new(kind: OutputBackendKind) -> Outputs {
+        match kind {
+            OutputBackendKind::Enigo => Outputs::default(),
+            #[cfg(target_os = "linux")]
+            OutputBackendKind::Uinput => match VirtualDevice::create() {
+                Ok(device) => Outputs {
+                    uinput: Some(Arc::new(device)),
+                    #[cfg(test)]
+                    recording: false,
+                },
+                Err(err) => {
+                    eprintln!("Failed to create uinput device, using enigo: {}", err);
+                    Outputs::default()
+                }
+            },
+            #[cfg(not(target_os = "linux"))]
+            OutputBackendKind::Uinput => {
+                eprintln!("uinput is only available on linux, using enigo");
+                Outputs::default()
+            }
+        }
+    }
+
+    /// Hands out recording backends that wait out their delays, for testing how macros are run
+    #[cfg(test)]
+    pub fn recording() -> Outputs {
+        Outputs {
+            #[cfg(target_os = "linux")]
+            uinput: None,
+            recording: true,
+        }
+    }
+
+    /// A backend for one macro job to send its input through
+    pub fn backend(&self) -> Result<Box<dyn OutputBackend>, OutputError> {
+        #[cfg(test)]
+        if self.recording {
+            return Ok(Box::new(RecordingBackend {
+                events: Vec::new(),
+                wait_on_delay: true,
+            }));
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(device) = &self.uinput {
+            return Ok(Box::new(UinputBackend::new(device.clone())));
+        }
+
+        Ok(Box::new(EnigoBackend::new()?))
+    }
+
+    /// Removes the uinput device, for exits that skip destructors
+    pub fn shutdown(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(device) = &self.uinput {
+            device.destroy();
+        }
+    }
+}
+
+/// Sends input through enigo, which works on X11, Windows and macOS
+pub struct EnigoBackend {
+    enigo: Enigo,
+}
+
+impl EnigoBackend {
+    pub fn new() -> Result<EnigoBackend, OutputError> {
+        Ok(EnigoBackend {
+            enigo: Enigo::new(&Settings::default())?,
+        })
+    }
+}
+
+impl OutputBackend for EnigoBackend {
+    fn key(&mut self, key: &Key, direction: Direction) -> Result<(), OutputError> {
+        Ok(self.enigo.key(
+            match_key_to_enigo(key.clone()),
+            match_direction_to_enigo(direction),
+        )?)
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), OutputError> {
+        Ok(self.enigo.text(text)?)
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        direction: Direction,
+    ) -> Result<(), OutputError> {
+        Ok(self.enigo.button(
+            match_button_to_enigo(button),
+            match_direction_to_enigo(direction),
+        )?)
+    }
+
+    fn move_mouse_to(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+        Ok(self.enigo.move_mouse(x, y, Coordinate::Abs)?)
+    }
+
+    fn move_mouse_by(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+        Ok(self.enigo.move_mouse(x, y, Coordinate::Rel)?)
+    }
+
+    fn scroll(&mut self, steps: i32, axis: Axis) -> Result<(), OutputError> {
+        let axis = match axis {
+            Axis::Vertical => enigo::Axis::Vertical,
+            Axis::Horizontal => enigo::Axis::Horizontal,
+        };
+        Ok(self.enigo.scroll(steps, axis)?)
+    }
+}
+
+/// Something a `RecordingBackend` was asked to send
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub enum OutputEvent {
+    Key {
+        key: Key,
+        direction: Direction,
+    },
+    Text(String),
+    MouseButton {
+        button: MouseButton,
+        direction: Direction,
+    },
+    MouseMoveTo {
+        x: i32,
+        y: i32,
+    },
+    MouseMoveBy {
+        x: i32,
+        y: i32,
+    },
+    Scroll {
+        steps: i32,
+        axis: Axis,
+    },
+    Delay {
+        ms: u64,
+    },
+}
+
+/// Writes down everything it is asked to send instead of sending it, and skips
+/// delays, for previewing macros and checking what they do
+#[derive(Debug, Default)]
+pub struct RecordingBackend {
+    pub events: Vec<OutputEvent>,
+    /// waits out delays instead of skipping them, so a macro takes as long as it would
+    pub wait_on_delay: bool,
+}
+
+impl RecordingBackend {
+    pub fn new() -> RecordingBackend {
+        RecordingBackend::default()
+    }
+}
+
+impl OutputBackend for RecordingBackend {
+    fn key(&mut self, key: &Key, direction: Direction) -> Result<(), OutputError> {
+        self.events.push(OutputEvent::Key {
+            key: key.clone(),
+            direction,
+        });
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), OutputError> {
+        self.events.push(OutputEvent::Text(text.to_string()));
+        Ok(())
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        direction: Direction,
+    ) -> Result<(), OutputError> {
+        self.events
+            .push(OutputEvent::MouseButton { button, direction });
+        Ok(())
+    }
+
+    fn move_mouse_to(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+        self.events.push(OutputEvent::MouseMoveTo { x, y });
+        Ok(())
+    }
+
+    fn move_mouse_by(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+        self.events.push(OutputEvent::MouseMoveBy { x, y });
+        Ok(())
+    }
+
+    fn scroll(&mut self, steps: i32, axis: Axis) -> Result<(), OutputError> {
+        self.events.push(OutputEvent::Scroll { steps, axis });
+        Ok(())
+    }
+
+    fn delay(&mut self, duration: Duration, token: &CancelToken) {
+        self.events.push(OutputEvent::Delay {
+            ms: duration.as_millis() as u64,
+        });
+
+        if self.wait_on_delay {
+            token.sleep(duration);
+        }
+    }
+}
+
+fn match_direction_to_enigo(direction: Direction) -> enigo::Direction {
+    match direction {
+        Direction::Press => enigo::Direction::Press,
+        Direction::Release => enigo::Direction::Release,
+        Direction::Click => enigo::Direction::Click,
+    }
+}
+
+fn match_key_to_enigo(key: Key) -> enigo::Key {
+    match key {
+        Key::Alt => enigo::Key::Alt,
+        Key::Backspace => enigo::Key::Backspace,
+        Key::CapsLock => enigo::Key::CapsLock,
+        Key::ControlLeft => enigo::Key::LControl,
+        Key::ControlRight => enigo::Key::RControl,
+        Key::Delete => enigo::Key::Delete,
+        Key::DownArrow => enigo::Key::DownArrow,
+        Key::End => enigo::Key::End,
+        Key::Escape => enigo::Key::Escape,
+        Key::F1 => enigo::Key::F1,
+        Key::F10 => enigo::Key::F10,
+        Key::F11 => enigo::Key::F11,
+        Key::F12 => enigo::Key::F12,
+        Key::F2 => enigo::Key::F2,
+        Key::F3 => enigo::Key::F3,
+        Key::F4 => enigo::Key::F4,
+        Key::F5 => enigo::Key::F5,
+        Key::F6 => enigo::Key::F6,
+        Key::F7 => enigo::Key::F7,
+        Key::F8 => enigo::Key::F8,
+        Key::F9 => enigo::Key::F9,
+        Key::Home => enigo::Key::Home,
+        Key::LeftArrow => enigo::Key::LeftArrow,
+        Key::MetaLeft => enigo::Key::Meta,
+        Key::MetaRight => enigo::Key::Meta,
+        Key::PageDown => enigo::Key::PageDown,
+        Key::PageUp => enigo::Key::PageUp,
+        Key::Return => enigo::Key::Return,
+        Key::RightArrow => enigo::Key::RightArrow,
+        Key::ShiftLeft => enigo::Key::LShift,
+        Key::ShiftRight => enigo::Key::RShift,
+        Key::Space => enigo::Key::Space,
+        Key::Tab => enigo::Key::Tab,
+        Key::UpArrow => enigo::Key::UpArrow,
+        Key::PrintScreen => enigo::Key::Print,
+        Key::Pause => enigo::Key::Pause,
+        Key::NumLock => enigo::Key::Numlock,
+        Key::BackQuote => enigo::Key::Unicode('\''),
+        Key::Num1 => enigo::Key::Unicode('1'),
+        Key::Num2 => enigo::Key::Unicode('2'),
+        Key::Num3 => enigo::Key::Unicode('3'),
+        Key::Num4 => enigo::Key::Unicode('4'),
+        Key::Num5 => enigo::Key::Unicode('5'),
+        Key::Num6 => enigo::Key::Unicode('6'),
+        Key::Num7 => enigo::Key::Unicode('7'),
+        Key::Num8 => enigo::Key::Unicode('8'),
+        Key::Num9 => enigo::Key::Unicode('9'),
+        Key::Num0 => enigo::Key::Unicode('0'),
+        Key::Minus => enigo::Key::Unicode('-'),
+        Key::Equal => enigo::Key::Unicode('='),
+        Key::KeyQ => enigo::Key::Unicode('q'),
+        Key::KeyW => enigo::Key::Unicode('w'),
+        Key::KeyE => enigo::Key::Unicode('e'),
+        Key::KeyR => enigo::Key::Unicode('r'),
+        Key::KeyT => enigo::Key::Unicode('t'),
+        Key::KeyY => enigo::Key::Unicode('y'),
+        Key::KeyU => enigo::Key::Unicode('u'),
+        Key::KeyI => enigo::Key::Unicode('i'),
+        Key::KeyO => enigo::Key::Unicode('o'),
+        Key::KeyP => enigo::Key::Unicode('p'),
+        Key::LeftBracket => enigo::Key::Unicode('['),
+        Key::RightBracket => enigo::Key::Unicode(']'),
+        Key::KeyA => enigo::Key::Unicode('a'),
+        Key::KeyS => enigo::Key::Unicode('s'),
+        Key::KeyD => enigo::Key::Unicode('d'),
+        Key::KeyF => enigo::Key::Unicode('f'),
+        Key::KeyG => enigo::Key::Unicode('g'),
+        Key::KeyH => enigo::Key::Unicode('h'),
+        Key::KeyJ => enigo::Key::Unicode('j'),
+        Key::KeyK => enigo::Key::Unicode('k'),
+        Key::KeyL => enigo::Key::Unicode('l'),
+        Key::SemiColon => enigo::Key::Unicode(';'),
+        Key::Quote => enigo::Key::Unicode('\''),
+        Key::BackSlash => enigo::Key::Unicode('\\'),
+        Key::IntlBackslash => enigo::Key::Unicode('\\'),
+        Key::KeyZ => enigo::Key::Unicode('z'),
+        Key::KeyX => enigo::Key::Unicode('x'),
+        Key::KeyC => enigo::Key::Unicode('c'),
+        Key::KeyV => enigo::Key::Unicode('v'),
+        Key::KeyB => enigo::Key::Unicode('b'),
+        Key::KeyN => enigo::Key::Unicode('n'),
+        Key::KeyM => enigo::Key::Unicode('m'),
+        Key::Comma => enigo::Key::Unicode(','),
+        Key::Dot => enigo::Key::Unicode('.'),
+        Key::Slash => enigo::Key::Unicode('/'),
+        Key::Insert => enigo::Key::Insert,
+        Key::KpPlus => enigo::Key::Unicode('+'),
+        Key::KpMultiply => enigo::Key::Unicode('*'),
+        Key::Unknown(num) => enigo::Key::Other(num),
+    }
+}
+
+fn match_button_to_enigo(button: MouseButton) -> enigo::Button {
+    match button {
+        MouseButton::Left => enigo::Button::Left,
+        MouseButton::Right => enigo::Button::Right,
+        MouseButton::Middle => enigo::Button::Middle,
+        MouseButton::Back => enigo::Button::Back,
+        MouseButton::Forward => enigo::Button::Forward,
+    }
+}
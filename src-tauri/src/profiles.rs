@@ -0,0 +1,609 @@
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::backups;
+use crate::button_codes::{ButtonCodes, CodeRange};
+use crate::events::EventSink;
+use crate::keymap::{Keymap, MacroAction};
+use crate::keymap_formats;
+use crate::layers::LayerState;
+use crate::programmable_keys::ProgrammableKeys;
+use crate::settings::SharedSettings;
+use crate::tray;
+use crate::validation;
+use crate::validation::Diagnostic;
+
+/// Manages the keymap files in the keymaps folder as named profiles, one of
+/// which is loaded into the shared keymap at a time.
+#[derive(Clone)]
+pub struct Profiles {
+    keymap: Arc<Mutex<Keymap>>,
+    layers: Arc<Mutex<LayerState>>,
+    events: EventSink,
+    /// for checking the button labels of the keymaps it loads and saves
+    codes: ButtonCodes,
+    /// remembers the active profile and each profile's toggled layers
+    settings: SharedSettings,
+    /// problems found in the active keymap when it was loaded or saved
+    diagnostics: Arc<Mutex<Vec<Diagnostic>>>,
+}
+
+impl Profiles {
+    pub fn new(
+        keymap: Arc<Mutex<Keymap>>,
+        layers: Arc<Mutex<LayerState>>,
+        events: EventSink,
+        codes: ButtonCodes,
+        settings: SharedSettings,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Profiles {
+        Profiles {
+            keymap,
+            layers,
+            events,
+            codes,
+            settings,
+            diagnostics: Arc::new(Mutex::new(diagnostics)),
+        }
+    }
+
+    /// Names of every keymap profile on disk, sorted alphabetically
+    pub fn list() -> Result<Vec<String>, io::Error> {
+        let keymap_dir = Keymap::keymap_dir();
+
+        if !keymap_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut profiles: Vec<String> = Vec::new();
+        for entry in fs::read_dir(keymap_dir)? {
+            let path = entry?.path();
+
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    profiles.push(name.to_string());
+                }
+            }
+        }
+
+        profiles.sort();
+        Ok(profiles)
+    }
+
+    /// Name of the profile currently loaded
+    pub fn active(&self) -> String {
+        match self.keymap.lock() {
+            Ok(keymap) => keymap.map_name.clone(),
+            Err(_) => {
+                panic!("Failed to acquire keymap lock")
+            }
+        }
+    }
+
+    /// Problems found in the active keymap
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self.diagnostics.lock() {
+            Ok(diagnostics) => diagnostics.clone(),
+            Err(_) => {
+                panic!("Failed to acquire keymap diagnostics lock")
+            }
+        }
+    }
+
+    /// Replaces the active keymap's diagnostics and lets the UI know
+    pub fn set_diagnostics(&self, diagnostics: Vec<Diagnostic>) {
+        match self.diagnostics.lock() {
+            Ok(mut borrowed_diagnostics) => *borrowed_diagnostics = diagnostics.clone(),
+            Err(_) => {
+                panic!("Failed to acquire keymap diagnostics lock")
+            }
+        }
+
+        self.events.emit("keymap-diagnostics", diagnostics);
+    }
+
+    /// Creates a new blank profile
+    pub fn create(&self, name: &str, button_count: i32) -> Result<(), io::Error> {
+        Profiles::check_new_name(name)?;
+        self.save(Keymap::new(name.to_string(), button_count))?;
+        self.refresh();
+        Ok(())
+    }
+
+    /// Copies an existing profile under a new name
+    pub fn duplicate(&self, name: &str, new_name: &str) -> Result<(), io::Error> {
+        Profiles::check_exists(name)?;
+        Profiles::check_new_name(new_name)?;
+
+        let mut keymap = Keymap::load_from_file(name.to_string(), self.codes.ranges())?;
+        keymap.map_name = new_name.to_string();
+        self.save(keymap)?;
+
+        self.refresh();
+        Ok(())
+    }
+
+    /// Renames a profile, carrying over its toggled layers and keeping it active if it was
+    pub fn rename(&self, name: &str, new_name: &str) -> Result<(), io::Error> {
+        Profiles::check_exists(name)?;
+        Profiles::check_new_name(new_name)?;
+
+        fs::rename(Keymap::keymap_path(name), Keymap::keymap_path(new_name))?;
+
+        // rewrite the file so the name inside matches
+        let keymap = Keymap::load_from_file(new_name.to_string(), self.codes.ranges())?;
+        self.save(keymap)?;
+
+        self.settings.update(|settings| {
+            if let Some(toggled) = settings.toggled_layers.remove(name) {
+                settings
+                    .toggled_layers
+                    .insert(new_name.to_string(), toggled);
+            }
+            if settings.active_profile == name {
+                settings.active_profile = new_name.to_string();
+            }
+        })?;
+
+        if self.active() == name {
+            match self.keymap.lock() {
+                Ok(mut keymap) => keymap.map_name = new_name.to_string(),
+                Err(_) => {
+                    panic!("Failed to acquire keymap lock")
+                }
+            }
+            self.reset_layers(new_name);
+            self.events.emit("load-keymap", "");
+        }
+
+        self.refresh();
+        Ok(())
+    }
+
+    /// Deletes a profile, the active profile can't be deleted
+    pub fn delete(&self, name: &str) -> Result<(), io::Error> {
+        Profiles::check_exists(name)?;
+
+        if self.active() == name {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Can't delete the active keymap profile",
+            ));
+        }
+
+        fs::remove_file(Keymap::keymap_path(name))?;
+
+        self.settings
+            .update(|settings| settings.toggled_layers.remove(name))?;
+
+        self.refresh();
+        Ok(())
+    }
+
+    /// Loads a profile into the shared keymap and remembers it for next launch
+    pub fn activate(&self, name: &str) -> Result<(), io::Error> {
+        self.switch_to(name)?;
+
+        self.settings
+            .update(|settings| settings.active_profile = name.to_string())
+    }
+
+    /// Loads a profile into the shared keymap without remembering it, for
+    /// automatic switches that shouldn't change the profile the app starts with
+    pub fn switch_to(&self, name: &str) -> Result<(), io::Error> {
+        Profiles::check_exists(name)?;
+
+        // a broken profile is refused, so the working keymap stays active
+        let keymap = Keymap::load_from_file(name.to_string(), self.codes.ranges())?;
+        let diagnostics = validation::validate(&keymap, self.codes.ranges())
+            .iter()
+            .map(Diagnostic::from)
+            .collect();
+
+        match self.keymap.lock() {
+            Ok(mut borrowed_keymap) => *borrowed_keymap = keymap,
+            Err(_) => {
+                panic!("Failed to acquire keymap lock")
+            }
+        }
+        self.reset_layers(name);
+        self.set_diagnostics(diagnostics);
+
+        println!("Activated keymap profile {}", name);
+        self.events.emit("load-keymap", "");
+        self.refresh();
+        Ok(())
+    }
+
+    /// Swaps in a new version of the active keymap after its file was edited on disk.
+    /// A version that doesn't pass validation is only reported, the old one stays active.
+    pub fn reload_active(&self, keymap_json: &str) {
+        let name = self.active();
+
+        let keymap = match Keymap::from_json(name.clone(), keymap_json, self.codes.ranges()) {
+            Ok((keymap, _)) => keymap,
+            Err(err) => {
+                eprintln!(
+                    "Keeping the current keymap, the edited file has errors: {}",
+                    err
+                );
+                self.set_diagnostics(err.diagnostics());
+                return;
+            }
+        };
+
+        let diagnostics = validation::validate(&keymap, self.codes.ranges())
+            .iter()
+            .map(Diagnostic::from)
+            .collect();
+
+        match self.keymap.lock() {
+            Ok(mut borrowed_keymap) => *borrowed_keymap = keymap,
+            Err(_) => {
+                panic!("Failed to acquire keymap lock")
+            }
+        }
+
+        println!("Reloaded keymap profile {}", name);
+        self.set_diagnostics(diagnostics);
+        self.events.emit("load-keymap", "");
+    }
+
+    /// Saves a keymap from the editor, refusing one that wouldn't load again.
+    /// A keymap for another existing profile than the active one is saved without
+    /// activating it, profiles are only ever created or renamed through their own calls.
+    pub fn save_keymap(&self, mut keymap: Keymap) -> Result<(), io::Error> {
+        keymap.correct_button_count();
+        let diagnostics = Profiles::check_keymap(&keymap, self.codes.ranges())?;
+
+        let mut active_keymap = match self.keymap.lock() {
+            Ok(keymap) => keymap,
+            Err(_) => {
+                panic!("Failed to acquire keymap lock")
+            }
+        };
+
+        // the profile was switched since the editor loaded this keymap
+        if keymap.map_name != active_keymap.map_name {
+            Profiles::check_exists(&keymap.map_name)?;
+            return self.save(keymap);
+        }
+
+        active_keymap.buttons = keymap.buttons;
+        active_keymap.button_count = keymap.button_count;
+        active_keymap.timing = keymap.timing;
+        active_keymap.layers = keymap.layers;
+
+        self.save(active_keymap.clone())?;
+        drop(active_keymap);
+
+        self.set_diagnostics(diagnostics);
+        Ok(())
+    }
+
+    /// Replaces the actions of a button in the active keymap's base layer and
+    /// saves it, adding the button if the keymap doesn't have it yet
+    pub fn set_button_actions(
+        &self,
+        button: &ProgrammableKeys,
+        actions: Vec<MacroAction>,
+    ) -> Result<(), io::Error> {
+        let mut active_keymap = match self.keymap.lock() {
+            Ok(keymap) => keymap,
+            Err(_) => {
+                panic!("Failed to acquire keymap lock")
+            }
+        };
+
+        // change a copy, so actions that would break the keymap never reach the live one
+        let mut keymap = active_keymap.clone();
+        keymap.set_button_actions(button, actions);
+        let diagnostics = Profiles::check_keymap(&keymap, self.codes.ranges())?;
+
+        self.save(keymap.clone())?;
+        *active_keymap = keymap;
+        drop(active_keymap);
+
+        self.set_diagnostics(diagnostics);
+        self.events.emit("load-keymap", "");
+        Ok(())
+    }
+
+    /// Same as `set_button_actions` for any profile, ones that aren't active are
+    /// changed in their file
+    pub fn set_profile_button_actions(
+        &self,
+        name: &str,
+        button: &ProgrammableKeys,
+        actions: Vec<MacroAction>,
+    ) -> Result<(), io::Error> {
+        if self.active() == name {
+            return self.set_button_actions(button, actions);
+        }
+
+        Profiles::set_stored_button_actions(
+            name,
+            button,
+            actions,
+            self.codes.ranges(),
+            self.settings.get().keymap_backups,
+        )?;
+        self.refresh();
+        Ok(())
+    }
+
+    /// Replaces the actions of a button in a profile's file, for when no profile is loaded
+    pub fn set_stored_button_actions(
+        name: &str,
+        button: &ProgrammableKeys,
+        actions: Vec<MacroAction>,
+        ranges: &[CodeRange],
+        backups: usize,
+    ) -> Result<(), io::Error> {
+        Profiles::check_exists(name)?;
+
+        let mut keymap = Keymap::load_from_file(name.to_string(), ranges)?;
+        keymap.set_button_actions(button, actions);
+        Profiles::check_keymap(&keymap, ranges)?;
+
+        Keymap::save_to_file(keymap, backups)?;
+        println!("Updated {} in keymap profile {}", button, name);
+        Ok(())
+    }
+
+    /// Puts a backed up keymap back in place. The file it replaces is backed up
+    /// first, so a restore can be undone by restoring that backup.
+    pub fn restore_backup(&self, file_name: &str) -> Result<(), io::Error> {
+        let backup = backups::find(file_name)?;
+        let keymap_json = fs::read_to_string(backup.path())?;
+
+        let (keymap, _) = Keymap::from_json(
+            backup.keymap_name.clone(),
+            &keymap_json,
+            self.codes.ranges(),
+        )?;
+        self.save(keymap)?;
+        println!("Restored keymap {} from {}", backup.keymap_name, file_name);
+
+        if self.active() == backup.keymap_name {
+            self.switch_to(&backup.keymap_name)
+        } else {
+            self.refresh();
+            Ok(())
+        }
+    }
+
+    /// Imports a json, toml or yaml keymap file as a new profile, named after
+    /// the file unless a name is given. Returns the new profile's name.
+    pub fn import(&self, path: &Path, name: Option<&str>) -> Result<String, io::Error> {
+        let mut keymap = keymap_formats::import_keymap(path, self.codes.ranges())?;
+        if let Some(name) = name {
+            keymap.map_name = name.to_string();
+        }
+
+        Profiles::check_new_name(&keymap.map_name)?;
+        let name = keymap.map_name.clone();
+        self.save(keymap)?;
+        println!("Imported keymap {} from {:?}", name, path);
+
+        self.refresh();
+        Ok(name)
+    }
+
+    /// Exports a profile to a file, in the format the file's extension asks for
+    pub fn export(&self, name: &str, path: &Path) -> Result<(), io::Error> {
+        Profiles::check_exists(name)?;
+
+        let keymap = Keymap::load_from_file(name.to_string(), self.codes.ranges())?;
+        keymap_formats::export_keymap(&keymap, path)?;
+        println!("Exported keymap {} to {:?}", name, path);
+        Ok(())
+    }
+
+    fn reset_layers(&self, name: &str) {
+        match self.layers.lock() {
+            Ok(mut layers) => *layers = LayerState::load(name, &self.settings),
+            Err(_) => {
+                panic!("Failed to acquire layer state lock")
+            }
+        }
+    }
+
+    /// Updates the tray menu after the profile list or active profile changed
+    pub fn refresh(&self) {
+        if let Some(app) = self.events.app() {
+            tray::refresh_tray_menu(&app, &self.active());
+        }
+    }
+
+    /// Validates a keymap about to be saved, refusing one with problems that would
+    /// stop it from loading again. Returns the problems that aren't fatal.
+    fn check_keymap(keymap: &Keymap, ranges: &[CodeRange]) -> Result<Vec<Diagnostic>, io::Error> {
+        let problems = validation::validate(keymap, ranges);
+
+        if problems.iter().any(|problem| problem.is_fatal()) {
+            let errors: Vec<String> = problems
+                .iter()
+                .filter(|problem| problem.is_fatal())
+                .map(|problem| problem.to_string())
+                .collect();
+            return Err(Error::new(ErrorKind::InvalidData, errors.join("\n")));
+        }
+
+        Ok(problems.iter().map(Diagnostic::from).collect())
+    }
+
+    /// Saves a keymap file, keeping as many backups as the settings ask for
+    fn save(&self, keymap: Keymap) -> Result<(), io::Error> {
+        Keymap::save_to_file(keymap, self.settings.get().keymap_backups)
+    }
+
+    fn check_exists(name: &str) -> Result<(), io::Error> {
+        Profiles::check_name(name)?;
+
+        if !Keymap::keymap_path(name).exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No keymap profile named {}", name),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_new_name(name: &str) -> Result<(), io::Error> {
+        Profiles::check_name(name)?;
+
+        if Keymap::keymap_path(name).exists() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("A keymap profile named {} already exists", name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Profile names become file names, so keep them to a single plain path component
+    fn check_name(name: &str) -> Result<(), io::Error> {
+        let trimmed = name.trim();
+        if trimmed.is_empty()
+            || trimmed != name
+            || name.starts_with('.')
+            || name.contains(['/', '\\', ':'])
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid keymap profile name: {:?}", name),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::button_codes::default_ranges;
+    use crate::keymap::Key;
+    use crate::test_support::{shared_settings, use_temp_data_dir};
+    use crate::validation::MAX_DELAY_MS;
+
+    fn button(index: i32) -> ProgrammableKeys {
+        ProgrammableKeys::get_from_index(index)
+    }
+
+    /// Saves a blank profile and makes it the active one
+    fn profiles_with_active(name: &str) -> Profiles {
+        use_temp_data_dir();
+        Keymap::save_to_file(Keymap::new(name.to_string(), 2), 0).unwrap();
+
+        let settings = shared_settings();
+        Profiles::new(
+            Arc::new(Mutex::new(Keymap::new(name.to_string(), 2))),
+            Arc::new(Mutex::new(LayerState::load(name, &settings))),
+            EventSink::new(),
+            ButtonCodes::new(default_ranges()),
+            settings,
+            Vec::new(),
+        )
+    }
+
+    fn stored(name: &str) -> Keymap {
+        Keymap::load_from_file(name.to_string(), &default_ranges()).unwrap()
+    }
+
+    #[test]
+    fn setting_actions_saves_the_active_keymap() {
+        let profiles = profiles_with_active("set-actions");
+        let actions = vec![MacroAction::Tap(Key::KeyA)];
+
+        profiles
+            .set_button_actions(&button(2), actions.clone())
+            .unwrap();
+
+        let live = profiles.keymap.lock().unwrap().clone();
+        assert_eq!(live.buttons[1].actions, actions);
+        assert_eq!(stored("set-actions"), live);
+    }
+
+    #[test]
+    fn actions_that_break_the_keymap_are_refused() {
+        let profiles = profiles_with_active("set-broken-actions");
+        let before = profiles.keymap.lock().unwrap().clone();
+        let actions = vec![MacroAction::Delay(MAX_DELAY_MS + 1)];
+
+        let err = profiles
+            .set_button_actions(&button(1), actions.clone())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // neither the live keymap nor its file changed
+        assert_eq!(*profiles.keymap.lock().unwrap(), before);
+        assert_eq!(stored("set-broken-actions"), before);
+
+        let err = Profiles::set_stored_button_actions(
+            "set-broken-actions",
+            &button(1),
+            actions,
+            &default_ranges(),
+            0,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(stored("set-broken-actions"), before);
+    }
+
+    #[test]
+    fn setting_actions_in_a_stored_profile_leaves_the_active_one() {
+        let profiles = profiles_with_active("set-active");
+        Keymap::save_to_file(Keymap::new("set-stored".to_string(), 1), 0).unwrap();
+        let actions = vec![MacroAction::Print("stored".to_string())];
+
+        profiles
+            .set_profile_button_actions("set-stored", &button(3), actions.clone())
+            .unwrap();
+
+        let keymap = stored("set-stored");
+        assert_eq!(keymap.buttons.len(), 2);
+        assert_eq!(keymap.buttons[1].programmable_key, button(3));
+        assert_eq!(keymap.buttons[1].actions, actions);
+        assert_eq!(
+            *profiles.keymap.lock().unwrap(),
+            Keymap::new("set-active".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn saving_keymap_for_another_profile_needs_that_profile() {
+        let profiles = profiles_with_active("save-active");
+        Keymap::save_to_file(Keymap::new("save-other".to_string(), 1), 0).unwrap();
+
+        let mut other = Keymap::new("save-other".to_string(), 1);
+        other.buttons[0].actions = vec![MacroAction::Tap(Key::KeyB)];
+        profiles.save_keymap(other.clone()).unwrap();
+        assert_eq!(stored("save-other"), other);
+
+        let err = profiles
+            .save_keymap(Keymap::new("save-missing".to_string(), 1))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!Keymap::keymap_path("save-missing").exists());
+
+        let err = profiles
+            .save_keymap(Keymap::new("../save-escaped".to_string(), 1))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(!Keymap::keymap_dir()
+            .join("..")
+            .join("save-escaped.json")
+            .exists());
+
+        // the active keymap is left as it was
+        assert_eq!(
+            *profiles.keymap.lock().unwrap(),
+            Keymap::new("save-active".to_string(), 2)
+        );
+    }
+}
@@ -1,345 +1,571 @@
 use std::cmp::PartialEq;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::fmt;
 use std::time::Duration;
 
-use enigo::{Enigo, Keyboard};
 use serde::{Deserialize, Serialize};
 
-use crate::keymap::{Key, Keymap, MacroAction, MacroKey};
+use crate::bindings::{KeyBinding, Modifier};
+use crate::button_codes;
+use crate::button_codes::CodeRange;
+use crate::keymap::{Key, MacroAction, MacroKey, MacroType, MouseButton, Trigger};
+use crate::macro_executor::{CancelToken, MacroExecutor};
+use crate::macro_script;
+use crate::output::{Axis, Direction, OutputBackend, OutputError};
 
-/// handles all the actions bound to a macro key
-fn handle_macro_key(macro_key: MacroKey, mut simulator: Enigo) {
-    for action in macro_key.actions {
-        match action {
-            MacroAction::Print(string) => simulator.text(&*string).unwrap(),
-            MacroAction::Tap(key) => simulator
-                .key(match_key_to_enigo(key), enigo::Direction::Click)
-                .unwrap(),
-            MacroAction::Press(key) => simulator
-                .key(match_key_to_enigo(key), enigo::Direction::Press)
-                .unwrap(),
-            MacroAction::Release(key) => simulator
-                .key(match_key_to_enigo(key), enigo::Direction::Release)
-                .unwrap(),
-            MacroAction::Delay(ms) => thread::sleep(Duration::from_millis(ms)),
-            MacroAction::None => {}
+/// handles all the actions bound to a macro key, stopping early if the job is cancelled
+/// and at the first input that can't be sent
+pub fn handle_macro_key(
+    macro_key: &MacroKey,
+    output: &mut dyn OutputBackend,
+    token: &CancelToken,
+) -> Result<(), OutputError> {
+    let mut held_keys: Vec<Key> = Vec::new();
+    let mut held_buttons: Vec<MouseButton> = Vec::new();
+
+    let result = run_macro(macro_key, output, token, &mut held_keys, &mut held_buttons);
+
+    // release anything the macro was holding when it was stopped or failed
+    if token.is_cancelled() || result.is_err() {
+        for key in held_keys {
+            if let Err(err) = output.key(&key, Direction::Release) {
+                eprintln!("Failed to release held key: {}", err);
+            }
+        }
+
+        for button in held_buttons {
+            if let Err(err) = output.mouse_button(button, Direction::Release) {
+                eprintln!("Failed to release held mouse button: {}", err);
+            }
         }
     }
+
+    result
 }
 
-fn match_key_to_enigo(key: Key) -> enigo::Key {
-    match key {
-        Key::Alt => enigo::Key::Alt,
-        Key::Backspace => enigo::Key::Backspace,
-        Key::CapsLock => enigo::Key::CapsLock,
-        Key::ControlLeft => enigo::Key::LControl,
-        Key::ControlRight => enigo::Key::RControl,
-        Key::Delete => enigo::Key::Delete,
-        Key::DownArrow => enigo::Key::DownArrow,
-        Key::End => enigo::Key::End,
-        Key::Escape => enigo::Key::Escape,
-        Key::F1 => enigo::Key::F1,
-        Key::F10 => enigo::Key::F10,
-        Key::F11 => enigo::Key::F11,
-        Key::F12 => enigo::Key::F12,
-        Key::F2 => enigo::Key::F2,
-        Key::F3 => enigo::Key::F3,
-        Key::F4 => enigo::Key::F4,
-        Key::F5 => enigo::Key::F5,
-        Key::F6 => enigo::Key::F6,
-        Key::F7 => enigo::Key::F7,
-        Key::F8 => enigo::Key::F8,
-        Key::F9 => enigo::Key::F9,
-        Key::Home => enigo::Key::Home,
-        Key::LeftArrow => enigo::Key::LeftArrow,
-        Key::MetaLeft => enigo::Key::Meta,
-        Key::MetaRight => enigo::Key::Meta,
-        Key::PageDown => enigo::Key::PageDown,
-        Key::PageUp => enigo::Key::PageUp,
-        Key::Return => enigo::Key::Return,
-        Key::RightArrow => enigo::Key::RightArrow,
-        Key::ShiftLeft => enigo::Key::LShift,
-        Key::ShiftRight => enigo::Key::RShift,
-        Key::Space => enigo::Key::Space,
-        Key::Tab => enigo::Key::Tab,
-        Key::UpArrow => enigo::Key::UpArrow,
-        Key::PrintScreen => enigo::Key::Print,
-        Key::Pause => enigo::Key::Pause,
-        Key::NumLock => enigo::Key::Numlock,
-        Key::BackQuote => enigo::Key::Unicode('\''),
-        Key::Num1 => enigo::Key::Unicode('1'),
-        Key::Num2 => enigo::Key::Unicode('2'),
-        Key::Num3 => enigo::Key::Unicode('3'),
-        Key::Num4 => enigo::Key::Unicode('4'),
-        Key::Num5 => enigo::Key::Unicode('5'),
-        Key::Num6 => enigo::Key::Unicode('6'),
-        Key::Num7 => enigo::Key::Unicode('7'),
-        Key::Num8 => enigo::Key::Unicode('8'),
-        Key::Num9 => enigo::Key::Unicode('9'),
-        Key::Num0 => enigo::Key::Unicode('0'),
-        Key::Minus => enigo::Key::Unicode('-'),
-        Key::Equal => enigo::Key::Unicode('='),
-        Key::KeyQ => enigo::Key::Unicode('q'),
-        Key::KeyW => enigo::Key::Unicode('w'),
-        Key::KeyE => enigo::Key::Unicode('e'),
-        Key::KeyR => enigo::Key::Unicode('r'),
-        Key::KeyT => enigo::Key::Unicode('t'),
-        Key::KeyY => enigo::Key::Unicode('y'),
-        Key::KeyU => enigo::Key::Unicode('u'),
-        Key::KeyI => enigo::Key::Unicode('i'),
-        Key::KeyO => enigo::Key::Unicode('o'),
-        Key::KeyP => enigo::Key::Unicode('p'),
-        Key::LeftBracket => enigo::Key::Unicode('['),
-        Key::RightBracket => enigo::Key::Unicode(']'),
-        Key::KeyA => enigo::Key::Unicode('a'),
-        Key::KeyS => enigo::Key::Unicode('s'),
-        Key::KeyD => enigo::Key::Unicode('d'),
-        Key::KeyF => enigo::Key::Unicode('f'),
-        Key::KeyG => enigo::Key::Unicode('g'),
-        Key::KeyH => enigo::Key::Unicode('h'),
-        Key::KeyJ => enigo::Key::Unicode('j'),
-        Key::KeyK => enigo::Key::Unicode('k'),
-        Key::KeyL => enigo::Key::Unicode('l'),
-        Key::SemiColon => enigo::Key::Unicode(';'),
-        Key::Quote => enigo::Key::Unicode('\''),
-        Key::BackSlash => enigo::Key::Unicode('\\'),
-        Key::IntlBackslash => enigo::Key::Unicode('\\'),
-        Key::KeyZ => enigo::Key::Unicode('z'),
-        Key::KeyX => enigo::Key::Unicode('x'),
-        Key::KeyC => enigo::Key::Unicode('c'),
-        Key::KeyV => enigo::Key::Unicode('v'),
-        Key::KeyB => enigo::Key::Unicode('b'),
-        Key::KeyN => enigo::Key::Unicode('n'),
-        Key::KeyM => enigo::Key::Unicode('m'),
-        Key::Comma => enigo::Key::Unicode(','),
-        Key::Dot => enigo::Key::Unicode('.'),
-        Key::Slash => enigo::Key::Unicode('/'),
-        Key::Insert => enigo::Key::Insert,
-        Key::KpPlus => enigo::Key::Unicode('+'),
-        Key::KpMultiply => enigo::Key::Unicode('*'),
-        Key::Unknown(num) => enigo::Key::Other(num.into()),
+/// Runs a macro's actions as many times as its macro type asks for
+fn run_macro(
+    macro_key: &MacroKey,
+    output: &mut dyn OutputBackend,
+    token: &CancelToken,
+    held_keys: &mut Vec<Key>,
+    held_buttons: &mut Vec<MouseButton>,
+) -> Result<(), OutputError> {
+    if macro_key.macro_type.loops_until_stopped() {
+        // avoid spinning if the macro has no delays of its own
+        let has_delay = macro_key
+            .actions
+            .iter()
+            .any(|action| matches!(action, MacroAction::Delay(_)));
+
+        while !token.is_cancelled() {
+            run_actions(&macro_key.actions, output, token, held_keys, held_buttons)?;
+
+            if !has_delay {
+                token.sleep(Duration::from_millis(1));
+            }
+        }
+    } else {
+        let runs = match macro_key.macro_type {
+            MacroType::Repeat(count) => count,
+            _ => 1,
+        };
+
+        for _ in 0..runs {
+            run_actions(&macro_key.actions, output, token, held_keys, held_buttons)?;
+        }
     }
+
+    Ok(())
 }
 
-// https://docs.qmk.fm/#/feature_programmable_button
-#[cfg(target_os = "linux")]
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
-pub enum ProgrammableKeys {
-    MACROUNKNOWN = 0,
-    MACRO1 = 656,
-    MACRO2 = 657,
-    MACRO3 = 658,
-    MACRO4 = 659,
-    MACRO5 = 660,
-    MACRO6 = 661,
-    MACRO7 = 662,
-    MACRO8 = 663,
-    MACRO9 = 664,
-    MACRO10 = 665,
-    MACRO11 = 666,
-    MACRO12 = 667,
-    MACRO13 = 668,
-    MACRO14 = 669,
-    MACRO15 = 670,
-    MACRO16 = 671,
-    MACRO17 = 672,
-    MACRO18 = 673,
-    MACRO19 = 674,
-    MACRO20 = 675,
-    MACRO21 = 676,
-    MACRO22 = 677,
-    MACRO23 = 678,
-    MACRO24 = 679,
-    MACRO25 = 680,
-    MACRO26 = 681,
-    MACRO27 = 682,
-    MACRO28 = 683,
-    MACRO29 = 684,
-    MACRO30 = 685,
-    MACRO31 = 686,
-    MACRO32 = 687,
+/// Runs a macro's action list once, stopping early if the job is cancelled.
+/// Keys and mouse buttons that are left pressed are tracked in `held_keys` and `held_buttons`.
+fn run_actions(
+    actions: &[MacroAction],
+    output: &mut dyn OutputBackend,
+    token: &CancelToken,
+    held_keys: &mut Vec<Key>,
+    held_buttons: &mut Vec<MouseButton>,
+) -> Result<(), OutputError> {
+    for action in actions {
+        if token.is_cancelled() {
+            return Ok(());
+        }
+
+        match action {
+            MacroAction::Print(string) => output.text(string)?,
+            MacroAction::Tap(key) => output.key(key, Direction::Click)?,
+            MacroAction::Press(key) => {
+                output.key(key, Direction::Press)?;
+                if !held_keys.contains(key) {
+                    held_keys.push(key.clone());
+                }
+            }
+            MacroAction::Release(key) => {
+                output.key(key, Direction::Release)?;
+                held_keys.retain(|held| held != key);
+            }
+            MacroAction::Delay(ms) => output.delay(Duration::from_millis(*ms), token),
+            MacroAction::MouseMoveTo { x, y } => output.move_mouse_to(*x, *y)?,
+            MacroAction::MouseMoveBy { x, y } => output.move_mouse_by(*x, *y)?,
+            MacroAction::MousePress(button) => {
+                output.mouse_button(*button, Direction::Press)?;
+                if !held_buttons.contains(button) {
+                    held_buttons.push(*button);
+                }
+            }
+            MacroAction::MouseRelease(button) => {
+                output.mouse_button(*button, Direction::Release)?;
+                held_buttons.retain(|held| held != button);
+            }
+            MacroAction::MouseClick(button) => output.mouse_button(*button, Direction::Click)?,
+            MacroAction::ScrollVertical(steps) => output.scroll(*steps, Axis::Vertical)?,
+            MacroAction::ScrollHorizontal(steps) => output.scroll(*steps, Axis::Horizontal)?,
+            MacroAction::None => {}
+            // handled by the key handler before the macro is started
+            MacroAction::LayerMomentary(_)
+            | MacroAction::LayerToggle(_)
+            | MacroAction::LayerOneShot(_) => {}
+        }
+    }
+
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+/// A macro button, either a programmable button known by its label or a
+/// regular key bound as one. Keymaps store buttons as their label, like "MACRO5".
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+#[serde(from = "KeyRepr", into = "KeyRepr")]
 pub enum ProgrammableKeys {
-    MACROUNKNOWN = 0,
-    MACRO1 = 261,
-    MACRO2 = 517,
-    MACRO3 = 1029,
-    MACRO4 = 2053,
-    MACRO5 = 4101,
-    MACRO6 = 8197,
-    MACRO7 = 16389,
-    MACRO8 = 32773,
-    MACRO9 = 65541,
-    MACRO10 = 131077,
-    MACRO11 = 262149,
-    MACRO12 = 200,
-    MACRO13 = 201,
-    MACRO14 = 202,
-    MACRO15 = 203,
-    MACRO16 = 204,
-    MACRO17 = 205,
-    MACRO18 = 206,
-    MACRO19 = 207,
-    MACRO20 = 134217733,
-    MACRO21 = 208,
-    MACRO22 = 209,
-    MACRO23 = 210,
-    MACRO24 = 211,
-    MACRO25 = 212,
-    MACRO26 = 213,
-    MACRO27 = 214,
-    MACRO28 = 215,
-    MACRO29 = 216,
-    MACRO30 = 217,
-    MACRO31 = 218,
-    MACRO32 = 219,
+    /// the button code table says which codes send each label
+    Button(String),
+    /// a regular key or chord bound as a macro button
+    Binding(Box<KeyBinding>),
 }
 
-impl ProgrammableKeys {
-    #[cfg(target_os = "linux")]
-    pub fn from_u32(value: u32) -> ProgrammableKeys {
-        match value {
-            656 => ProgrammableKeys::MACRO1,
-            657 => ProgrammableKeys::MACRO2,
-            658 => ProgrammableKeys::MACRO3,
-            659 => ProgrammableKeys::MACRO4,
-            660 => ProgrammableKeys::MACRO5,
-            661 => ProgrammableKeys::MACRO6,
-            662 => ProgrammableKeys::MACRO7,
-            663 => ProgrammableKeys::MACRO8,
-            664 => ProgrammableKeys::MACRO9,
-            665 => ProgrammableKeys::MACRO10,
-            666 => ProgrammableKeys::MACRO11,
-            667 => ProgrammableKeys::MACRO12,
-            668 => ProgrammableKeys::MACRO13,
-            669 => ProgrammableKeys::MACRO14,
-            670 => ProgrammableKeys::MACRO15,
-            671 => ProgrammableKeys::MACRO16,
-            672 => ProgrammableKeys::MACRO17,
-            673 => ProgrammableKeys::MACRO18,
-            674 => ProgrammableKeys::MACRO19,
-            675 => ProgrammableKeys::MACRO20,
-            676 => ProgrammableKeys::MACRO21,
-            677 => ProgrammableKeys::MACRO22,
-            678 => ProgrammableKeys::MACRO23,
-            679 => ProgrammableKeys::MACRO24,
-            680 => ProgrammableKeys::MACRO25,
-            681 => ProgrammableKeys::MACRO26,
-            682 => ProgrammableKeys::MACRO27,
-            683 => ProgrammableKeys::MACRO28,
-            684 => ProgrammableKeys::MACRO29,
-            685 => ProgrammableKeys::MACRO30,
-            686 => ProgrammableKeys::MACRO31,
-            687 => ProgrammableKeys::MACRO32,
-            _ => ProgrammableKeys::MACROUNKNOWN,
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum KeyRepr {
+    Button(String),
+    Tagged(TaggedKey),
+}
+
+#[derive(Serialize, Deserialize)]
+enum TaggedKey {
+    Binding(Box<KeyBinding>),
+}
+
+impl From<KeyRepr> for ProgrammableKeys {
+    fn from(repr: KeyRepr) -> Self {
+        match repr {
+            KeyRepr::Button(label) => ProgrammableKeys::Button(label),
+            KeyRepr::Tagged(TaggedKey::Binding(binding)) => ProgrammableKeys::Binding(binding),
+        }
+    }
+}
+
+impl From<ProgrammableKeys> for KeyRepr {
+    fn from(key: ProgrammableKeys) -> Self {
+        match key {
+            ProgrammableKeys::Button(label) => KeyRepr::Button(label),
+            ProgrammableKeys::Binding(binding) => KeyRepr::Tagged(TaggedKey::Binding(binding)),
         }
     }
+}
 
-    #[cfg(target_os = "windows")]
-    pub fn from_u32(value: u32) -> ProgrammableKeys {
-        match value {
-            261 => ProgrammableKeys::MACRO1,
-            517 => ProgrammableKeys::MACRO2,
-            1029 => ProgrammableKeys::MACRO3,
-            2053 => ProgrammableKeys::MACRO4,
-            4101 => ProgrammableKeys::MACRO5,
-            8197 => ProgrammableKeys::MACRO6,
-            16389 => ProgrammableKeys::MACRO7,
-            32773 => ProgrammableKeys::MACRO8,
-            65541 => ProgrammableKeys::MACRO9,
-            131077 => ProgrammableKeys::MACRO10,
-            262149 => ProgrammableKeys::MACRO11,
-            524293 => ProgrammableKeys::MACRO12,
-            1048581 => ProgrammableKeys::MACRO13,
-            2097157 => ProgrammableKeys::MACRO14,
-            4194309 => ProgrammableKeys::MACRO15,
-            8388613 => ProgrammableKeys::MACRO16,
-            16777221 => ProgrammableKeys::MACRO17,
-            33554437 => ProgrammableKeys::MACRO18,
-            207 => ProgrammableKeys::MACRO19,
-            134217733 => ProgrammableKeys::MACRO20,
-            208 => ProgrammableKeys::MACRO21,
-            209 => ProgrammableKeys::MACRO22,
-            210 => ProgrammableKeys::MACRO23,
-            211 => ProgrammableKeys::MACRO24,
-            212 => ProgrammableKeys::MACRO25,
-            213 => ProgrammableKeys::MACRO26,
-            214 => ProgrammableKeys::MACRO27,
-            215 => ProgrammableKeys::MACRO28,
-            216 => ProgrammableKeys::MACRO29,
-            217 => ProgrammableKeys::MACRO30,
-            218 => ProgrammableKeys::MACRO31,
-            219 => ProgrammableKeys::MACRO32,
-            _ => ProgrammableKeys::MACROUNKNOWN,
+impl fmt::Display for ProgrammableKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgrammableKeys::Button(label) => write!(f, "{}", label),
+            ProgrammableKeys::Binding(binding) => {
+                for modifier in &binding.modifiers {
+                    write!(f, "{:?}+", modifier)?;
+                }
+                write!(f, "{:?}", binding.key)
+            }
         }
     }
+}
 
+impl ProgrammableKeys {
+    /// The default label of a programmable button, counting from 1
     pub fn get_from_index(index: i32) -> ProgrammableKeys {
-        match index {
-            1 => ProgrammableKeys::MACRO1,
-            2 => ProgrammableKeys::MACRO2,
-            3 => ProgrammableKeys::MACRO3,
-            4 => ProgrammableKeys::MACRO4,
-            5 => ProgrammableKeys::MACRO5,
-            6 => ProgrammableKeys::MACRO6,
-            7 => ProgrammableKeys::MACRO7,
-            8 => ProgrammableKeys::MACRO8,
-            9 => ProgrammableKeys::MACRO9,
-            10 => ProgrammableKeys::MACRO10,
-            11 => ProgrammableKeys::MACRO11,
-            12 => ProgrammableKeys::MACRO12,
-            13 => ProgrammableKeys::MACRO13,
-            14 => ProgrammableKeys::MACRO14,
-            15 => ProgrammableKeys::MACRO15,
-            16 => ProgrammableKeys::MACRO16,
-            17 => ProgrammableKeys::MACRO17,
-            18 => ProgrammableKeys::MACRO18,
-            19 => ProgrammableKeys::MACRO19,
-            20 => ProgrammableKeys::MACRO20,
-            21 => ProgrammableKeys::MACRO21,
-            22 => ProgrammableKeys::MACRO22,
-            23 => ProgrammableKeys::MACRO23,
-            24 => ProgrammableKeys::MACRO24,
-            25 => ProgrammableKeys::MACRO25,
-            26 => ProgrammableKeys::MACRO26,
-            27 => ProgrammableKeys::MACRO27,
-            28 => ProgrammableKeys::MACRO28,
-            29 => ProgrammableKeys::MACRO29,
-            30 => ProgrammableKeys::MACRO30,
-            31 => ProgrammableKeys::MACRO31,
-            32 => ProgrammableKeys::MACRO32,
-            _ => ProgrammableKeys::MACROUNKNOWN,
+        ProgrammableKeys::Button(format!("MACRO{}", index))
+    }
+
+    /// Reads a button the way it is displayed, a label like `MACRO5` or a key
+    /// binding like `Ctrl+Shift+F12`. Bindings for one device can't be written
+    /// this way. Anything that isn't a binding is taken as a label.
+    pub fn parse(text: &str, ranges: &[CodeRange]) -> ProgrammableKeys {
+        if button_codes::is_known_label(ranges, text) {
+            return ProgrammableKeys::Button(text.to_string());
+        }
+
+        let mut parts: Vec<&str> = text.split('+').collect();
+        let key = parts.pop().and_then(macro_script::parse_key);
+        let modifiers: Option<Vec<Modifier>> = parts
+            .into_iter()
+            .map(|part| match part.to_lowercase().as_str() {
+                "ctrl" | "control" => Some(Modifier::Ctrl),
+                "shift" => Some(Modifier::Shift),
+                "alt" => Some(Modifier::Alt),
+                "meta" | "super" | "win" | "cmd" => Some(Modifier::Meta),
+                _ => None,
+            })
+            .collect();
+
+        match (key, modifiers) {
+            (Some(key), Some(modifiers)) => ProgrammableKeys::Binding(Box::new(KeyBinding {
+                key,
+                modifiers,
+                device: None,
+            })),
+            _ => ProgrammableKeys::Button(text.to_string()),
         }
     }
 
-    pub fn process_keys(key: ProgrammableKeys, keymap_arc: &Arc<Mutex<Keymap>>, simulator: Enigo) {
-        let borrowed_map = match keymap_arc.lock() {
-            Ok(keymap) => Some(keymap),
-            Err(err) => {
-                eprintln!("Error retrieving keymap lock: {}", err);
-                None
-            }
+    /// Runs the actions a macro key binds to a button event.
+    /// Layer actions are left to the key handler.
+    pub fn process_keys(macro_key: &MacroKey, trigger: Trigger, executor: &MacroExecutor) {
+        let actions: Vec<MacroAction> = match macro_key.actions_for(&trigger) {
+            None => return,
+            Some(actions) => actions
+                .iter()
+                .filter(|action| !action.is_layer_action())
+                .cloned()
+                .collect(),
+        };
+
+        if actions.is_empty() {
+            return;
+        }
+
+        // only the press actions follow the macro type, the others run once
+        let macro_type = match trigger {
+            Trigger::Press => macro_key.macro_type.clone(),
+            _ => MacroType::Once,
         };
 
-        match borrowed_map {
-            Some(keymap) => {
-                let matching_key = match keymap
-                    .buttons
-                    .clone()
-                    .into_iter()
-                    .find(|k| k.programmable_key == key)
-                {
-                    None => return,
-                    Some(key) => key,
-                };
-
-                handle_macro_key(matching_key, simulator);
+        executor.trigger(
+            MacroKey {
+                macro_type,
+                actions,
+                ..macro_key.clone()
+            },
+            trigger,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{OutputEvent, RecordingBackend};
+
+    fn run(actions: Vec<MacroAction>) -> Vec<OutputEvent> {
+        let mut macro_key = MacroKey::new(ProgrammableKeys::Button("MACRO1".to_string()));
+        macro_key.actions = actions;
+
+        let mut output = RecordingBackend::new();
+        handle_macro_key(&macro_key, &mut output, &CancelToken::default()).unwrap();
+        output.events
+    }
+
+    /// Records like `RecordingBackend`, but cancels the job during delays and
+    /// fails to type text, for testing how a macro stops
+    #[derive(Default)]
+    struct InterruptingBackend {
+        recording: RecordingBackend,
+        cancel_on_delay: bool,
+        fail_on_text: bool,
+    }
+
+    impl OutputBackend for InterruptingBackend {
+        fn key(&mut self, key: &Key, direction: Direction) -> Result<(), OutputError> {
+            self.recording.key(key, direction)
+        }
+
+        fn text(&mut self, text: &str) -> Result<(), OutputError> {
+            if self.fail_on_text {
+                return Err(OutputError::new("no display".to_string()));
             }
-            None => (),
+            self.recording.text(text)
+        }
+
+        fn mouse_button(
+            &mut self,
+            button: MouseButton,
+            direction: Direction,
+        ) -> Result<(), OutputError> {
+            self.recording.mouse_button(button, direction)
         }
+
+        fn move_mouse_to(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+            self.recording.move_mouse_to(x, y)
+        }
+
+        fn move_mouse_by(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+            self.recording.move_mouse_by(x, y)
+        }
+
+        fn scroll(&mut self, steps: i32, axis: Axis) -> Result<(), OutputError> {
+            self.recording.scroll(steps, axis)
+        }
+
+        fn delay(&mut self, duration: Duration, token: &CancelToken) {
+            self.recording.delay(duration, token);
+            if self.cancel_on_delay {
+                token.cancel();
+            }
+        }
+    }
+
+    fn key_event(key: Key, direction: Direction) -> OutputEvent {
+        OutputEvent::Key { key, direction }
+    }
+
+    #[test]
+    fn taps_presses_releases_and_waits() {
+        let events = run(vec![
+            MacroAction::Tap(Key::KeyA),
+            MacroAction::Press(Key::ShiftLeft),
+            MacroAction::Delay(30),
+            MacroAction::Tap(Key::KeyB),
+            MacroAction::Release(Key::ShiftLeft),
+            MacroAction::Print("hi".to_string()),
+            MacroAction::None,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                key_event(Key::KeyA, Direction::Click),
+                key_event(Key::ShiftLeft, Direction::Press),
+                OutputEvent::Delay { ms: 30 },
+                key_event(Key::KeyB, Direction::Click),
+                key_event(Key::ShiftLeft, Direction::Release),
+                OutputEvent::Text("hi".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_the_actions() {
+        let mut macro_key = MacroKey::new(ProgrammableKeys::Button("MACRO1".to_string()));
+        macro_key.macro_type = MacroType::Repeat(3);
+        macro_key.actions = vec![MacroAction::Tap(Key::KeyA)];
+
+        let mut output = RecordingBackend::new();
+        handle_macro_key(&macro_key, &mut output, &CancelToken::default()).unwrap();
+
+        assert_eq!(
+            output.events,
+            vec![key_event(Key::KeyA, Direction::Click); 3]
+        );
+    }
+
+    #[test]
+    fn cancelling_releases_what_the_macro_held() {
+        let mut macro_key = MacroKey::new(ProgrammableKeys::Button("MACRO1".to_string()));
+        macro_key.actions = vec![
+            MacroAction::Press(Key::ControlLeft),
+            MacroAction::MousePress(MouseButton::Left),
+            MacroAction::Delay(1000),
+            MacroAction::Tap(Key::KeyC),
+            MacroAction::Release(Key::ControlLeft),
+            MacroAction::MouseRelease(MouseButton::Left),
+        ];
+
+        let mut output = InterruptingBackend {
+            cancel_on_delay: true,
+            ..Default::default()
+        };
+        let token = CancelToken::default();
+        handle_macro_key(&macro_key, &mut output, &token).unwrap();
+
+        assert!(token.is_cancelled());
+        assert_eq!(
+            output.recording.events,
+            vec![
+                key_event(Key::ControlLeft, Direction::Press),
+                OutputEvent::MouseButton {
+                    button: MouseButton::Left,
+                    direction: Direction::Press,
+                },
+                OutputEvent::Delay { ms: 1000 },
+                key_event(Key::ControlLeft, Direction::Release),
+                OutputEvent::MouseButton {
+                    button: MouseButton::Left,
+                    direction: Direction::Release,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_finished_macro_leaves_held_keys_alone() {
+        // holding a key past the end of a macro is how press only macros work
+        let events = run(vec![MacroAction::Press(Key::ShiftLeft)]);
+
+        assert_eq!(events, vec![key_event(Key::ShiftLeft, Direction::Press)]);
+    }
+
+    #[test]
+    fn a_backend_error_stops_the_macro_and_is_returned() {
+        let mut macro_key = MacroKey::new(ProgrammableKeys::Button("MACRO1".to_string()));
+        macro_key.actions = vec![
+            MacroAction::Press(Key::ShiftLeft),
+            MacroAction::Print("hello".to_string()),
+            MacroAction::Tap(Key::KeyA),
+        ];
+
+        let mut output = InterruptingBackend {
+            fail_on_text: true,
+            ..Default::default()
+        };
+        let result = handle_macro_key(&macro_key, &mut output, &CancelToken::default());
+
+        assert_eq!(result, Err(OutputError::new("no display".to_string())));
+        assert_eq!(
+            output.recording.events,
+            vec![
+                key_event(Key::ShiftLeft, Direction::Press),
+                key_event(Key::ShiftLeft, Direction::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_the_mouse() {
+        let events = run(vec![
+            MacroAction::MouseMoveTo { x: 640, y: 360 },
+            MacroAction::MouseMoveBy { x: -20, y: 15 },
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                OutputEvent::MouseMoveTo { x: 640, y: 360 },
+                OutputEvent::MouseMoveBy { x: -20, y: 15 },
+            ]
+        );
+    }
+
+    #[test]
+    fn presses_and_releases_mouse_buttons() {
+        let events = run(vec![
+            MacroAction::MousePress(MouseButton::Left),
+            MacroAction::MouseMoveBy { x: 50, y: 0 },
+            MacroAction::MouseRelease(MouseButton::Left),
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                OutputEvent::MouseButton {
+                    button: MouseButton::Left,
+                    direction: Direction::Press,
+                },
+                OutputEvent::MouseMoveBy { x: 50, y: 0 },
+                OutputEvent::MouseButton {
+                    button: MouseButton::Left,
+                    direction: Direction::Release,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn clicks_every_mouse_button() {
+        let buttons = [
+            MouseButton::Left,
+            MouseButton::Right,
+            MouseButton::Middle,
+            MouseButton::Back,
+            MouseButton::Forward,
+        ];
+
+        let events = run(buttons
+            .iter()
+            .copied()
+            .map(MacroAction::MouseClick)
+            .collect());
+
+        let clicks: Vec<OutputEvent> = buttons
+            .iter()
+            .map(|&button| OutputEvent::MouseButton {
+                button,
+                direction: Direction::Click,
+            })
+            .collect();
+        assert_eq!(events, clicks);
+    }
+
+    #[test]
+    fn scrolls_both_axes() {
+        let events = run(vec![
+            MacroAction::ScrollVertical(3),
+            MacroAction::ScrollVertical(-2),
+            MacroAction::ScrollHorizontal(-1),
+            MacroAction::ScrollHorizontal(4),
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                OutputEvent::Scroll {
+                    steps: 3,
+                    axis: Axis::Vertical,
+                },
+                OutputEvent::Scroll {
+                    steps: -2,
+                    axis: Axis::Vertical,
+                },
+                OutputEvent::Scroll {
+                    steps: -1,
+                    axis: Axis::Horizontal,
+                },
+                OutputEvent::Scroll {
+                    steps: 4,
+                    axis: Axis::Horizontal,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn buttons_parse_back_from_how_they_are_shown() {
+        let ranges = crate::button_codes::default_ranges();
+        let binding = ProgrammableKeys::Binding(Box::new(KeyBinding {
+            key: Key::F12,
+            modifiers: vec![Modifier::Ctrl, Modifier::Shift],
+            device: None,
+        }));
+
+        for button in [ProgrammableKeys::get_from_index(5), binding.clone()] {
+            assert_eq!(
+                ProgrammableKeys::parse(&button.to_string(), &ranges),
+                button
+            );
+        }
+        assert_eq!(ProgrammableKeys::parse("ctrl+shift+f12", &ranges), binding);
+        assert_eq!(
+            ProgrammableKeys::parse("a", &ranges),
+            ProgrammableKeys::Binding(Box::new(KeyBinding {
+                key: Key::KeyA,
+                modifiers: Vec::new(),
+                device: None,
+            }))
+        );
+
+        // unknown labels stay labels, validation reports them
+        assert_eq!(
+            ProgrammableKeys::parse("MACRO99", &ranges),
+            ProgrammableKeys::Button("MACRO99".to_string())
+        );
+        assert_eq!(
+            ProgrammableKeys::parse("Hyper+F12", &ranges),
+            ProgrammableKeys::Button("Hyper+F12".to_string())
+        );
     }
 }
@@ -0,0 +1,355 @@
+// only the linux listener can record so far
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::key_queue::KeyState;
+use crate::keymap::{Key, MacroAction};
+use crate::programmable_keys::ProgrammableKeys;
+
+/// A keyboard key going down or up while a macro was being recorded
+#[derive(Debug, Clone)]
+pub struct RecordedKey {
+    pub key: Key,
+    pub state: KeyState,
+    pub timestamp: Instant,
+}
+
+struct Recording {
+    button: ProgrammableKeys,
+    keys: Vec<RecordedKey>,
+}
+
+/// How a recording is turned into macro actions
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RecordOptions {
+    /// leave out the pauses between keys
+    pub strip_delays: bool,
+    /// round pauses to the nearest multiple of this many milliseconds
+    pub quantize_ms: Option<u64>,
+    /// turn a press followed straight away by its release into a `Tap`
+    pub collapse_taps: bool,
+}
+
+/// Captures the keyboard while a macro is being recorded for a button. The
+/// listener feeds it every key that isn't a macro button.
+#[derive(Clone, Default)]
+pub struct MacroRecorder {
+    recording: Arc<Mutex<Option<Recording>>>,
+}
+
+impl MacroRecorder {
+    pub fn new() -> MacroRecorder {
+        MacroRecorder::default()
+    }
+
+    /// Starts recording a macro for a button, only one recording can run at a time
+    pub fn start(&self, button: ProgrammableKeys) -> Result<(), io::Error> {
+        let mut recording = match self.recording.lock() {
+            Ok(recording) => recording,
+            Err(_) => {
+                panic!("Failed to acquire macro recorder lock")
+            }
+        };
+
+        if let Some(current) = recording.as_ref() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Already recording a macro for {:?}", current.button),
+            ));
+        }
+
+        println!("Recording a macro for {:?}", button);
+        *recording = Some(Recording {
+            button,
+            keys: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Stops recording, returning the button it was for and the keys it caught
+    pub fn stop(&self) -> Option<(ProgrammableKeys, Vec<RecordedKey>)> {
+        match self.recording.lock() {
+            Ok(mut recording) => recording
+                .take()
+                .map(|recording| (recording.button, recording.keys)),
+            Err(_) => {
+                panic!("Failed to acquire macro recorder lock")
+            }
+        }
+    }
+
+    /// Adds a key event from the listener, if a recording is running
+    pub fn record(&self, key: Key, state: KeyState) {
+        match self.recording.lock() {
+            Ok(mut recording) => {
+                if let Some(recording) = recording.as_mut() {
+                    // a button bound to a regular key never ends up in its own macro
+                    if let ProgrammableKeys::Binding(binding) = &recording.button {
+                        if binding.key == key {
+                            return;
+                        }
+                    }
+
+                    recording.keys.push(RecordedKey {
+                        key,
+                        state,
+                        timestamp: Instant::now(),
+                    });
+                }
+            }
+            Err(err) => eprintln!("Error retrieving macro recorder lock: {}", err),
+        }
+    }
+}
+
+/// Turns recorded keys into Press, Release and Delay actions
+pub fn to_actions(keys: &[RecordedKey], options: &RecordOptions) -> Vec<MacroAction> {
+    let mut actions = Vec::new();
+    let mut held: Vec<Key> = Vec::new();
+    let mut previous: Option<Instant> = None;
+
+    for recorded in keys {
+        let action = match recorded.state {
+            KeyState::Pressed => {
+                held.push(recorded.key.clone());
+                MacroAction::Press(recorded.key.clone())
+            }
+            // keys that were already down when recording started
+            KeyState::Released if !held.contains(&recorded.key) => continue,
+            KeyState::Released => {
+                held.retain(|key| *key != recorded.key);
+                MacroAction::Release(recorded.key.clone())
+            }
+        };
+
+        if let Some(previous) = previous {
+            let delay_ms = recorded.timestamp.duration_since(previous).as_millis() as u64;
+            let delay_ms = match options.quantize_ms {
+                Some(step) if step > 0 => (delay_ms + step / 2) / step * step,
+                _ => delay_ms,
+            };
+
+            if !options.strip_delays && delay_ms > 0 {
+                actions.push(MacroAction::Delay(delay_ms));
+            }
+        }
+        previous = Some(recorded.timestamp);
+
+        actions.push(action);
+    }
+
+    // keys still down when recording stopped would otherwise be left stuck
+    actions.extend(held.into_iter().map(MacroAction::Release));
+
+    if options.collapse_taps {
+        collapse_taps(actions)
+    } else {
+        actions
+    }
+}
+
+/// Replaces each press that is directly followed by its release with a tap,
+/// dropping how long the key was held
+fn collapse_taps(actions: Vec<MacroAction>) -> Vec<MacroAction> {
+    let mut collapsed = Vec::with_capacity(actions.len());
+    let mut index = 0;
+
+    while index < actions.len() {
+        if let MacroAction::Press(key) = &actions[index] {
+            let release = match actions.get(index + 1) {
+                Some(MacroAction::Delay(_)) => index + 2,
+                _ => index + 1,
+            };
+
+            if actions.get(release) == Some(&MacroAction::Release(key.clone())) {
+                collapsed.push(MacroAction::Tap(key.clone()));
+                index = release + 1;
+                continue;
+            }
+        }
+
+        collapsed.push(actions[index].clone());
+        index += 1;
+    }
+
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::bindings::KeyBinding;
+
+    /// Keys recorded at these many milliseconds after the start
+    fn recorded(keys: &[(Key, KeyState, u64)]) -> Vec<RecordedKey> {
+        let start = Instant::now();
+        keys.iter()
+            .map(|(key, state, ms)| RecordedKey {
+                key: key.clone(),
+                state: *state,
+                timestamp: start + Duration::from_millis(*ms),
+            })
+            .collect()
+    }
+
+    fn press(key: Key, ms: u64) -> (Key, KeyState, u64) {
+        (key, KeyState::Pressed, ms)
+    }
+
+    fn release(key: Key, ms: u64) -> (Key, KeyState, u64) {
+        (key, KeyState::Released, ms)
+    }
+
+    #[test]
+    fn keys_become_presses_releases_and_delays() {
+        let keys = recorded(&[press(Key::KeyA, 0), release(Key::KeyA, 40)]);
+
+        assert_eq!(
+            to_actions(&keys, &RecordOptions::default()),
+            vec![
+                MacroAction::Press(Key::KeyA),
+                MacroAction::Delay(40),
+                MacroAction::Release(Key::KeyA),
+            ]
+        );
+    }
+
+    #[test]
+    fn press_and_release_collapse_into_a_tap() {
+        let keys = recorded(&[
+            press(Key::KeyA, 0),
+            release(Key::KeyA, 40),
+            press(Key::KeyB, 100),
+            release(Key::KeyB, 100),
+        ]);
+        let options = RecordOptions {
+            collapse_taps: true,
+            ..RecordOptions::default()
+        };
+
+        assert_eq!(
+            to_actions(&keys, &options),
+            vec![
+                MacroAction::Tap(Key::KeyA),
+                MacroAction::Delay(60),
+                MacroAction::Tap(Key::KeyB),
+            ]
+        );
+    }
+
+    #[test]
+    fn delays_round_to_the_quantum_and_zero_delays_are_dropped() {
+        let keys = recorded(&[
+            press(Key::KeyA, 0),
+            release(Key::KeyA, 4),
+            press(Key::KeyB, 30),
+            release(Key::KeyB, 30),
+            press(Key::KeyC, 45),
+        ]);
+        let options = RecordOptions {
+            quantize_ms: Some(10),
+            ..RecordOptions::default()
+        };
+
+        assert_eq!(
+            to_actions(&keys, &options),
+            vec![
+                MacroAction::Press(Key::KeyA),
+                MacroAction::Release(Key::KeyA),
+                MacroAction::Delay(30),
+                MacroAction::Press(Key::KeyB),
+                MacroAction::Release(Key::KeyB),
+                MacroAction::Delay(20),
+                MacroAction::Press(Key::KeyC),
+                MacroAction::Release(Key::KeyC),
+            ]
+        );
+
+        let stripped = RecordOptions {
+            strip_delays: true,
+            ..RecordOptions::default()
+        };
+        assert!(!to_actions(&keys, &stripped)
+            .iter()
+            .any(|action| matches!(action, MacroAction::Delay(_))));
+    }
+
+    #[test]
+    fn held_modifiers_stay_presses_and_releases() {
+        let keys = recorded(&[
+            press(Key::ControlLeft, 0),
+            press(Key::KeyC, 10),
+            release(Key::KeyC, 20),
+            release(Key::ControlLeft, 30),
+        ]);
+        let options = RecordOptions {
+            strip_delays: true,
+            collapse_taps: true,
+            ..RecordOptions::default()
+        };
+
+        assert_eq!(
+            to_actions(&keys, &options),
+            vec![
+                MacroAction::Press(Key::ControlLeft),
+                MacroAction::Tap(Key::KeyC),
+                MacroAction::Release(Key::ControlLeft),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_down_before_or_after_the_recording_are_balanced() {
+        let keys = recorded(&[release(Key::ShiftLeft, 0), press(Key::KeyA, 0)]);
+        let options = RecordOptions {
+            strip_delays: true,
+            ..RecordOptions::default()
+        };
+
+        assert_eq!(
+            to_actions(&keys, &options),
+            vec![
+                MacroAction::Press(Key::KeyA),
+                MacroAction::Release(Key::KeyA)
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_button_is_never_recorded() {
+        let recorder = MacroRecorder::new();
+        let button = ProgrammableKeys::Binding(Box::new(KeyBinding {
+            key: Key::F12,
+            modifiers: Vec::new(),
+            device: None,
+        }));
+
+        recorder.record(Key::KeyZ, KeyState::Pressed);
+        recorder.start(button.clone()).unwrap();
+        recorder.record(Key::F12, KeyState::Released);
+        recorder.record(Key::KeyA, KeyState::Pressed);
+        recorder.record(Key::KeyA, KeyState::Released);
+        recorder.record(Key::F12, KeyState::Pressed);
+
+        let (recorded_for, keys) = recorder.stop().unwrap();
+        assert_eq!(recorded_for, button);
+        let keys: Vec<(Key, KeyState)> = keys.into_iter().map(|key| (key.key, key.state)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                (Key::KeyA, KeyState::Pressed),
+                (Key::KeyA, KeyState::Released)
+            ]
+        );
+        assert!(recorder.stop().is_none());
+    }
+}
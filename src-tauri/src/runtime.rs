@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::button_codes::ButtonCodes;
+#[cfg(target_os = "linux")]
+use crate::control_socket::ControlServer;
+use crate::events::EventSink;
+#[cfg(target_os = "linux")]
+use crate::input_devices::InputBackendKind;
+use crate::key_handler::KeyHandler;
+use crate::key_queue::{key_queue, Received};
+use crate::keymap::Keymap;
+use crate::keymap_watcher;
+use crate::layers::LayerState;
+use crate::macro_executor::MacroExecutor;
+use crate::output::Outputs;
+use crate::profiles::Profiles;
+use crate::recorder::MacroRecorder;
+use crate::settings::{Settings, SharedSettings};
+#[cfg(target_os = "linux")]
+use crate::window_rules::RuleMatcher;
+
+#[cfg(target_os = "windows")]
+use crate::windows_listener;
+#[cfg(target_os = "linux")]
+use crate::{control_socket, evdev_listener, focus_watcher, linux_listener};
+
+/// The listener, key queue, key handler and macro executor, with the shared
+/// state the tauri app and the headless daemon both work on
+pub struct Runtime {
+    pub keymap: Arc<Mutex<Keymap>>,
+    pub layers: Arc<Mutex<LayerState>>,
+    pub events: EventSink,
+    pub profiles: Profiles,
+    pub executor: MacroExecutor,
+    pub recorder: MacroRecorder,
+}
+
+impl Runtime {
+    /// Loads a keymap profile and starts the background threads that run macros for it.
+    /// A broken profile leaves us with an empty keymap.
+    pub fn start(settings: &Settings, profile: &str) -> Runtime {
+        let button_codes = ButtonCodes::new(settings.button_codes.clone());
+        let (keymap, diagnostics) = Keymap::load_or_fallback(profile, button_codes.ranges());
+
+        let keymap_arc: Arc<Mutex<Keymap>> = Arc::new(Mutex::new(keymap.clone()));
+        let shared_settings = SharedSettings::new(settings.clone());
+        let layers_arc: Arc<Mutex<LayerState>> = Arc::new(Mutex::new(LayerState::load(
+            &keymap.map_name,
+            &shared_settings,
+        )));
+        let events = EventSink::new();
+        let profiles = Profiles::new(
+            keymap_arc.clone(),
+            layers_arc.clone(),
+            events.clone(),
+            button_codes.clone(),
+            shared_settings,
+            diagnostics,
+        );
+
+        // Handle keyboard presses
+        let (key_sender, key_receiver) =
+            key_queue(settings.key_queue_capacity, settings.key_queue_overflow);
+
+        let executor = MacroExecutor::new(Outputs::new(settings.output_backend), events.clone());
+        let mut key_handler = KeyHandler::new(
+            keymap_arc.clone(),
+            layers_arc.clone(),
+            executor.clone(),
+            events.clone(),
+        );
+        thread::spawn(move || {
+            println!("started handler thread");
+
+            // blocks until the next event or hold deadline, so events are handled as soon as they arrive
+            loop {
+                match key_receiver.recv_until(key_handler.next_deadline()) {
+                    Received::Event(event) => key_handler.handle_event(event),
+                    Received::Timeout => key_handler.handle_timeouts(Instant::now()),
+                    Received::Disconnected => break,
+                }
+            }
+        });
+
+        // keys that aren't macro buttons go to the recorder while a macro is being recorded
+        let recorder = MacroRecorder::new();
+        #[cfg(target_os = "linux")]
+        let control_keys = key_sender.clone();
+        #[cfg(target_os = "linux")]
+        let listener_recorder = recorder.clone();
+        #[cfg(target_os = "linux")]
+        let listener_keymap = keymap_arc.clone();
+        #[cfg(target_os = "linux")]
+        let (input_backend, input_devices, grab_input_devices) = (
+            settings.input_backend,
+            settings.input_devices.clone(),
+            settings.grab_input_devices,
+        );
+
+        thread::spawn(move || {
+            #[cfg(target_os = "linux")]
+            match input_backend {
+                InputBackendKind::Libinput => linux_listener::linux_start(
+                    &key_sender,
+                    &listener_recorder,
+                    &button_codes,
+                    listener_keymap,
+                ),
+                InputBackendKind::Evdev => evdev_listener::evdev_start(
+                    &key_sender,
+                    &listener_recorder,
+                    &button_codes,
+                    listener_keymap,
+                    &input_devices,
+                    grab_input_devices,
+                ),
+            }
+
+            #[cfg(target_os = "windows")]
+            windows_listener::windows_start(&key_sender, &button_codes);
+        });
+
+        // Take commands from the cli and other tools on this machine
+        #[cfg(target_os = "linux")]
+        {
+            let server = ControlServer {
+                keymap: keymap_arc.clone(),
+                profiles: profiles.clone(),
+                events: events.clone(),
+                keys: control_keys,
+            };
+            thread::spawn(move || control_socket::serve(server));
+        }
+
+        // Pick up keymap files edited outside the app
+        if settings.watch_keymaps {
+            let profiles = profiles.clone();
+            thread::spawn(move || keymap_watcher::watch_keymaps(profiles));
+        }
+
+        // Switch profiles to follow the focused window
+        #[cfg(target_os = "linux")]
+        if settings.auto_switch_profiles {
+            let matcher = RuleMatcher::new(
+                &settings.profile_rules,
+                Some(settings.default_profile.clone()),
+            );
+            let profiles = profiles.clone();
+
+            thread::spawn(move || {
+                // only switch when the matched profile changes, so picking one by hand sticks until then
+                let mut matched: Option<String> = None;
+
+                focus_watcher::watch_focus(|window| {
+                    let profile = match matcher.profile_for(&window) {
+                        None => return,
+                        Some(profile) => profile,
+                    };
+
+                    if matched.as_deref() == Some(profile) {
+                        return;
+                    }
+                    matched = Some(profile.to_string());
+
+                    if profiles.active() != profile {
+                        if let Err(err) = profiles.switch_to(profile) {
+                            eprintln!("Failed to switch to keymap profile {}: {}", profile, err);
+                        }
+                    }
+                });
+            });
+        }
+
+        Runtime {
+            keymap: keymap_arc,
+            layers: layers_arc,
+            events,
+            profiles,
+            executor,
+            recorder,
+        }
+    }
+}
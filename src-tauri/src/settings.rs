@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::api::path;
+
+use crate::button_codes;
+use crate::button_codes::CodeRange;
+use crate::input_devices::{DeviceSelector, InputBackendKind};
+use crate::key_queue::OverflowPolicy;
+use crate::keymap::write_atomically;
+use crate::output::OutputBackendKind;
+use crate::window_rules::ProfileRule;
+
+/// App wide settings, stored next to the keymaps folder
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// how many presses can wait for the handler before the overflow policy kicks in
+    pub key_queue_capacity: usize,
+    pub key_queue_overflow: OverflowPolicy,
+    /// layers left toggled on, by keymap name
+    pub toggled_layers: HashMap<String, Vec<String>>,
+    /// reload the active keymap when its file is edited outside the app
+    pub watch_keymaps: bool,
+    /// how many old versions of each keymap file to keep in the backups folder
+    pub keymap_backups: usize,
+    /// keymap profile that was active when the app last ran
+    pub active_profile: String,
+    /// switch profiles to follow the focused window
+    pub auto_switch_profiles: bool,
+    /// profile used when no rule matches the focused window, skipped if it doesn't exist
+    pub default_profile: String,
+    /// rules tried in order to pick a profile for the focused window
+    pub profile_rules: Vec<ProfileRule>,
+    /// what macros send their keyboard and mouse input through
+    pub output_backend: OutputBackendKind,
+    /// where macro keys are read from, linux only
+    pub input_backend: InputBackendKind,
+    /// keyboards the evdev backend reads from, all of them if empty
+    pub input_devices: Vec<DeviceSelector>,
+    /// keep macro keys from reaching other apps by grabbing the evdev backend's
+    /// keyboards, their other keys are passed on through a virtual device
+    pub grab_input_devices: bool,
+    /// which codes are programmable buttons and the labels keymaps know them by
+    pub button_codes: Vec<CodeRange>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            key_queue_capacity: 64,
+            key_queue_overflow: OverflowPolicy::DropOldest,
+            toggled_layers: HashMap::new(),
+            watch_keymaps: true,
+            keymap_backups: 10,
+            active_profile: "keymap".to_string(),
+            auto_switch_profiles: false,
+            default_profile: "default".to_string(),
+            profile_rules: Vec::new(),
+            output_backend: OutputBackendKind::default(),
+            input_backend: InputBackendKind::default(),
+            input_devices: Vec::new(),
+            grab_input_devices: false,
+            button_codes: button_codes::default_ranges(),
+        }
+    }
+}
+
+impl Settings {
+    fn settings_path() -> PathBuf {
+        let mut settings_path = path::local_data_dir().unwrap();
+        settings_path.extend(["hotmap", "settings.json"]);
+        settings_path
+    }
+
+    /// Load the settings file, falling back to the defaults if it is missing or unreadable
+    pub fn load() -> Settings {
+        let settings_path = Settings::settings_path();
+
+        if !settings_path.exists() {
+            return Settings::default();
+        }
+
+        let mut settings_json = String::new();
+        if let Err(err) =
+            File::open(&settings_path).and_then(|mut file| file.read_to_string(&mut settings_json))
+        {
+            eprintln!("Failed to read settings file: {}", err);
+            return Settings::default();
+        }
+
+        match serde_json::from_str(&settings_json) {
+            Ok(settings) => settings,
+            Err(err) => {
+                eprintln!("Failed to parse settings file: {}", err);
+                Settings::default()
+            }
+        }
+    }
+
+    /// Writes the settings file if it is missing or doesn't have every option
+    /// yet, so new options show up in it. A file that is already complete is
+    /// left alone, it may be open in an editor.
+    pub fn save_new_options(&self) -> Result<(), io::Error> {
+        let settings_path = Settings::settings_path();
+
+        if settings_path.exists() {
+            let settings_json = std::fs::read_to_string(&settings_path)?;
+            let stored: Value = match serde_json::from_str(&settings_json) {
+                Ok(stored) => stored,
+                // leave a broken file for the user to fix rather than replacing it
+                Err(_) => return Ok(()),
+            };
+            let current = serde_json::to_value(self).expect("Failed to serialize settings!");
+
+            let has_every_option = match (stored.as_object(), current.as_object()) {
+                (Some(stored), Some(current)) => current.keys().all(|key| stored.contains_key(key)),
+                _ => false,
+            };
+            if has_every_option {
+                return Ok(());
+            }
+        }
+
+        self.save()
+    }
+
+    /// Saves the settings to the settings file, changes go through `SharedSettings::update`
+    fn save(&self) -> Result<(), io::Error> {
+        let settings_path = Settings::settings_path();
+
+        if let Some(parent) = settings_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        write_atomically(
+            &settings_path,
+            serde_json::to_string_pretty(self)
+                .expect("Failed to serialize settings!")
+                .as_bytes(),
+        )
+    }
+}
+
+/// The settings a running app changes, shared by everything that changes them
+/// so one change can't undo another made at the same time
+#[derive(Clone, Debug)]
+pub struct SharedSettings {
+    settings: Arc<Mutex<Settings>>,
+}
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> SharedSettings {
+        SharedSettings {
+            settings: Arc::new(Mutex::new(settings)),
+        }
+    }
+
+    /// A copy of the current settings
+    pub fn get(&self) -> Settings {
+        match self.settings.lock() {
+            Ok(settings) => settings.clone(),
+            Err(_) => {
+                panic!("Failed to acquire settings lock")
+            }
+        }
+    }
+
+    /// Changes the settings and saves them before letting anything else change them
+    pub fn update<T>(&self, change: impl FnOnce(&mut Settings) -> T) -> Result<T, io::Error> {
+        let mut settings = match self.settings.lock() {
+            Ok(settings) => settings,
+            Err(_) => {
+                panic!("Failed to acquire settings lock")
+            }
+        };
+
+        let changed = change(&mut settings);
+        settings.save()?;
+        Ok(changed)
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::key_queue::{KeyEvent, KeyState};
+use crate::keymap::{MacroKey, TapTiming, Trigger};
+use crate::programmable_keys::ProgrammableKeys;
+
+/// Timing rules for a single button, taken from its keymap entry
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ButtonTiming {
+    pub double_tap: bool,
+    pub triple_tap: bool,
+    pub tap_window: Duration,
+    /// how long the button has to be held for a long press, if it has one
+    pub long_press: Option<Duration>,
+}
+
+impl ButtonTiming {
+    pub fn from_macro_key(macro_key: &MacroKey, timing: &TapTiming) -> ButtonTiming {
+        ButtonTiming {
+            double_tap: macro_key.on_double_tap.is_some(),
+            triple_tap: macro_key.on_triple_tap.is_some(),
+            tap_window: macro_key.tap_window(timing),
+            long_press: macro_key
+                .on_hold
+                .as_ref()
+                .map(|_| macro_key.hold_threshold(timing)),
+        }
+    }
+
+    /// Buttons with multi-tap bindings have to wait out the tap window before firing
+    fn is_tap_dance(&self) -> bool {
+        self.double_tap || self.triple_tap
+    }
+
+    fn max_taps(&self) -> u8 {
+        if self.triple_tap {
+            3
+        } else if self.double_tap {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The triggers a finished sequence of taps resolves to
+    fn tap_triggers(&self, taps: u8) -> Vec<Trigger> {
+        match taps {
+            0 => vec![],
+            1 => vec![Trigger::Press],
+            2 if self.double_tap => vec![Trigger::DoubleTap],
+            3 if self.triple_tap => vec![Trigger::TripleTap],
+            // no binding for this many taps, count each one as a single tap
+            _ => vec![Trigger::Press; taps as usize],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ButtonState {
+    timing: ButtonTiming,
+    taps: u8,
+    held: bool,
+    /// the current press already fired as a long press
+    long_pressed: bool,
+    /// when the current press turns into a long press
+    long_press_at: Option<Instant>,
+    /// when the tap sequence resolves if the button isn't pressed again
+    resolve_at: Option<Instant>,
+}
+
+impl ButtonState {
+    fn new(timing: ButtonTiming) -> ButtonState {
+        ButtonState {
+            timing,
+            taps: 0,
+            held: false,
+            long_pressed: false,
+            long_press_at: None,
+            resolve_at: None,
+        }
+    }
+}
+
+/// Timing state machine that sits between the key queue and the macro handler,
+/// turning raw presses and releases into taps, multi-taps and long presses.
+///
+/// It never looks at the clock itself, event timestamps and the `now` passed
+/// to `on_timeout` drive everything.
+#[derive(Debug, Default)]
+pub struct TapDance {
+    buttons: HashMap<ProgrammableKeys, ButtonState>,
+}
+
+impl TapDance {
+    pub fn new() -> TapDance {
+        TapDance::default()
+    }
+
+    /// Feeds a key event through the state machine, returning the triggers that fire right away
+    pub fn on_event(
+        &mut self,
+        event: &KeyEvent,
+        timing: &ButtonTiming,
+    ) -> Vec<(ProgrammableKeys, Trigger)> {
+        match event.state {
+            KeyState::Pressed => self.on_press(&event.key, event.timestamp, timing),
+            KeyState::Released => self.on_release(&event.key, event.timestamp, timing),
+        }
+    }
+
+    /// The next time `on_timeout` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.buttons
+            .values()
+            .flat_map(|state| [state.long_press_at, state.resolve_at])
+            .flatten()
+            .min()
+    }
+
+    /// Fires long presses and finishes tap sequences whose deadlines have passed
+    pub fn on_timeout(&mut self, now: Instant) -> Vec<(ProgrammableKeys, Trigger)> {
+        let mut fired = Vec::new();
+        let mut finished = Vec::new();
+
+        for (key, state) in self.buttons.iter_mut() {
+            if state.long_press_at.is_some_and(|deadline| deadline <= now) {
+                state.long_press_at = None;
+
+                if state.held {
+                    // a long press swallows any taps before it and the release after it
+                    state.long_pressed = true;
+                    state.taps = 0;
+                    fired.push((key.clone(), Trigger::Hold));
+                }
+            }
+
+            if state.resolve_at.is_some_and(|deadline| deadline <= now) {
+                for trigger in state.timing.tap_triggers(state.taps) {
+                    fired.push((key.clone(), trigger));
+                }
+                finished.push(key.clone());
+            }
+        }
+
+        for key in finished {
+            self.buttons.remove(&key);
+        }
+
+        fired
+    }
+
+    fn on_press(
+        &mut self,
+        key: &ProgrammableKeys,
+        timestamp: Instant,
+        timing: &ButtonTiming,
+    ) -> Vec<(ProgrammableKeys, Trigger)> {
+        // pressing another button finishes any tap sequence still waiting on its window
+        let mut fired = self.interrupt(key);
+
+        // a press after the window closed starts a new sequence, even if `on_timeout` hasn't run yet
+        if let Some(state) = self.buttons.get(key) {
+            if state
+                .resolve_at
+                .is_some_and(|deadline| deadline < timestamp)
+            {
+                for trigger in state.timing.tap_triggers(state.taps) {
+                    fired.push((key.clone(), trigger));
+                }
+                self.buttons.remove(key);
+            }
+        }
+
+        let state = self
+            .buttons
+            .entry(key.clone())
+            .or_insert_with(|| ButtonState::new(timing.clone()));
+
+        state.held = true;
+        state.long_pressed = false;
+        state.resolve_at = None;
+        state.long_press_at = state.timing.long_press.map(|duration| timestamp + duration);
+
+        if state.timing.is_tap_dance() {
+            state.taps += 1;
+        } else {
+            fired.push((key.clone(), Trigger::Press));
+        }
+
+        fired
+    }
+
+    fn on_release(
+        &mut self,
+        key: &ProgrammableKeys,
+        timestamp: Instant,
+        timing: &ButtonTiming,
+    ) -> Vec<(ProgrammableKeys, Trigger)> {
+        let state = match self.buttons.get_mut(key) {
+            Some(state) => state,
+            // we never saw the press, so there is nothing to resolve
+            None if timing.is_tap_dance() => return vec![],
+            None => return vec![(key.clone(), Trigger::Release)],
+        };
+
+        state.held = false;
+        state.long_press_at = None;
+
+        if !state.timing.is_tap_dance() {
+            self.buttons.remove(key);
+            return vec![(key.clone(), Trigger::Release)];
+        }
+
+        if state.long_pressed {
+            self.buttons.remove(key);
+            return vec![];
+        }
+
+        if state.taps >= state.timing.max_taps() {
+            let fired = state
+                .timing
+                .tap_triggers(state.taps)
+                .into_iter()
+                .map(|trigger| (key.clone(), trigger))
+                .collect();
+            self.buttons.remove(key);
+            return fired;
+        }
+
+        state.resolve_at = Some(timestamp + state.timing.tap_window);
+        vec![]
+    }
+
+    /// Resolves the released, waiting tap sequences of every other button
+    fn interrupt(&mut self, pressed: &ProgrammableKeys) -> Vec<(ProgrammableKeys, Trigger)> {
+        let waiting: Vec<ProgrammableKeys> = self
+            .buttons
+            .iter()
+            .filter(|(key, state)| *key != pressed && !state.held && state.resolve_at.is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut fired = Vec::new();
+        for key in waiting {
+            if let Some(state) = self.buttons.remove(&key) {
+                for trigger in state.timing.tap_triggers(state.taps) {
+                    fired.push((key.clone(), trigger));
+                }
+            }
+        }
+
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::MacroAction;
+
+    fn button() -> ProgrammableKeys {
+        ProgrammableKeys::get_from_index(1)
+    }
+
+    fn event(state: KeyState, timestamp: Instant) -> KeyEvent {
+        KeyEvent {
+            key: button(),
+            state,
+            timestamp,
+            device: None,
+        }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// a button with double and triple tap bindings and a long press
+    fn tap_dance_timing() -> ButtonTiming {
+        ButtonTiming {
+            double_tap: true,
+            triple_tap: true,
+            tap_window: ms(200),
+            long_press: Some(ms(500)),
+        }
+    }
+
+    /// Presses and releases the button at the given offsets, returning everything that fired
+    fn run(
+        tap_dance: &mut TapDance,
+        timing: &ButtonTiming,
+        start: Instant,
+        taps: &[(u64, u64)],
+    ) -> Vec<Trigger> {
+        let mut fired = Vec::new();
+        for (press, release) in taps {
+            fired.extend(tap_dance.on_event(&event(KeyState::Pressed, start + ms(*press)), timing));
+            fired.extend(tap_dance.on_timeout(start + ms(*release)));
+            fired.extend(
+                tap_dance.on_event(&event(KeyState::Released, start + ms(*release)), timing),
+            );
+        }
+        fired.into_iter().map(|(_, trigger)| trigger).collect()
+    }
+
+    #[test]
+    fn plain_button_fires_press_and_release_right_away() {
+        let mut tap_dance = TapDance::new();
+        let fired = run(
+            &mut tap_dance,
+            &ButtonTiming::default(),
+            Instant::now(),
+            &[(0, 50)],
+        );
+
+        assert_eq!(fired, vec![Trigger::Press, Trigger::Release]);
+        assert_eq!(tap_dance.next_deadline(), None);
+    }
+
+    #[test]
+    fn single_tap_fires_once_the_window_closes() {
+        let mut tap_dance = TapDance::new();
+        let start = Instant::now();
+
+        assert!(run(&mut tap_dance, &tap_dance_timing(), start, &[(0, 50)]).is_empty());
+        assert_eq!(tap_dance.next_deadline(), Some(start + ms(250)));
+        assert!(tap_dance.on_timeout(start + ms(249)).is_empty());
+        assert_eq!(
+            tap_dance.on_timeout(start + ms(250)),
+            vec![(button(), Trigger::Press)]
+        );
+    }
+
+    #[test]
+    fn double_tap_fires_once_the_window_closes() {
+        let mut tap_dance = TapDance::new();
+        let start = Instant::now();
+
+        let fired = run(
+            &mut tap_dance,
+            &tap_dance_timing(),
+            start,
+            &[(0, 50), (150, 200)],
+        );
+        assert!(fired.is_empty());
+        assert_eq!(
+            tap_dance.on_timeout(start + ms(400)),
+            vec![(button(), Trigger::DoubleTap)]
+        );
+    }
+
+    #[test]
+    fn triple_tap_fires_on_the_last_release() {
+        let mut tap_dance = TapDance::new();
+        let fired = run(
+            &mut tap_dance,
+            &tap_dance_timing(),
+            Instant::now(),
+            &[(0, 50), (150, 200), (300, 350)],
+        );
+
+        assert_eq!(fired, vec![Trigger::TripleTap]);
+        assert_eq!(tap_dance.next_deadline(), None);
+    }
+
+    #[test]
+    fn long_press_swallows_the_tap_and_release() {
+        let mut tap_dance = TapDance::new();
+        let start = Instant::now();
+        let timing = tap_dance_timing();
+
+        tap_dance.on_event(&event(KeyState::Pressed, start), &timing);
+        assert_eq!(tap_dance.next_deadline(), Some(start + ms(500)));
+        assert_eq!(
+            tap_dance.on_timeout(start + ms(500)),
+            vec![(button(), Trigger::Hold)]
+        );
+        assert!(tap_dance
+            .on_event(&event(KeyState::Released, start + ms(800)), &timing)
+            .is_empty());
+        assert_eq!(tap_dance.next_deadline(), None);
+    }
+
+    #[test]
+    fn per_button_overrides_replace_the_keymap_timing() {
+        let macro_key = MacroKey {
+            on_hold: Some(vec![MacroAction::None]),
+            on_double_tap: Some(vec![MacroAction::None]),
+            hold_threshold_ms: Some(1000),
+            tap_window_ms: Some(50),
+            ..MacroKey::new(button())
+        };
+        let timing = ButtonTiming::from_macro_key(&macro_key, &TapTiming::default());
+        assert_eq!(timing.tap_window, ms(50));
+        assert_eq!(timing.long_press, Some(ms(1000)));
+
+        let mut tap_dance = TapDance::new();
+        let start = Instant::now();
+
+        // the keymap's 500 ms long press doesn't apply
+        tap_dance.on_event(&event(KeyState::Pressed, start), &timing);
+        assert!(tap_dance.on_timeout(start + ms(600)).is_empty());
+        tap_dance.on_event(&event(KeyState::Released, start + ms(600)), &timing);
+
+        // and the tap resolves 50 ms after the release instead of 200
+        assert_eq!(tap_dance.next_deadline(), Some(start + ms(650)));
+        assert_eq!(
+            tap_dance.on_timeout(start + ms(650)),
+            vec![(button(), Trigger::Press)]
+        );
+    }
+
+    #[test]
+    fn press_after_the_window_expired_starts_a_new_sequence() {
+        let mut tap_dance = TapDance::new();
+        let start = Instant::now();
+        let timing = tap_dance_timing();
+
+        tap_dance.on_event(&event(KeyState::Pressed, start), &timing);
+        tap_dance.on_event(&event(KeyState::Released, start + ms(50)), &timing);
+
+        // on_timeout never ran for the deadline at 250 ms
+        assert_eq!(
+            tap_dance.on_event(&event(KeyState::Pressed, start + ms(300)), &timing),
+            vec![(button(), Trigger::Press)]
+        );
+        tap_dance.on_event(&event(KeyState::Released, start + ms(350)), &timing);
+        assert_eq!(
+            tap_dance.on_timeout(start + ms(550)),
+            vec![(button(), Trigger::Press)]
+        );
+    }
+}
@@ -1,48 +1,236 @@
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use crate::keymap::{Keymap, MacroKey};
+use crate::backups;
+use crate::backups::Backup;
+#[cfg(target_os = "linux")]
+use crate::evdev_listener;
+use crate::input_devices::InputDevice;
+use crate::keymap::{Keymap, MacroAction, MacroKey, MacroType, Trigger};
+use crate::layers::LayerState;
+use crate::macro_executor::{CancelToken, MacroExecutor};
+use crate::macro_script;
+use crate::macro_script::ScriptError;
+use crate::output::{OutputEvent, RecordingBackend};
+use crate::profiles::Profiles;
+use crate::programmable_keys::{handle_macro_key, ProgrammableKeys};
+use crate::recorder;
+use crate::recorder::{MacroRecorder, RecordOptions};
+use crate::validation::Diagnostic;
 
 #[tauri::command]
 pub fn send_keymap(state: tauri::State<Arc<Mutex<Keymap>>>) -> Keymap {
-    let keymap_clone = match state.lock() {
-        Ok(keymap) => keymap,
+    match state.lock() {
+        Ok(keymap) => keymap.clone(),
         Err(_) => {
             panic!("Failed to acquire keymap lock")
         }
-    };
-
-    Keymap {
-        map_name: keymap_clone.map_name.clone(),
-        buttons: keymap_clone.buttons.clone(),
-        button_count: keymap_clone.button_count,
     }
 }
 
 #[tauri::command]
 pub fn add_button(button: MacroKey, state: tauri::State<Arc<Mutex<Keymap>>>) {
-    let mut keymap_clone = match state.lock() {
-        Ok(keymap) => keymap,
+    match state.lock() {
+        Ok(mut keymap) => keymap.add_button(button),
         Err(_) => {
             panic!("Failed to acquire keymap lock")
         }
-    };
+    }
+}
+
+#[tauri::command]
+pub fn save_keymap(keymap: Keymap, profiles: tauri::State<Profiles>) -> Result<(), String> {
+    profiles.save_keymap(keymap).map_err(|err| err.to_string())
+}
 
-    keymap_clone.button_count += 1;
-    keymap_clone.buttons.push(button);
+#[tauri::command]
+pub fn stop_all_macros(state: tauri::State<MacroExecutor>) {
+    state.stop_all();
 }
 
 #[tauri::command]
-pub fn save_keymap(keymap: Keymap, state: tauri::State<Arc<Mutex<Keymap>>>) {
-    let mut keymap_clone = match state.lock() {
+pub fn send_active_layers(
+    keymap_state: tauri::State<Arc<Mutex<Keymap>>>,
+    layer_state: tauri::State<Arc<Mutex<LayerState>>>,
+) -> Vec<String> {
+    let keymap = match keymap_state.lock() {
         Ok(keymap) => keymap,
         Err(_) => {
-            panic!("failed to acquire keymap lock");
+            panic!("Failed to acquire keymap lock")
+        }
+    };
+
+    match layer_state.lock() {
+        Ok(layers) => layers.active_layers(&keymap),
+        Err(_) => {
+            panic!("Failed to acquire layer state lock")
         }
+    }
+}
+
+#[tauri::command]
+pub fn send_keymap_diagnostics(state: tauri::State<Profiles>) -> Vec<Diagnostic> {
+    state.diagnostics()
+}
+
+#[tauri::command]
+pub fn list_profiles() -> Result<Vec<String>, String> {
+    Profiles::list().map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn send_active_profile(state: tauri::State<Profiles>) -> String {
+    state.active()
+}
+
+#[tauri::command]
+pub fn create_profile(
+    name: String,
+    button_count: i32,
+    state: tauri::State<Profiles>,
+) -> Result<(), String> {
+    state
+        .create(&name, button_count)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn duplicate_profile(
+    name: String,
+    new_name: String,
+    state: tauri::State<Profiles>,
+) -> Result<(), String> {
+    state
+        .duplicate(&name, &new_name)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn rename_profile(
+    name: String,
+    new_name: String,
+    state: tauri::State<Profiles>,
+) -> Result<(), String> {
+    state
+        .rename(&name, &new_name)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn delete_profile(name: String, state: tauri::State<Profiles>) -> Result<(), String> {
+    state.delete(&name).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn activate_profile(name: String, state: tauri::State<Profiles>) -> Result<(), String> {
+    state.activate(&name).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn list_backups(name: Option<String>) -> Result<Vec<Backup>, String> {
+    backups::list(name.as_deref()).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn restore_backup(file_name: String, state: tauri::State<Profiles>) -> Result<(), String> {
+    state
+        .restore_backup(&file_name)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn import_keymap(
+    path: String,
+    name: Option<String>,
+    state: tauri::State<Profiles>,
+) -> Result<String, String> {
+    state
+        .import(Path::new(&path), name.as_deref())
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn export_keymap(
+    name: String,
+    path: String,
+    state: tauri::State<Profiles>,
+) -> Result<(), String> {
+    state
+        .export(&name, Path::new(&path))
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+pub fn parse_macro_script(script: String) -> Result<Vec<MacroAction>, ScriptError> {
+    macro_script::parse(&script)
+}
+
+#[tauri::command]
+pub fn print_macro_script(actions: Vec<MacroAction>) -> String {
+    macro_script::print(&actions)
+}
+
+#[tauri::command]
+pub fn start_recording(
+    button: ProgrammableKeys,
+    state: tauri::State<MacroRecorder>,
+) -> Result<(), String> {
+    if !cfg!(target_os = "linux") {
+        return Err("Recording macros is only supported on Linux".to_string());
+    }
+
+    state.start(button).map_err(|err| err.to_string())
+}
+
+/// Stops recording and saves what was recorded as the button's actions
+#[tauri::command]
+pub fn stop_recording(
+    options: RecordOptions,
+    state: tauri::State<MacroRecorder>,
+    profiles: tauri::State<Profiles>,
+) -> Result<Vec<MacroAction>, String> {
+    let (button, keys) = state
+        .stop()
+        .ok_or_else(|| "No macro is being recorded".to_string())?;
+
+    let actions = recorder::to_actions(&keys, &options);
+    println!("Recorded {} actions for {:?}", actions.len(), button);
+
+    profiles
+        .set_button_actions(&button, actions.clone())
+        .map_err(|err| err.to_string())?;
+    Ok(actions)
+}
+
+#[tauri::command]
+pub fn cancel_recording(state: tauri::State<MacroRecorder>) {
+    state.stop();
+}
+
+/// Runs a button's press actions once without sending anything, returning the input they would send
+#[tauri::command]
+pub fn preview_macro(macro_key: MacroKey) -> Result<Vec<OutputEvent>, String> {
+    let actions = macro_key
+        .actions_for(&Trigger::Press)
+        .cloned()
+        .unwrap_or_default();
+    let once = MacroKey {
+        macro_type: MacroType::Once,
+        actions,
+        ..macro_key
     };
 
-    keymap_clone.map_name = keymap.map_name;
-    keymap_clone.buttons = keymap.buttons;
-    keymap_clone.button_count = keymap.button_count;
+    let mut output = RecordingBackend::new();
+    handle_macro_key(&once, &mut output, &CancelToken::default()).map_err(|err| err.to_string())?;
+    Ok(output.events)
+}
+
+/// Keyboards the evdev listener can read from
+#[tauri::command]
+pub fn list_input_devices() -> Result<Vec<InputDevice>, String> {
+    #[cfg(target_os = "linux")]
+    return evdev_listener::list_devices().map_err(|err| err.to_string());
 
-    Keymap::save_to_file(keymap_clone.clone()).unwrap()
+    #[cfg(not(target_os = "linux"))]
+    Err("Reading input devices directly is only supported on Linux".to_string())
 }
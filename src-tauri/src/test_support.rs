@@ -0,0 +1,37 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::OnceLock;
+
+use crate::settings::{Settings, SharedSettings};
+
+/// Points the app's data folder at an empty temporary folder for the whole
+/// test run, so tests never touch the real keymaps and settings. Tests
+/// sharing it use their own keymap names.
+pub fn use_temp_data_dir() -> PathBuf {
+    static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+    DATA_DIR
+        .get_or_init(|| {
+            let data_dir = env::temp_dir().join(format!("hotmap-test-{}", process::id()));
+            let _ = fs::remove_dir_all(&data_dir);
+            fs::create_dir_all(&data_dir).expect("Failed to create test data folder");
+
+            // where tauri looks for the local data folder on linux
+            env::set_var("XDG_DATA_HOME", &data_dir);
+            data_dir
+        })
+        .clone()
+}
+
+/// Settings for tests in the temporary data folder, shared like the running
+/// app shares them so tests saving settings at once don't undo each other
+pub fn shared_settings() -> SharedSettings {
+    static SETTINGS: OnceLock<SharedSettings> = OnceLock::new();
+
+    use_temp_data_dir();
+    SETTINGS
+        .get_or_init(|| SharedSettings::new(Settings::default()))
+        .clone()
+}
@@ -0,0 +1,47 @@
+use tauri::{AppHandle, CustomMenuItem, SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu};
+
+use crate::profiles::Profiles;
+
+/// Prefix of the tray menu ids that switch keymap profile
+pub const PROFILE_ITEM_PREFIX: &str = "profile:";
+
+/// Builds the tray menu, with a submenu to switch between keymap profiles
+pub fn build_tray_menu(active_profile: &str) -> SystemTrayMenu {
+    let profiles = match Profiles::list() {
+        Ok(profiles) => profiles,
+        Err(err) => {
+            eprintln!("Failed to list keymap profiles: {}", err);
+            Vec::new()
+        }
+    };
+
+    let mut profile_menu = SystemTrayMenu::new();
+    for profile in profiles {
+        let mut item =
+            CustomMenuItem::new(PROFILE_ITEM_PREFIX.to_string() + &profile, profile.clone());
+        if profile == active_profile {
+            item = item.selected();
+        }
+        profile_menu = profile_menu.add_item(item);
+    }
+
+    let show = CustomMenuItem::new("show".to_string(), "Show");
+    let quit = CustomMenuItem::new("quit".to_string(), "Quit");
+    let hide = CustomMenuItem::new("hide".to_string(), "Hide");
+    let stop_macros = CustomMenuItem::new("stop_macros".to_string(), "Stop All Macros");
+    SystemTrayMenu::new()
+        .add_item(quit)
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_submenu(SystemTraySubmenu::new("Profiles", profile_menu))
+        .add_item(stop_macros)
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(show)
+        .add_item(hide)
+}
+
+/// Rebuilds the tray menu so the profile submenu stays in sync
+pub fn refresh_tray_menu(app: &AppHandle, active_profile: &str) {
+    if let Err(err) = app.tray_handle().set_menu(build_tray_menu(active_profile)) {
+        eprintln!("Failed to update tray menu: {}", err);
+    }
+}
@@ -0,0 +1,422 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use libc::{
+    input_absinfo, input_event, input_id, uinput_abs_setup, uinput_setup, O_NONBLOCK,
+    UINPUT_MAX_NAME_SIZE,
+};
+
+use crate::keymap::{Key, MouseButton};
+use crate::linux_keycodes;
+use crate::output::{Axis, Direction, OutputBackend, OutputError};
+
+const UINPUT_PATH: &str = "/dev/uinput";
+const DEVICE_NAME: &str = "HotMap virtual input";
+const PASSTHROUGH_NAME: &str = "HotMap passthrough for";
+
+/// Whether an input device is one of the virtual devices made here
+pub fn is_own_device(name: &str) -> bool {
+    name == DEVICE_NAME || name.starts_with(PASSTHROUGH_NAME)
+}
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const EV_MSC: u16 = 0x04;
+const EV_LED: u16 = 0x11;
+const SYN_REPORT: u16 = 0;
+
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_SIDE: u16 = 0x113;
+const BTN_EXTRA: u16 = 0x114;
+
+const KEY_LEFTSHIFT: u16 = 42;
+/// the last regular keyboard key, the codes after it are buttons of other kinds
+/// of devices that would get the virtual device mistaken for a joystick or tablet
+const KEY_MICMUTE: u16 = 248;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+const fn uinput_ioctl(write: bool, number: u64, size: usize) -> u64 {
+    ((write as u64) << 30) | ((size as u64) << 16) | ((b'U' as u64) << 8) | number
+}
+
+const UI_DEV_CREATE: u64 = uinput_ioctl(false, 1, 0);
+const UI_DEV_DESTROY: u64 = uinput_ioctl(false, 2, 0);
+const UI_DEV_SETUP: u64 = uinput_ioctl(true, 3, std::mem::size_of::<uinput_setup>());
+const UI_ABS_SETUP: u64 = uinput_ioctl(true, 4, std::mem::size_of::<uinput_abs_setup>());
+const UI_SET_EVBIT: u64 = uinput_ioctl(true, 100, std::mem::size_of::<libc::c_int>());
+const UI_SET_KEYBIT: u64 = uinput_ioctl(true, 101, std::mem::size_of::<libc::c_int>());
+const UI_SET_RELBIT: u64 = uinput_ioctl(true, 102, std::mem::size_of::<libc::c_int>());
+const UI_SET_MSCBIT: u64 = uinput_ioctl(true, 104, std::mem::size_of::<libc::c_int>());
+const UI_SET_LEDBIT: u64 = uinput_ioctl(true, 105, std::mem::size_of::<libc::c_int>());
+const UI_SET_PROPBIT: u64 = uinput_ioctl(true, 110, std::mem::size_of::<libc::c_int>());
+
+/// The events a virtual device can send, by event type
+#[derive(Default)]
+pub struct Capabilities {
+    pub keys: Vec<u16>,
+    pub relative_axes: Vec<u16>,
+    /// each axis with its range, which readers need to make sense of its values
+    pub absolute_axes: Vec<(u16, input_absinfo)>,
+    pub misc: Vec<u16>,
+    pub leds: Vec<u16>,
+    /// tell apart things like touchpads and touchscreens, which send the same events
+    pub properties: Vec<u16>,
+}
+
+/// A virtual keyboard and mouse made through `/dev/uinput`. It is removed again
+/// when dropped, or earlier through `destroy` for exits that skip destructors.
+pub struct VirtualDevice {
+    file: File,
+    name: String,
+    destroyed: AtomicBool,
+}
+
+impl VirtualDevice {
+    /// The device macros type and click through
+    pub fn create() -> Result<VirtualDevice, io::Error> {
+        let capabilities = Capabilities {
+            keys: (1..=KEY_MICMUTE).chain(BTN_LEFT..=BTN_EXTRA).collect(),
+            relative_axes: vec![REL_X, REL_Y, REL_HWHEEL, REL_WHEEL],
+            ..Capabilities::default()
+        };
+        VirtualDevice::with_capabilities(DEVICE_NAME, &capabilities)
+    }
+
+    /// A copy of a grabbed device that passes on everything but its macro keys,
+    /// so it needs the same capabilities minus those keys
+    pub fn passthrough(
+        source_name: &str,
+        capabilities: &Capabilities,
+    ) -> Result<VirtualDevice, io::Error> {
+        let name = format!("{} {}", PASSTHROUGH_NAME, source_name);
+        VirtualDevice::with_capabilities(&name, capabilities)
+    }
+
+    fn with_capabilities(
+        name: &str,
+        capabilities: &Capabilities,
+    ) -> Result<VirtualDevice, io::Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(O_NONBLOCK)
+            .open(UINPUT_PATH)?;
+        let fd = file.as_raw_fd();
+
+        let ioctl = |request: u64, value: libc::c_ulong| -> Result<(), io::Error> {
+            // the value is either a plain number or a pointer, depending on the request
+            if unsafe { libc::ioctl(fd, request as libc::Ioctl, value) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        };
+
+        ioctl(UI_SET_EVBIT, EV_KEY.into())?;
+        for key_code in &capabilities.keys {
+            ioctl(UI_SET_KEYBIT, (*key_code).into())?;
+        }
+
+        let other_codes = [
+            (EV_REL, UI_SET_RELBIT, &capabilities.relative_axes),
+            (EV_MSC, UI_SET_MSCBIT, &capabilities.misc),
+            (EV_LED, UI_SET_LEDBIT, &capabilities.leds),
+        ];
+        for (event_type, request, codes) in other_codes {
+            if !codes.is_empty() {
+                ioctl(UI_SET_EVBIT, event_type.into())?;
+            }
+            for code in codes {
+                ioctl(request, (*code).into())?;
+            }
+        }
+
+        // absolute axes are set up along with their ranges, after the device itself
+        if !capabilities.absolute_axes.is_empty() {
+            ioctl(UI_SET_EVBIT, EV_ABS.into())?;
+        }
+        for property in &capabilities.properties {
+            ioctl(UI_SET_PROPBIT, (*property).into())?;
+        }
+
+        let mut setup = uinput_setup {
+            id: input_id {
+                bustype: BUS_VIRTUAL,
+                vendor: 0,
+                product: 0,
+                version: 1,
+            },
+            name: [0; UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        // long names are cut short, leaving the last byte to end the name
+        for (name_char, byte) in setup.name[..UINPUT_MAX_NAME_SIZE - 1]
+            .iter_mut()
+            .zip(name.bytes())
+        {
+            *name_char = byte as libc::c_char;
+        }
+
+        ioctl(UI_DEV_SETUP, &setup as *const uinput_setup as libc::c_ulong)?;
+        for (code, absinfo) in &capabilities.absolute_axes {
+            let abs_setup = uinput_abs_setup {
+                code: *code,
+                absinfo: *absinfo,
+            };
+            ioctl(
+                UI_ABS_SETUP,
+                &abs_setup as *const uinput_abs_setup as libc::c_ulong,
+            )?;
+        }
+        ioctl(UI_DEV_CREATE, 0)?;
+
+        println!("Created uinput device {:?}", name);
+        Ok(VirtualDevice {
+            file,
+            name: name.to_string(),
+            destroyed: AtomicBool::new(false),
+        })
+    }
+
+    /// Sends a batch of events, followed by the report that makes them take effect together
+    pub fn emit(&self, events: &[(u16, u16, i32)]) -> Result<(), OutputError> {
+        let mut batch: Vec<input_event> = Vec::with_capacity(events.len() + 1);
+        for (event_type, code, value) in events.iter().chain([(EV_SYN, SYN_REPORT, 0)].iter()) {
+            // the kernel fills in the timestamp
+            let mut event: input_event = unsafe { std::mem::zeroed() };
+            event.type_ = *event_type;
+            event.code = *code;
+            event.value = *value;
+            batch.push(event);
+        }
+
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                batch.as_ptr() as *const u8,
+                std::mem::size_of_val(batch.as_slice()),
+            )
+        };
+
+        (&self.file)
+            .write_all(bytes)
+            .map_err(|err| OutputError::new(format!("Failed to write to uinput: {}", err)))
+    }
+
+    /// Removes the virtual device, anything still held down on it is released by the kernel
+    pub fn destroy(&self) {
+        if self.destroyed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY as libc::Ioctl) } < 0 {
+            eprintln!(
+                "Failed to remove uinput device: {}",
+                io::Error::last_os_error()
+            );
+        } else {
+            println!("Removed uinput device {:?}", self.name);
+        }
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
+
+/// Sends macro input as raw key codes through a shared uinput device. Keys are
+/// physical positions, so they come out the same whatever layout is set, but
+/// typing text assumes a US layout to find the keys for each character.
+pub struct UinputBackend {
+    device: Arc<VirtualDevice>,
+}
+
+impl UinputBackend {
+    pub fn new(device: Arc<VirtualDevice>) -> UinputBackend {
+        UinputBackend { device }
+    }
+
+    fn key_code(&mut self, code: u16, direction: Direction) -> Result<(), OutputError> {
+        match direction {
+            Direction::Press => self.device.emit(&[(EV_KEY, code, 1)]),
+            Direction::Release => self.device.emit(&[(EV_KEY, code, 0)]),
+            Direction::Click => {
+                self.device.emit(&[(EV_KEY, code, 1)])?;
+                self.device.emit(&[(EV_KEY, code, 0)])
+            }
+        }
+    }
+}
+
+impl OutputBackend for UinputBackend {
+    fn key(&mut self, key: &Key, direction: Direction) -> Result<(), OutputError> {
+        let code = u16::try_from(linux_keycodes::code_for_key(key))
+            .ok()
+            .filter(|code| (1..=KEY_MICMUTE).contains(code))
+            .ok_or_else(|| OutputError::new(format!("{:?} can't be sent through uinput", key)))?;
+
+        self.key_code(code, direction)
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), OutputError> {
+        for c in text.chars() {
+            let (key, shifted) = us_layout_key(c).ok_or_else(|| {
+                OutputError::new(format!(
+                    "Can't type {:?} through uinput, only US layout characters can be typed",
+                    c
+                ))
+            })?;
+            let code = linux_keycodes::code_for_key(&key) as u16;
+
+            if shifted {
+                self.key_code(KEY_LEFTSHIFT, Direction::Press)?;
+            }
+            let typed = self.key_code(code, Direction::Click);
+            if shifted {
+                self.key_code(KEY_LEFTSHIFT, Direction::Release)?;
+            }
+            typed?;
+        }
+
+        Ok(())
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        direction: Direction,
+    ) -> Result<(), OutputError> {
+        let code = match button {
+            MouseButton::Left => BTN_LEFT,
+            MouseButton::Right => BTN_RIGHT,
+            MouseButton::Middle => BTN_MIDDLE,
+            MouseButton::Back => BTN_SIDE,
+            MouseButton::Forward => BTN_EXTRA,
+        };
+
+        self.key_code(code, direction)
+    }
+
+    fn move_mouse_to(&mut self, _x: i32, _y: i32) -> Result<(), OutputError> {
+        // a relative mouse has no idea where it is, and an absolute one would need the screen layout
+        Err(OutputError::new(
+            "The uinput backend can only move the mouse relative to where it is".to_string(),
+        ))
+    }
+
+    fn move_mouse_by(&mut self, x: i32, y: i32) -> Result<(), OutputError> {
+        self.device.emit(&[(EV_REL, REL_X, x), (EV_REL, REL_Y, y)])
+    }
+
+    fn scroll(&mut self, steps: i32, axis: Axis) -> Result<(), OutputError> {
+        match axis {
+            // the wheel counts upwards as positive
+            Axis::Vertical => self.device.emit(&[(EV_REL, REL_WHEEL, -steps)]),
+            Axis::Horizontal => self.device.emit(&[(EV_REL, REL_HWHEEL, steps)]),
+        }
+    }
+}
+
+/// The key that types a character on a US layout, and whether it needs shift
+fn us_layout_key(c: char) -> Option<(Key, bool)> {
+    let unshifted = |key| Some((key, false));
+    let shifted = |key| Some((key, true));
+
+    match c {
+        'a'..='z' | 'A'..='Z' => {
+            Some((letter_key(c.to_ascii_lowercase())?, c.is_ascii_uppercase()))
+        }
+        '1' => unshifted(Key::Num1),
+        '2' => unshifted(Key::Num2),
+        '3' => unshifted(Key::Num3),
+        '4' => unshifted(Key::Num4),
+        '5' => unshifted(Key::Num5),
+        '6' => unshifted(Key::Num6),
+        '7' => unshifted(Key::Num7),
+        '8' => unshifted(Key::Num8),
+        '9' => unshifted(Key::Num9),
+        '0' => unshifted(Key::Num0),
+        '!' => shifted(Key::Num1),
+        '@' => shifted(Key::Num2),
+        '#' => shifted(Key::Num3),
+        '$' => shifted(Key::Num4),
+        '%' => shifted(Key::Num5),
+        '^' => shifted(Key::Num6),
+        '&' => shifted(Key::Num7),
+        '*' => shifted(Key::Num8),
+        '(' => shifted(Key::Num9),
+        ')' => shifted(Key::Num0),
+        '-' => unshifted(Key::Minus),
+        '_' => shifted(Key::Minus),
+        '=' => unshifted(Key::Equal),
+        '+' => shifted(Key::Equal),
+        '[' => unshifted(Key::LeftBracket),
+        '{' => shifted(Key::LeftBracket),
+        ']' => unshifted(Key::RightBracket),
+        '}' => shifted(Key::RightBracket),
+        ';' => unshifted(Key::SemiColon),
+        ':' => shifted(Key::SemiColon),
+        '\'' => unshifted(Key::Quote),
+        '"' => shifted(Key::Quote),
+        '`' => unshifted(Key::BackQuote),
+        '~' => shifted(Key::BackQuote),
+        '\\' => unshifted(Key::BackSlash),
+        '|' => shifted(Key::BackSlash),
+        ',' => unshifted(Key::Comma),
+        '<' => shifted(Key::Comma),
+        '.' => unshifted(Key::Dot),
+        '>' => shifted(Key::Dot),
+        '/' => unshifted(Key::Slash),
+        '?' => shifted(Key::Slash),
+        ' ' => unshifted(Key::Space),
+        '\t' => unshifted(Key::Tab),
+        '\n' => unshifted(Key::Return),
+        _ => None,
+    }
+}
+
+fn letter_key(letter: char) -> Option<Key> {
+    Some(match letter {
+        'a' => Key::KeyA,
+        'b' => Key::KeyB,
+        'c' => Key::KeyC,
+        'd' => Key::KeyD,
+        'e' => Key::KeyE,
+        'f' => Key::KeyF,
+        'g' => Key::KeyG,
+        'h' => Key::KeyH,
+        'i' => Key::KeyI,
+        'j' => Key::KeyJ,
+        'k' => Key::KeyK,
+        'l' => Key::KeyL,
+        'm' => Key::KeyM,
+        'n' => Key::KeyN,
+        'o' => Key::KeyO,
+        'p' => Key::KeyP,
+        'q' => Key::KeyQ,
+        'r' => Key::KeyR,
+        's' => Key::KeyS,
+        't' => Key::KeyT,
+        'u' => Key::KeyU,
+        'v' => Key::KeyV,
+        'w' => Key::KeyW,
+        'x' => Key::KeyX,
+        'y' => Key::KeyY,
+        'z' => Key::KeyZ,
+        _ => return None,
+    })
+}
@@ -0,0 +1,522 @@
+use std::fmt;
+use std::io;
+
+use serde::Serialize;
+
+use crate::button_codes;
+use crate::button_codes::CodeRange;
+use crate::keymap::{Key, Keymap, MacroAction, MacroKey, BASE_LAYER};
+use crate::macro_script::ScriptError;
+use crate::migrations::CURRENT_SCHEMA_VERSION;
+use crate::programmable_keys::ProgrammableKeys;
+
+/// delays longer than this are almost certainly a typo, ten minutes
+pub const MAX_DELAY_MS: u64 = 10 * 60 * 1000;
+
+/// Why a keymap file couldn't be used
+#[derive(Debug)]
+pub enum KeymapError {
+    Io(io::Error),
+    /// the file isn't valid keymap json
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// the file was written by a newer version of hotmap
+    UnsupportedVersion {
+        version: u64,
+    },
+    /// upgrading the file from an older schema version failed
+    Migration {
+        from: u64,
+        message: String,
+    },
+    /// a button's macro script doesn't compile
+    Script {
+        layer: String,
+        button: ProgrammableKeys,
+        error: ScriptError,
+    },
+    /// the file parsed but has problems that make it unsafe to run
+    Invalid(Vec<KeymapProblem>),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::Io(err) => write!(f, "Failed to read keymap file: {}", err),
+            KeymapError::Parse {
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "Keymap file is invalid at line {}, column {}: {}",
+                line, column, message
+            ),
+            KeymapError::UnsupportedVersion { version } => write!(
+                f,
+                "Keymap schema version {} is newer than this version of hotmap supports ({})",
+                version, CURRENT_SCHEMA_VERSION
+            ),
+            KeymapError::Migration { from, message } => write!(
+                f,
+                "Failed to upgrade keymap from schema version {}: {}",
+                from, message
+            ),
+            KeymapError::Script {
+                layer,
+                button,
+                error,
+            } => write!(
+                f,
+                "Macro script for {} in layer {} is invalid at {}",
+                button, layer, error
+            ),
+            KeymapError::Invalid(problems) => {
+                let problems: Vec<String> = problems
+                    .iter()
+                    .filter(|problem| problem.is_fatal())
+                    .map(|problem| problem.to_string())
+                    .collect();
+                write!(f, "Keymap has errors: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeymapError {}
+
+impl From<io::Error> for KeymapError {
+    fn from(err: io::Error) -> Self {
+        KeymapError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KeymapError {
+    fn from(err: serde_json::Error) -> Self {
+        // serde_json puts the location at the end of the message, we keep it separately
+        let message = err.to_string();
+        let location = format!(" at line {} column {}", err.line(), err.column());
+
+        KeymapError::Parse {
+            line: err.line(),
+            column: err.column(),
+            message: message
+                .strip_suffix(&location)
+                .unwrap_or(&message)
+                .to_string(),
+        }
+    }
+}
+
+impl From<KeymapError> for io::Error {
+    fn from(err: KeymapError) -> Self {
+        match err {
+            KeymapError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+        }
+    }
+}
+
+impl KeymapError {
+    /// The error as diagnostics for the UI
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            KeymapError::Io(err) => vec![Diagnostic::error(format!(
+                "Failed to read keymap file: {}",
+                err
+            ))],
+            KeymapError::Parse {
+                line,
+                column,
+                message,
+            } => vec![Diagnostic {
+                severity: Severity::Error,
+                message: message.clone(),
+                line: Some(*line),
+                column: Some(*column),
+            }],
+            KeymapError::UnsupportedVersion { .. }
+            | KeymapError::Migration { .. }
+            | KeymapError::Script { .. } => {
+                vec![Diagnostic::error(self.to_string())]
+            }
+            KeymapError::Invalid(problems) => problems.iter().map(Diagnostic::from).collect(),
+        }
+    }
+}
+
+/// Something wrong with a keymap that parsed fine
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum KeymapProblem {
+    /// more than one binding for the same button in a layer
+    DuplicateButton {
+        layer: String,
+        button: ProgrammableKeys,
+    },
+    /// `button_count` disagrees with the buttons listed, the list wins and the count is corrected
+    ButtonCountMismatch { button_count: i32, buttons: usize },
+    /// no button code has this label, so the binding can never be triggered
+    UnknownButton {
+        layer: String,
+        index: usize,
+        label: String,
+    },
+    /// a key is pressed more often than it is released, so it can get stuck down
+    UnreleasedKey {
+        layer: String,
+        button: ProgrammableKeys,
+        key: Key,
+    },
+    /// a key is released more often than it is pressed
+    UnpressedRelease {
+        layer: String,
+        button: ProgrammableKeys,
+        key: Key,
+    },
+    AbsurdDelay {
+        layer: String,
+        button: ProgrammableKeys,
+        delay_ms: u64,
+    },
+}
+
+impl KeymapProblem {
+    /// Fatal problems stop the keymap from loading, the rest are only warnings
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            KeymapProblem::DuplicateButton { .. } | KeymapProblem::AbsurdDelay { .. }
+        )
+    }
+}
+
+impl fmt::Display for KeymapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapProblem::DuplicateButton { layer, button } => {
+                write!(f, "{} is bound more than once in layer {}", button, layer)
+            }
+            KeymapProblem::ButtonCountMismatch {
+                button_count,
+                buttons,
+            } => write!(
+                f,
+                "button_count is {} but there are {} buttons, using {}",
+                button_count, buttons, buttons
+            ),
+            KeymapProblem::UnknownButton {
+                layer,
+                index,
+                label,
+            } => write!(
+                f,
+                "Binding {} in layer {} is for {}, which no button code is labelled as, so it can never run",
+                index + 1,
+                layer,
+                label
+            ),
+            KeymapProblem::UnreleasedKey { layer, button, key } => write!(
+                f,
+                "{} in layer {} presses {:?} without releasing it",
+                button, layer, key
+            ),
+            KeymapProblem::UnpressedRelease { layer, button, key } => write!(
+                f,
+                "{} in layer {} releases {:?} without pressing it",
+                button, layer, key
+            ),
+            KeymapProblem::AbsurdDelay {
+                layer,
+                button,
+                delay_ms,
+            } => write!(
+                f,
+                "{} in layer {} has a {} ms delay, the most allowed is {} ms",
+                button, layer, delay_ms, MAX_DELAY_MS
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A keymap problem in the shape the UI shows it
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn error(message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl From<&KeymapProblem> for Diagnostic {
+    fn from(problem: &KeymapProblem) -> Self {
+        Diagnostic {
+            severity: if problem.is_fatal() {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            message: problem.to_string(),
+            line: None,
+            column: None,
+        }
+    }
+}
+
+/// Checks a keymap for problems, fatal ones first. Button labels are checked
+/// against the code ranges the listeners were started with.
+pub fn validate(keymap: &Keymap, ranges: &[CodeRange]) -> Vec<KeymapProblem> {
+    let mut problems = Vec::new();
+
+    if keymap.button_count < 0 || keymap.button_count as usize != keymap.buttons.len() {
+        problems.push(KeymapProblem::ButtonCountMismatch {
+            button_count: keymap.button_count,
+            buttons: keymap.buttons.len(),
+        });
+    }
+
+    check_buttons(BASE_LAYER, &keymap.buttons, ranges, &mut problems);
+    for layer in keymap.layers.iter() {
+        check_buttons(&layer.name, &layer.buttons, ranges, &mut problems);
+    }
+
+    problems.sort_by_key(|problem| !problem.is_fatal());
+    problems
+}
+
+fn check_buttons(
+    layer: &str,
+    buttons: &[MacroKey],
+    ranges: &[CodeRange],
+    problems: &mut Vec<KeymapProblem>,
+) {
+    for (index, macro_key) in buttons.iter().enumerate() {
+        let button = &macro_key.programmable_key;
+
+        let unknown_label = match button {
+            ProgrammableKeys::Button(label) if !button_codes::is_known_label(ranges, label) => {
+                Some(label)
+            }
+            _ => None,
+        };
+
+        if let Some(label) = unknown_label {
+            problems.push(KeymapProblem::UnknownButton {
+                layer: layer.to_string(),
+                index,
+                label: label.clone(),
+            });
+        } else if buttons[..index]
+            .iter()
+            .any(|earlier| earlier.programmable_key == *button)
+        {
+            problems.push(KeymapProblem::DuplicateButton {
+                layer: layer.to_string(),
+                button: button.clone(),
+            });
+        }
+
+        // presses and releases can be split across triggers, so count them over the whole button
+        let mut held: Vec<(Key, i32)> = Vec::new();
+        for action in macro_key.all_actions() {
+            let (key, change) = match action {
+                MacroAction::Press(key) => (key, 1),
+                MacroAction::Release(key) => (key, -1),
+                MacroAction::Delay(delay_ms) if *delay_ms > MAX_DELAY_MS => {
+                    problems.push(KeymapProblem::AbsurdDelay {
+                        layer: layer.to_string(),
+                        button: button.clone(),
+                        delay_ms: *delay_ms,
+                    });
+                    continue;
+                }
+                _ => continue,
+            };
+
+            match held.iter_mut().find(|(held_key, _)| held_key == key) {
+                Some((_, count)) => *count += change,
+                None => held.push((key.clone(), change)),
+            }
+        }
+
+        for (key, count) in held {
+            if count > 0 {
+                problems.push(KeymapProblem::UnreleasedKey {
+                    layer: layer.to_string(),
+                    button: button.clone(),
+                    key,
+                });
+            } else if count < 0 {
+                problems.push(KeymapProblem::UnpressedRelease {
+                    layer: layer.to_string(),
+                    button: button.clone(),
+                    key,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::button_codes::default_ranges;
+
+    fn button(index: i32) -> ProgrammableKeys {
+        ProgrammableKeys::get_from_index(index)
+    }
+
+    fn problems(keymap: &Keymap) -> Vec<KeymapProblem> {
+        validate(keymap, &default_ranges())
+    }
+
+    #[test]
+    fn blank_keymap_has_no_problems() {
+        assert_eq!(problems(&Keymap::new("blank".to_string(), 3)), Vec::new());
+    }
+
+    #[test]
+    fn duplicate_button_is_fatal() {
+        let mut keymap = Keymap::new("duplicate".to_string(), 2);
+        keymap.buttons[1].programmable_key = button(1);
+
+        let problems = problems(&keymap);
+        assert_eq!(
+            problems,
+            vec![KeymapProblem::DuplicateButton {
+                layer: BASE_LAYER.to_string(),
+                button: button(1),
+            }]
+        );
+        assert!(problems[0].is_fatal());
+    }
+
+    #[test]
+    fn button_count_mismatch_is_a_warning_and_gets_corrected() {
+        let mut keymap = Keymap::new("count".to_string(), 2);
+        keymap.button_count = 5;
+
+        let problems = problems(&keymap);
+        assert_eq!(
+            problems,
+            vec![KeymapProblem::ButtonCountMismatch {
+                button_count: 5,
+                buttons: 2,
+            }]
+        );
+        assert!(!problems[0].is_fatal());
+
+        let json = serde_json::to_string(&keymap).unwrap();
+        let (loaded, _) = Keymap::from_json("count".to_string(), &json, &default_ranges()).unwrap();
+        assert_eq!(loaded.button_count, 2);
+    }
+
+    #[test]
+    fn absurd_delay_is_fatal() {
+        let mut keymap = Keymap::new("delay".to_string(), 1);
+        keymap.buttons[0].actions = vec![
+            MacroAction::Delay(MAX_DELAY_MS),
+            MacroAction::Delay(MAX_DELAY_MS + 1),
+        ];
+
+        let problems = problems(&keymap);
+        assert_eq!(
+            problems,
+            vec![KeymapProblem::AbsurdDelay {
+                layer: BASE_LAYER.to_string(),
+                button: button(1),
+                delay_ms: MAX_DELAY_MS + 1,
+            }]
+        );
+        assert!(problems[0].is_fatal());
+    }
+
+    #[test]
+    fn unknown_button_is_a_warning() {
+        let mut keymap = Keymap::new("unknown".to_string(), 2);
+        keymap.buttons[1].programmable_key = ProgrammableKeys::Button("MACROUNKNOWN".to_string());
+
+        let problems = problems(&keymap);
+        assert_eq!(
+            problems,
+            vec![KeymapProblem::UnknownButton {
+                layer: BASE_LAYER.to_string(),
+                index: 1,
+                label: "MACROUNKNOWN".to_string(),
+            }]
+        );
+        assert!(!problems[0].is_fatal());
+    }
+
+    #[test]
+    fn unbalanced_presses_and_releases_are_warnings() {
+        let mut keymap = Keymap::new("unbalanced".to_string(), 3);
+        keymap.buttons[0].actions = vec![MacroAction::Press(Key::ShiftLeft)];
+        keymap.buttons[1].actions = vec![MacroAction::Release(Key::ControlLeft)];
+        // held on press and let go on release balances out
+        keymap.buttons[2].on_press = Some(vec![MacroAction::Press(Key::Alt)]);
+        keymap.buttons[2].on_release = Some(vec![MacroAction::Release(Key::Alt)]);
+
+        let problems = problems(&keymap);
+        assert_eq!(
+            problems,
+            vec![
+                KeymapProblem::UnreleasedKey {
+                    layer: BASE_LAYER.to_string(),
+                    button: button(1),
+                    key: Key::ShiftLeft,
+                },
+                KeymapProblem::UnpressedRelease {
+                    layer: BASE_LAYER.to_string(),
+                    button: button(2),
+                    key: Key::ControlLeft,
+                },
+            ]
+        );
+        assert!(problems.iter().all(|problem| !problem.is_fatal()));
+    }
+
+    #[test]
+    fn fatal_problems_come_first() {
+        let mut keymap = Keymap::new("order".to_string(), 2);
+        keymap.buttons[0].actions = vec![MacroAction::Press(Key::ShiftLeft)];
+        keymap.buttons[1].actions = vec![MacroAction::Delay(MAX_DELAY_MS + 1)];
+
+        let fatal: Vec<bool> = problems(&keymap)
+            .iter()
+            .map(KeymapProblem::is_fatal)
+            .collect();
+        assert_eq!(fatal, vec![true, false]);
+    }
+
+    #[test]
+    fn parse_errors_keep_their_line_and_column() {
+        let json = "{\n  \"schema_version\": 1,\n  \"map_name\": \"parse\",\n  \"button_count\": \"two\",\n  \"buttons\": []\n}";
+
+        match Keymap::from_json("parse".to_string(), json, &default_ranges()) {
+            Err(KeymapError::Parse { line, column, .. }) => assert_eq!((line, column), (4, 23)),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+}
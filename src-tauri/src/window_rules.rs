@@ -0,0 +1,324 @@
+// focus watching is only implemented on linux so far
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// What we know about the focused window, any of it can be missing
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WindowInfo {
+    /// WM_CLASS on X11, the app id on Wayland
+    pub class: Option<String>,
+    pub title: Option<String>,
+    /// full path of the window's executable
+    pub executable: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum PatternSyntax {
+    /// shell style globs, matched case-insensitively
+    #[default]
+    Glob,
+    Regex,
+}
+
+/// Activates `profile` when the focused window matches every pattern that is set
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ProfileRule {
+    pub profile: String,
+    #[serde(default)]
+    pub syntax: PatternSyntax,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// matched against the executable's file name and its full path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<String>,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn compile(syntax: PatternSyntax, pattern: &str) -> Result<Pattern, String> {
+        match syntax {
+            PatternSyntax::Glob => GlobBuilder::new(pattern)
+                .case_insensitive(true)
+                .literal_separator(false)
+                .build()
+                .map(|glob| Pattern::Glob(glob.compile_matcher()))
+                .map_err(|err| err.to_string()),
+            PatternSyntax::Regex => Regex::new(pattern)
+                .map(Pattern::Regex)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    fn is_match(&self, value: &str) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob.is_match(value),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    profile: String,
+    class: Option<Pattern>,
+    title: Option<Pattern>,
+    executable: Option<Pattern>,
+}
+
+impl CompiledRule {
+    fn compile(rule: &ProfileRule) -> Result<CompiledRule, String> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(|pattern| Pattern::compile(rule.syntax, pattern))
+                .transpose()
+        };
+
+        let compiled = CompiledRule {
+            profile: rule.profile.clone(),
+            class: compile(&rule.class)?,
+            title: compile(&rule.title)?,
+            executable: compile(&rule.executable)?,
+        };
+
+        if compiled.class.is_none() && compiled.title.is_none() && compiled.executable.is_none() {
+            return Err("the rule has no class, title or executable pattern".to_string());
+        }
+
+        Ok(compiled)
+    }
+
+    fn matches(&self, window: &WindowInfo) -> bool {
+        let field_matches = |pattern: &Option<Pattern>, value: &Option<String>| match pattern {
+            None => true,
+            Some(pattern) => value
+                .as_deref()
+                .is_some_and(|value| pattern.is_match(value)),
+        };
+
+        let executable_matches = match (&self.executable, &window.executable) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(pattern), Some(executable)) => {
+                let file_name = Path::new(executable)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(executable);
+                pattern.is_match(file_name) || pattern.is_match(executable)
+            }
+        };
+
+        field_matches(&self.class, &window.class)
+            && field_matches(&self.title, &window.title)
+            && executable_matches
+    }
+}
+
+/// Picks the keymap profile for a focused window. Rules are tried in order
+/// and the first match wins, windows nothing matches get the default profile.
+#[derive(Debug)]
+pub struct RuleMatcher {
+    rules: Vec<CompiledRule>,
+    default_profile: Option<String>,
+}
+
+impl RuleMatcher {
+    /// Compiles the rules, skipping any with invalid patterns
+    pub fn new(rules: &[ProfileRule], default_profile: Option<String>) -> RuleMatcher {
+        let rules = rules
+            .iter()
+            .filter_map(|rule| match CompiledRule::compile(rule) {
+                Ok(compiled) => Some(compiled),
+                Err(err) => {
+                    eprintln!("Skipping profile rule for {}: {}", rule.profile, err);
+                    None
+                }
+            })
+            .collect();
+
+        RuleMatcher {
+            rules,
+            default_profile,
+        }
+    }
+
+    pub fn profile_for(&self, window: &WindowInfo) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(window))
+            .map(|rule| rule.profile.as_str())
+            .or(self.default_profile.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(profile: &str, syntax: PatternSyntax) -> ProfileRule {
+        ProfileRule {
+            profile: profile.to_string(),
+            syntax,
+            class: None,
+            title: None,
+            executable: None,
+        }
+    }
+
+    fn window(class: &str, title: &str, executable: &str) -> WindowInfo {
+        WindowInfo {
+            class: Some(class.to_string()),
+            title: Some(title.to_string()),
+            executable: Some(executable.to_string()),
+        }
+    }
+
+    fn firefox() -> WindowInfo {
+        window(
+            "firefox",
+            "Rust docs - Mozilla Firefox",
+            "/usr/lib/firefox/firefox",
+        )
+    }
+
+    fn blender() -> WindowInfo {
+        window(
+            "Blender",
+            "Blender [/home/me/scene.blend]",
+            "/opt/blender/blender",
+        )
+    }
+
+    #[test]
+    fn matches_class_with_globs_and_regexes() {
+        let glob = ProfileRule {
+            class: Some("FIRE*".to_string()),
+            ..rule("browser", PatternSyntax::Glob)
+        };
+        let regex = ProfileRule {
+            class: Some("^Blend(er)?$".to_string()),
+            ..rule("3d", PatternSyntax::Regex)
+        };
+        let matcher = RuleMatcher::new(&[glob, regex], None);
+
+        assert_eq!(matcher.profile_for(&firefox()), Some("browser"));
+        assert_eq!(matcher.profile_for(&blender()), Some("3d"));
+    }
+
+    #[test]
+    fn matches_title_with_globs_and_regexes() {
+        let glob = ProfileRule {
+            title: Some("*mozilla firefox".to_string()),
+            ..rule("browser", PatternSyntax::Glob)
+        };
+        let regex = ProfileRule {
+            title: Some(r"\.blend\]$".to_string()),
+            ..rule("3d", PatternSyntax::Regex)
+        };
+        let matcher = RuleMatcher::new(&[glob, regex], None);
+
+        assert_eq!(matcher.profile_for(&firefox()), Some("browser"));
+        assert_eq!(matcher.profile_for(&blender()), Some("3d"));
+    }
+
+    #[test]
+    fn matches_executable_name_or_path() {
+        let by_name = ProfileRule {
+            executable: Some("firefox".to_string()),
+            ..rule("browser", PatternSyntax::Glob)
+        };
+        let by_path = ProfileRule {
+            executable: Some("^/opt/.*/blender$".to_string()),
+            ..rule("3d", PatternSyntax::Regex)
+        };
+        let matcher = RuleMatcher::new(&[by_name, by_path], None);
+
+        assert_eq!(matcher.profile_for(&firefox()), Some("browser"));
+        assert_eq!(matcher.profile_for(&blender()), Some("3d"));
+        assert_eq!(
+            matcher.profile_for(&WindowInfo {
+                executable: None,
+                ..firefox()
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn every_set_pattern_has_to_match() {
+        let both = ProfileRule {
+            class: Some("firefox".to_string()),
+            title: Some("*github*".to_string()),
+            ..rule("github", PatternSyntax::Glob)
+        };
+        let matcher = RuleMatcher::new(&[both], None);
+
+        assert_eq!(matcher.profile_for(&firefox()), None);
+        assert_eq!(
+            matcher.profile_for(&window("firefox", "GitHub - Mozilla Firefox", "firefox")),
+            Some("github")
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let specific = ProfileRule {
+            title: Some("*rust*".to_string()),
+            ..rule("rust", PatternSyntax::Glob)
+        };
+        let general = ProfileRule {
+            class: Some("firefox".to_string()),
+            ..rule("browser", PatternSyntax::Glob)
+        };
+
+        let matcher = RuleMatcher::new(&[specific.clone(), general.clone()], None);
+        assert_eq!(matcher.profile_for(&firefox()), Some("rust"));
+
+        let matcher = RuleMatcher::new(&[general, specific], None);
+        assert_eq!(matcher.profile_for(&firefox()), Some("browser"));
+    }
+
+    #[test]
+    fn invalid_rules_are_skipped() {
+        let invalid = ProfileRule {
+            class: Some("(firefox".to_string()),
+            ..rule("broken", PatternSyntax::Regex)
+        };
+        let empty = rule("empty", PatternSyntax::Glob);
+        let valid = ProfileRule {
+            class: Some("firefox".to_string()),
+            ..rule("browser", PatternSyntax::Glob)
+        };
+        let matcher = RuleMatcher::new(&[invalid, empty, valid], None);
+
+        assert_eq!(matcher.rules.len(), 1);
+        assert_eq!(matcher.profile_for(&firefox()), Some("browser"));
+    }
+
+    #[test]
+    fn falls_back_to_the_default_profile() {
+        let rules = [ProfileRule {
+            class: Some("firefox".to_string()),
+            ..rule("browser", PatternSyntax::Glob)
+        }];
+
+        let matcher = RuleMatcher::new(&rules, Some("default".to_string()));
+        assert_eq!(matcher.profile_for(&blender()), Some("default"));
+        assert_eq!(matcher.profile_for(&WindowInfo::default()), Some("default"));
+
+        let matcher = RuleMatcher::new(&rules, None);
+        assert_eq!(matcher.profile_for(&blender()), None);
+    }
+}
@@ -1,11 +1,12 @@
 use std::ptr::{self};
-use std::sync::Arc;
-use std::sync::Mutex;
 use winapi::shared::minwindef::{DWORD, LPARAM, LRESULT, WPARAM};
 use winapi::shared::windef::*;
 use winapi::um::libloaderapi::GetModuleHandleW;
 use winapi::um::winuser::*;
 
+use crate::button_codes::ButtonCodes;
+use crate::input_devices::InputDevice;
+use crate::key_queue::{KeyEvent, KeySender, KeyState};
 use crate::programmable_keys::ProgrammableKeys;
 
 #[macro_export]
@@ -16,37 +17,66 @@ macro_rules! native_str {
     };
 }
 
+/// Pushes a key event onto the shared key queue
+fn send_event(prog_key: ProgrammableKeys, state: KeyState, device: String) {
+    unsafe {
+        match KEY_QUEUE.as_ref() {
+            Some(queue) => {
+                queue.send(KeyEvent::new(prog_key, state, Some(device)));
+            }
+            None => {
+                eprintln!("KEY_QUEUE is not initialized");
+            }
+        }
+    }
+}
+
 fn handle_hid(raw_input: &RAWINPUT) {
     unsafe {
         let raw_keyboard_input = raw_input.data.keyboard();
+        let device = format!("{:?}", raw_input.header.hDevice);
 
         // println!("flags {:?}, extra info {:?}, makeCoke {:?}, message {:?}" , raw_keyboard_input.Flags, raw_keyboard_input.ExtraInformation, raw_keyboard_input.MakeCode, raw_keyboard_input.Message);
 
-        if raw_keyboard_input.Flags == 0
-            && raw_keyboard_input.MakeCode == 5
-            && raw_keyboard_input.Message != 5
-        {
-            let prog_key = ProgrammableKeys::from_u32(raw_keyboard_input.Message);
-            match prog_key {
-                ProgrammableKeys::MACROUNKNOWN => {
-                    eprintln!("MACROUNKNOWN PRESSED");
-                }
-                _ => {
-                    let queue_temp = KEY_QUEUE.clone();
-                    match queue_temp {
-                        Some(queue) => match queue.lock() {
-                            Ok(mut borrowed_queue) => {
-                                borrowed_queue.push(prog_key);
-                            }
-                            Err(e) => {
-                                eprintln!("Error locking queue: {:?}", e);
-                            }
-                        },
-                        None => {
-                            eprintln!("KEY_QUEUE is not initialized");
-                        }
+        if raw_keyboard_input.Flags != 0 || raw_keyboard_input.MakeCode != 5 {
+            return;
+        }
+
+        // a report with no button bits set means the held button was released
+        if raw_keyboard_input.Message == 5 {
+            if let Some(held_key) = HELD_KEY.take() {
+                send_event(held_key, KeyState::Released, device);
+            }
+            return;
+        }
+
+        // raw input only tells devices apart by their handle
+        let input_device = InputDevice {
+            path: device.clone(),
+            name: String::new(),
+            vendor: 0,
+            product: 0,
+            phys: String::new(),
+        };
+        let prog_key = BUTTON_CODES
+            .as_ref()
+            .and_then(|codes| codes.button_for(raw_keyboard_input.Message, &input_device));
+        match prog_key {
+            None => {
+                eprintln!("Unknown button code {}", raw_keyboard_input.Message);
+            }
+            Some(prog_key) => {
+                // switching straight to another button releases the previous one
+                if let Some(held_key) = HELD_KEY.take() {
+                    if held_key == prog_key {
+                        HELD_KEY = Some(held_key);
+                        return;
                     }
+                    send_event(held_key, KeyState::Released, device.clone());
                 }
+
+                HELD_KEY = Some(prog_key.clone());
+                send_event(prog_key, KeyState::Pressed, device);
             }
         }
     }
@@ -176,12 +206,17 @@ fn message_loop(hwnd: HWND) {
     }
 }
 
-static mut KEY_QUEUE: Option<Arc<Mutex<Vec<ProgrammableKeys>>>> = None;
+static mut KEY_QUEUE: Option<KeySender> = None;
+/// the button from the last report, so we know what a release report refers to
+static mut HELD_KEY: Option<ProgrammableKeys> = None;
+
+static mut BUTTON_CODES: Option<ButtonCodes> = None;
 
-pub fn windows_start(queue: &Arc<Mutex<Vec<ProgrammableKeys>>>) {
+pub fn windows_start(queue: &KeySender, codes: &ButtonCodes) {
     let temp = queue.clone();
     unsafe {
         KEY_QUEUE = Some(temp);
+        BUTTON_CODES = Some(codes.clone());
     }
     let hwnd = create_window();
     attach(hwnd);
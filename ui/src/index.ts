@@ -1,5 +1,6 @@
 import {Modal, Toast} from "bootstrap";
 import {invoke} from "@tauri-apps/api";
+import {listen} from "@tauri-apps/api/event";
 import {createKeySelectorTemplate, Keys, sortedArray, sortedFormated} from "./ProgrammableKeys";
 
 // static assets
@@ -31,6 +32,31 @@ document.addEventListener("DOMContentLoaded", () => {
     currentThemeSetting = calculateSettingAsThemeString(localStorageTheme, systemSettingDark );
     populateKeymapButtons();
 
+    // show which keymap layers are live
+    invoke("send_active_layers").then((layers) => showActiveLayers(layers as string[]));
+    listen<string[]>("active-layers", (event) => showActiveLayers(event.payload));
+
+    // keymap profile switching, the backend sends load-keymap whenever the active profile changes
+    populateProfiles();
+    document.getElementById("profileSelect")!.addEventListener("change", (event) => {
+        let name = (event.target as HTMLSelectElement).value;
+        invoke("activate_profile", {name: name}).catch((err) => {
+            console.error("Failed to switch profile:", err);
+            populateProfiles();
+        });
+    });
+    listen("load-keymap", () => {
+        prevIndex = null;
+        dirty = false;
+        actionsDiv.innerHTML = '';
+        populateKeymapButtons();
+        populateProfiles();
+    });
+
+    // show problems found in the keymap file
+    invoke("send_keymap_diagnostics").then((diagnostics) => showDiagnostics(diagnostics as Diagnostic[]));
+    listen<Diagnostic[]>("keymap-diagnostics", (event) => showDiagnostics(event.payload));
+
     saveAlertModal = new Modal(document.getElementById('save-alert')!, {backdrop: true});
     // populate events for adding macro buttons
 
@@ -47,7 +73,7 @@ document.addEventListener("DOMContentLoaded", () => {
             dirty = false;
             secondOpen = false;
             new Toast(document.getElementById("saveToast")!).show();
-        });
+        }).catch(showSaveError);
     });
 
     document.getElementById("save-btn-modal")!.addEventListener("click", () => {
@@ -57,6 +83,9 @@ document.addEventListener("DOMContentLoaded", () => {
             secondOpen = false;
             saveAlertModal.hide();
             new Toast(document.getElementById("saveToast")!).show();
+        }).catch((err) => {
+            saveAlertModal.hide();
+            showSaveError(err);
         });
     });
 
@@ -98,6 +127,62 @@ document.addEventListener("DOMContentLoaded", () => {
     });
 })
 
+let showActiveLayers = (layers: string[]) => {
+    document.getElementById("activeLayers")!.textContent = layers.join(" > ");
+}
+
+interface Diagnostic {
+    severity: "Error" | "Warning";
+    message: string;
+    line?: number;
+    column?: number;
+}
+
+let showDiagnostics = (diagnostics: Diagnostic[]) => {
+    let alert = document.getElementById("keymapDiagnostics")!;
+    alert.innerHTML = '';
+
+    if (diagnostics.length == 0) {
+        alert.classList.add("d-none");
+        return;
+    }
+
+    let hasErrors = diagnostics.some((diagnostic) => diagnostic.severity == "Error");
+    alert.className = "alert " + (hasErrors ? "alert-danger" : "alert-warning");
+
+    let list = document.createElement("ul");
+    list.className = "mb-0";
+    for (let diagnostic of diagnostics) {
+        let item = document.createElement("li");
+        item.textContent = diagnostic.severity + ": " + diagnostic.message;
+        if (diagnostic.line !== undefined) {
+            item.textContent += " (line " + diagnostic.line + ", column " + diagnostic.column + ")";
+        }
+        list.append(item);
+    }
+
+    alert.append(list);
+}
+
+let showSaveError = (err: any) => {
+    showDiagnostics(String(err).split("\n").map((message) => ({severity: "Error", message: message} as Diagnostic)));
+}
+
+let populateProfiles = () => {
+    Promise.all([invoke("list_profiles"), invoke("send_active_profile")]).then(([profiles, active]) => {
+        let select = document.getElementById("profileSelect")! as HTMLSelectElement;
+        select.innerHTML = '';
+
+        for (let profile of profiles as string[]) {
+            let option = document.createElement("option");
+            option.value = profile;
+            option.textContent = profile;
+            option.selected = profile == active;
+            select.append(option);
+        }
+    });
+}
+
 let calculateSettingAsThemeString = (localStorageTheme: string | null, systemSettingDark: MediaQueryList) =>{
     if (localStorageTheme !== null) {
         return localStorageTheme;
@@ -110,6 +195,17 @@ let calculateSettingAsThemeString = (localStorageTheme: string | null, systemSet
     return "light";
 }
 
+// programmable buttons are plain names, key bindings show their chord
+let buttonLabel = (programmableKey: any): string => {
+    if (typeof programmableKey === "string") {
+        return programmableKey;
+    }
+
+    let binding = programmableKey.Binding;
+    let key = typeof binding.key === "string" ? binding.key : "Key " + binding.key.Unknown;
+    return [...(binding.modifiers ?? []), key].join("+");
+}
+
 let populateKeymapButtons = () => {
 
     invoke("send_keymap").then((result) => {
@@ -125,7 +221,7 @@ let populateKeymapButtons = () => {
             let editButton = document.createElement("a");
             editButton.className = "list-group-item list-group-item-action content-box";
             editButton.id = "button" + x;
-            editButton.textContent = button.programmable_key
+            editButton.textContent = buttonLabel(button.programmable_key)
 
             // event listener for opening config window
             editButton.addEventListener("click", () => {